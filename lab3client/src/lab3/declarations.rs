@@ -10,39 +10,463 @@
 
 pub const MIN_ARGS: usize = 2;
 pub const MAX_ARGS: usize = 3;
+pub const REMOTE_ARGS: usize = 1; // just the program name, when a server recites the script
 pub const PROG_NAME: usize = 0;
 pub const CONFIG_FILE: usize = 1;
 pub const WHINGE_MODE: usize = 2;
+pub const OPTION_PREFIX: &str = "--";
+pub const TCP_PREFIX: &str = "tcp://";
+const PROTOCOL_VERSION: &str = "LAB3 v1";
+const HANDSHAKE_ACCEPTED: &str = "LAB3 v1 OK";
+const GET_COMMAND: &str = "GET";
+const END_OF_REPLY: &str = "LAB3 END"; // sent by the server after a file or a recited play
+const SERVER_ERROR_PREFIX: &str = "LAB3 ERR";
+const BUSY: &str = "BUSY"; // sent by a server that is handling as many connections as it can
+const PROTOCOL_PREFIX: &str = "LAB3"; // starts every line the protocol itself sends
+const REPLY_ESCAPE: char = '\\'; // put before a line of a reply that could be mistaken for a protocol line
+pub const WHINGE_ENV: &str = "LAB3_WHINGE";
+pub const OUTPUT_ENV: &str = "LAB3_OUTPUT";
+pub const ENV_ON: &str = "1";
 
 pub const ERR_CMD_LINE: u8 = 1;
 pub const ERR_SCRIPT_GEN: u8 = 2;
 pub const ERR_MUTEX: u8 = 3;
+pub const ERR_MISSING_LEADING_TITLE: u8 = 4;
+pub const ERR_TIMEOUT: u8 = 5;
+pub const ERR_SCENE_INDEX: u8 = 6;
+pub const ERR_OUTPUT: u8 = 7;
 
-use std::sync::atomic::AtomicBool;
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+pub static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+pub static FILES_READ: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new())); // every file read, for watch mode
+pub static CONFIG_ECHO: Mutex<String> = Mutex::new(String::new()); // parsed config entries, for --echo-config
+pub const DEFAULT_SILENT_ENTRANCE_LABEL: &str = "attendants";
+pub static SILENT_ENTRANCE_LABEL: Mutex<String> = Mutex::new(String::new()); // empty is the default
+pub const DEFAULT_PART_FILE_EXTENSION: &str = ".txt";
+pub static PART_FILE_EXTENSION: Mutex<String> = Mutex::new(String::new()); // empty is the default
+pub const DEFAULT_COMMENT_MARKER: &str = "//";
+pub static COMMENT_MARKER: Mutex<String> = Mutex::new(String::new()); // empty is the default
+pub static WARNING_SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None); // None is stderr
 pub static WHINGE_ON: AtomicBool = AtomicBool::new(false);
+pub static DEFER_WARNINGS_ON: AtomicBool = AtomicBool::new(false);
+pub static STRICT_ON: AtomicBool = AtomicBool::new(false);
+pub static TIMINGS_ON: AtomicBool = AtomicBool::new(false);
+pub static SKIP_MISSING_ON: AtomicBool = AtomicBool::new(false);
+pub static TOC_ON: AtomicBool = AtomicBool::new(false);
+pub static CANONICAL_ON: AtomicBool = AtomicBool::new(false);
+pub static BLOCKING_ON: AtomicBool = AtomicBool::new(false);
+pub static COUNT_ON: AtomicBool = AtomicBool::new(false);
+pub static NUMBER_SCENES_ON: AtomicBool = AtomicBool::new(false);
+pub static REQUIRE_SORTED_ON: AtomicBool = AtomicBool::new(false);
+pub static CONTINUOUS_NUMBERING_ON: AtomicBool = AtomicBool::new(false);
+pub static PARALLEL_RECITE_ON: AtomicBool = AtomicBool::new(false);
+pub static SHUFFLE_ON: AtomicBool = AtomicBool::new(false);
+pub static SHUFFLE_SEED: AtomicU64 = AtomicU64::new(0);
+pub static FIND_ON: AtomicBool = AtomicBool::new(false);
+pub static FIND_LINE: AtomicUsize = AtomicUsize::new(0);
+pub static UNTIL_ON: AtomicBool = AtomicBool::new(false);
+pub static UNTIL_LINE: AtomicUsize = AtomicUsize::new(0);
+pub const DEFAULT_NUMBER_BASE: u32 = 10;
+pub const MIN_NUMBER_BASE: u32 = 2;
+pub const MAX_NUMBER_BASE: u32 = 36;
+pub static NUMBER_BASE: AtomicU32 = AtomicU32::new(DEFAULT_NUMBER_BASE);
+pub static LONGEST_ON: AtomicBool = AtomicBool::new(false);
+pub static MARKDOWN_ON: AtomicBool = AtomicBool::new(false);
+pub static STATS_JSON_ON: AtomicBool = AtomicBool::new(false);
+pub static SPEAKERS_ON: AtomicBool = AtomicBool::new(false);
+pub const NO_TIMEOUT: u64 = 0;
+pub static PREPARE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(NO_TIMEOUT);
+pub static LOSSY_ON: AtomicBool = AtomicBool::new(false);
+pub static TRACE_FILES_ON: AtomicBool = AtomicBool::new(false);
+pub static PROGRESS_ON: AtomicBool = AtomicBool::new(false);
+pub const PROGRESS_INTERVAL: usize = 100_000; // how many lines of a part file are read between progress reports
+pub const NO_PROGRESS: usize = 0; // a progress interval that never reports
+pub static WATCH_ON: AtomicBool = AtomicBool::new(false);
+pub static ECHO_CONFIG_ON: AtomicBool = AtomicBool::new(false);
+pub const DEFAULT_LINE_BASE: usize = 0; // the number part files are expected to start from
+pub static LINE_BASE: AtomicUsize = AtomicUsize::new(DEFAULT_LINE_BASE);
+pub const SINGLE_RECITAL: usize = 1;
+pub static REPEAT: AtomicUsize = AtomicUsize::new(SINGLE_RECITAL);
+pub static PRESERVE_INDENT_ON: AtomicBool = AtomicBool::new(false);
+pub static IGNORE_NAME_CASE_ON: AtomicBool = AtomicBool::new(false);
+pub static STRIP_INLINE_COMMENTS_ON: AtomicBool = AtomicBool::new(false);
+pub static INFER_PART_FILES_ON: AtomicBool = AtomicBool::new(false);
+pub const DEFAULT_MAX_SCENES: usize = 10000;
+pub const DEFAULT_COLUMN_WIDTH: usize = 38; // two columns and the separator fit in 80 characters
+pub static COLUMN_WIDTH: AtomicUsize = AtomicUsize::new(DEFAULT_COLUMN_WIDTH);
+pub static MAX_SCENES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_SCENES);
+pub const NO_LIMIT: usize = 0;
+pub static MAX_OUTPUT_LINES: AtomicUsize = AtomicUsize::new(NO_LIMIT);
+pub static OUTPUT_LINES: AtomicUsize = AtomicUsize::new(0);
+pub const NO_RETRIES: usize = 0;
+pub static FETCH_RETRIES: AtomicUsize = AtomicUsize::new(NO_RETRIES);
+const RETRY_BASE_DELAY_MS: u64 = 100;
+const RETRY_MAX_DELAY_MS: u64 = 1000;
+const PATH_SEPARATOR: char = '/';
 
 
 use std::fs::File;
 use std::io::{BufReader, BufRead, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
 
-// This function is used to open and read lines from a file. 
+
+// This function returns what characters who enter and exit silently are collectively called in
+// stage directions
+pub fn silent_entrance_label() -> String {
+    match SILENT_ENTRANCE_LABEL.lock() {
+        Ok(ref label) if !label.is_empty() => label.to_string(),
+        _ => DEFAULT_SILENT_ENTRANCE_LABEL.to_string(),
+    }
+}
+
+// This function returns the extension that part and config file names are expected to end in,
+// which is .txt unless another extension was given on the command line
+pub fn part_file_extension() -> String {
+    match PART_FILE_EXTENSION.lock() {
+        Ok(ref extension) if !extension.is_empty() => extension.to_string(),
+        _ => DEFAULT_PART_FILE_EXTENSION.to_string(),
+    }
+}
+
+// This function returns the marker that starts an inline comment in a part file line, which is //
+// unless another marker was given on the command line
+pub fn comment_marker() -> String {
+    match COMMENT_MARKER.lock() {
+        Ok(ref marker) if !marker.is_empty() => marker.to_string(),
+        _ => DEFAULT_COMMENT_MARKER.to_string(),
+    }
+}
+
+// This function sends warnings to the given writer instead of stderr
+pub fn set_warning_sink(sink: Box<dyn Write + Send>) {
+    match WARNING_SINK.lock() {
+        Ok(ref mut current) => **current = Some(sink),
+        Err(_) => {}, // warnings keep going to stderr
+    }
+}
+
+// This function writes a warning to the warning sink, which is stderr unless another sink was
+// set. The sink is behind a mutex since warnings are raised from the preparation threads.
+fn write_warning(warning: &str) {
+    match WARNING_SINK.lock() {
+        Ok(ref mut sink) => {
+            if let Some(w) = sink.as_mut() {
+                match writeln!(w, "{}", warning) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return;
+            }
+        }
+        Err(_) => {}, // fall back to stderr so that the warning is not lost
+    }
+    match writeln!(std::io::stderr().lock(), "{}", warning) {
+        Ok(_) => {}, //success
+        Err(_) => {}, //fail
+    }
+}
+
+// This function records a warning so that it can be collected later with take_warnings, and
+// writes it to the warning sink unless warnings are being deferred. The collector is behind a
+// mutex since warnings are raised from the preparation threads.
+pub fn record_warning(warning: &str) {
+    let deferred = DEFER_WARNINGS_ON.load(Ordering::SeqCst);
+    match WARNINGS.lock() {
+        Ok(ref mut warnings) => {
+            warnings.push(warning.to_string());
+            if deferred {
+                return;
+            }
+        }
+        Err(_) => {}, // the warning is still written below so that it is not lost
+    }
+    write_warning(warning);
+}
+
+// This function writes every warning that was held back because warnings are being deferred to
+// the warning sink, in the order they were raised, and clears the collector. It does nothing if
+// warnings are written as they are raised.
+pub fn flush_deferred_warnings() {
+    if !DEFER_WARNINGS_ON.load(Ordering::SeqCst) {
+        return;
+    }
+    for warning in take_warnings() {
+        write_warning(&warning);
+    }
+}
+
+// This function records a warning only if whinge mode is on
+pub fn whinge(warning: &str) {
+    if WHINGE_ON.load(Ordering::SeqCst) {
+        record_warning(warning);
+    }
+}
+
+// This function returns the names of the files read so far, each once and sorted by name, and
+// clears them
+pub fn take_files_read() -> Vec<String> {
+    match FILES_READ.lock() {
+        Ok(ref mut files) => {
+            let mut names: Vec<String> = files.drain().collect();
+            names.sort();
+            names
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+// This function holds on to the given echo of parsed config entries until it is taken with
+// take_config_echo, so that it can be written once the play is prepared. Echoes are collected
+// behind a mutex since scenes are prepared on their own threads.
+pub fn echo_config(echo: &str) {
+    match CONFIG_ECHO.lock() {
+        Ok(ref mut echoed) => echoed.push_str(echo),
+        Err(_) => {}, // the echo is lost, which only means the entries are not shown
+    }
+}
+
+// This function returns everything echoed with echo_config so far and clears it
+pub fn take_config_echo() -> String {
+    match CONFIG_ECHO.lock() {
+        Ok(ref mut echoed) => std::mem::take(echoed),
+        Err(_) => String::new(),
+    }
+}
+
+// This function returns all warnings recorded so far and clears the collector
+pub fn take_warnings() -> Vec<String> {
+    match WARNINGS.lock() {
+        Ok(ref mut warnings) => std::mem::take(warnings),
+        Err(_) => Vec::new(),
+    }
+}
+
+// This function returns how many warnings have been recorded and not yet taken
+pub fn warning_count() -> usize {
+    match WARNINGS.lock() {
+        Ok(ref warnings) => warnings.len(),
+        Err(_) => 0,
+    }
+}
+
+// This function counts a spoken line or stage direction that is about to be printed. It returns
+// false if the output line limit has already been reached, in which case the line should not be
+// printed.
+pub fn count_output_line() -> bool {
+    let max = MAX_OUTPUT_LINES.load(Ordering::SeqCst);
+    max == NO_LIMIT || OUTPUT_LINES.fetch_add(1, Ordering::SeqCst) < max
+}
+
+// This function returns whether any output has been held back because of the output line limit
+pub fn output_truncated() -> bool {
+    let max = MAX_OUTPUT_LINES.load(Ordering::SeqCst);
+    max != NO_LIMIT && OUTPUT_LINES.load(Ordering::SeqCst) > max
+}
+
+// This function puts every option back to its default and forgets the warnings raised, files read,
+// config entries echoed, and output lines counted so far, so that several plays can be prepared
+// with different options in the same process, as the tests do
+pub fn reset_options() {
+    for flag in [
+        &WHINGE_ON,
+        &DEFER_WARNINGS_ON,
+        &STRICT_ON,
+        &TIMINGS_ON,
+        &SKIP_MISSING_ON,
+        &TOC_ON,
+        &CANONICAL_ON,
+        &BLOCKING_ON,
+        &COUNT_ON,
+        &NUMBER_SCENES_ON,
+        &REQUIRE_SORTED_ON,
+        &CONTINUOUS_NUMBERING_ON,
+        &PARALLEL_RECITE_ON,
+        &SHUFFLE_ON,
+        &FIND_ON,
+        &UNTIL_ON,
+        &LONGEST_ON,
+        &MARKDOWN_ON,
+        &STATS_JSON_ON,
+        &SPEAKERS_ON,
+        &LOSSY_ON,
+        &TRACE_FILES_ON,
+        &PROGRESS_ON,
+        &WATCH_ON,
+        &ECHO_CONFIG_ON,
+        &PRESERVE_INDENT_ON,
+        &IGNORE_NAME_CASE_ON,
+        &STRIP_INLINE_COMMENTS_ON,
+        &INFER_PART_FILES_ON,
+    ] {
+        flag.store(false, Ordering::SeqCst);
+    }
+    SHUFFLE_SEED.store(0, Ordering::SeqCst);
+    FIND_LINE.store(0, Ordering::SeqCst);
+    UNTIL_LINE.store(0, Ordering::SeqCst);
+    NUMBER_BASE.store(DEFAULT_NUMBER_BASE, Ordering::SeqCst);
+    PREPARE_TIMEOUT_MS.store(NO_TIMEOUT, Ordering::SeqCst);
+    LINE_BASE.store(DEFAULT_LINE_BASE, Ordering::SeqCst);
+    REPEAT.store(SINGLE_RECITAL, Ordering::SeqCst);
+    COLUMN_WIDTH.store(DEFAULT_COLUMN_WIDTH, Ordering::SeqCst);
+    MAX_SCENES.store(DEFAULT_MAX_SCENES, Ordering::SeqCst);
+    MAX_OUTPUT_LINES.store(NO_LIMIT, Ordering::SeqCst);
+    FETCH_RETRIES.store(NO_RETRIES, Ordering::SeqCst);
+    for setting in [&SILENT_ENTRANCE_LABEL, &PART_FILE_EXTENSION, &COMMENT_MARKER] {
+        match setting.lock() {
+            Ok(ref mut value) => value.clear(),
+            Err(_) => {}, // a poisoned setting keeps its value
+        }
+    }
+    match WARNING_SINK.lock() {
+        Ok(ref mut sink) => **sink = None,
+        Err(_) => {}, // warnings keep going to the sink that was set
+    }
+    reset_run_state();
+}
+
+// This function forgets the warnings, files read, echoed config entries, and output lines counted
+// while preparing and reciting a play, so that the next play starts from nothing. A server calls
+// it after each request so that they do not build up over its connections.
+pub fn reset_run_state() {
+    take_warnings();
+    take_files_read();
+    take_config_echo();
+    OUTPUT_LINES.store(0, Ordering::SeqCst);
+}
+
+// This function records a file name in the set of files being read in the current chain of
+// includes. Paths are canonicalized where possible so that two spellings of the same file are
+// caught. It returns an error if the file has already been read earlier in the chain.
+pub fn check_circular_include(file_name: &str, includes: &mut HashSet<String>) -> Result<(), u8> {
+    let path = match std::fs::canonicalize(file_name) {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(_) => file_name.to_string(), // missing files are reported when they are opened
+    };
+    if !includes.insert(path) {
+        match writeln!(std::io::stderr().lock(), "Error: circular include of {}", file_name) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        return Err(ERR_SCRIPT_GEN);
+    }
+    Ok(())
+}
+
+// This function records the name of a file that was read, so that watch mode knows which files
+// to watch, and prints it and how many lines it had to stderr if file tracing is on
+fn trace_file_read(file_name: &str, num_lines: usize) {
+    record_file_read(file_name);
+    print_file_read(file_name, num_lines);
+}
+
+// This function records the bundle a file was read from, so that watch mode watches the bundle
+// since the file is not on disk itself, and prints the file's name in the bundle and how many
+// lines it had to stderr if file tracing is on
+pub fn trace_bundle_read(bundle: &str, file_name: &str, num_lines: usize) {
+    record_file_read(bundle);
+    print_file_read(&format!("{} in {}", file_name, bundle), num_lines);
+}
+
+// This function adds the named file to the files read
+fn record_file_read(file_name: &str) {
+    match FILES_READ.lock() {
+        Ok(ref mut files) => {
+            files.insert(file_name.to_string());
+        }
+        Err(_) => {}, // the file is not watched, which only means changes to it are missed
+    }
+}
+
+// This function returns the line traced for reading the given file with the given number of lines
+pub fn file_read_trace(file_name: &str, num_lines: usize) -> String {
+    format!("Reading {} ({} lines)", file_name, num_lines)
+}
+
+// This function prints the given file and how many lines it had to stderr if file tracing is on
+fn print_file_read(file_name: &str, num_lines: usize) {
+    if TRACE_FILES_ON.load(Ordering::SeqCst) {
+        match writeln!(std::io::stderr().lock(), "{}", file_read_trace(file_name, num_lines)) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+    }
+}
+
+// This function is used to open and read trimmed lines from a file. 
 // Ita Result type that is an error if a file could not be opened or read from,
-// and success otherwise.
+// and success otherwise. A line that is not valid UTF-8 is an error unless lossy mode
+// is on, in which case the invalid bytes are replaced. A path that is not a regular file,
+// such as a directory, is an error.
 pub fn grab_trimmed_file_lines(file_name: &str, file_lines: &mut Vec<String>) -> Result<(), u8> {
+    grab_file_lines(file_name, file_lines, str::trim, None)
+}
+
+// This function reads trimmed lines from a file like grab_trimmed_file_lines, and calls the given
+// progress callback with the number of lines read so far each time another every lines have been
+// read, so a file of n lines calls it n / every times
+pub fn grab_trimmed_file_lines_with_progress(
+    file_name: &str,
+    file_lines: &mut Vec<String>,
+    every: usize,
+    progress: &mut dyn FnMut(usize),
+) -> Result<(), u8> {
+    grab_file_lines(file_name, file_lines, str::trim, Some((every, progress)))
+}
+
+// This function reads lines from a file like grab_trimmed_file_lines, but only trims the end of
+// each line so that leading indentation is kept
+pub fn grab_end_trimmed_file_lines(file_name: &str, file_lines: &mut Vec<String>) -> Result<(), u8> {
+    grab_file_lines(file_name, file_lines, str::trim_end, None)
+}
+
+// This function reads end trimmed lines from a file like grab_end_trimmed_file_lines, calling the
+// given progress callback like grab_trimmed_file_lines_with_progress
+pub fn grab_end_trimmed_file_lines_with_progress(
+    file_name: &str,
+    file_lines: &mut Vec<String>,
+    every: usize,
+    progress: &mut dyn FnMut(usize),
+) -> Result<(), u8> {
+    grab_file_lines(file_name, file_lines, str::trim_end, Some((every, progress)))
+}
+
+// This function reads the lines of a file, trimming each with the given function. If a progress
+// interval and callback are given, the callback is called with the number of lines read so far
+// every time that many more lines have been read.
+fn grab_file_lines(
+    file_name: &str,
+    file_lines: &mut Vec<String>,
+    trim: fn(&str) -> &str,
+    mut progress: Option<(usize, &mut dyn FnMut(usize))>,
+) -> Result<(), u8> {
+    if let Ok(metadata) = std::fs::metadata(file_name) {
+        if !metadata.is_file() {
+            match writeln!(std::io::stderr().lock(), "Error: {} is not a regular file", file_name) {
+                Ok(_) => {},
+                Err(_) => {},
+            }
+            return Err(ERR_SCRIPT_GEN);
+        }
+    }
     match File::open(file_name) {
         Err(_) => {
             match writeln!(std::io::stderr().lock(), "Error: script generation failed because the file {} could not be opened", file_name){
                 Ok(_) => {},
                 Err(_) => {},
             }
-            return Err(ERR_SCRIPT_GEN);
+            Err(ERR_SCRIPT_GEN)
         },
         Ok(f) => {
             let mut reader = BufReader::new(f);
-            let mut s = String::new();
+            let mut bytes = Vec::new();
+            let mut num_lines = 0;
             loop {
-                s.clear();
-                match reader.read_line(&mut s) {
+                bytes.clear();
+                match reader.read_until(b'\n', &mut bytes) {
                     Err(_) => {
                         match writeln!(std::io::stderr().lock(), "Error: script generation failed because line could not be read") {
                             Ok(_) => {},
@@ -52,9 +476,29 @@ pub fn grab_trimmed_file_lines(file_name: &str, file_lines: &mut Vec<String>) ->
                     },
                     Ok(bytes_read) => {
                         if bytes_read == 0 { //done reading
+                            trace_file_read(file_name, num_lines);
                             return Ok(())
                         }
-                        file_lines.push(s.trim().to_string());
+                        let s = match std::str::from_utf8(&bytes) {
+                            Ok(s) => s.to_string(),
+                            Err(_) if LOSSY_ON.load(Ordering::SeqCst) => {
+                                String::from_utf8_lossy(&bytes).to_string()
+                            },
+                            Err(_) => {
+                                match writeln!(std::io::stderr().lock(), "Error: script generation failed because the file {} contains invalid UTF-8", file_name) {
+                                    Ok(_) => {},
+                                    Err(_) => {},
+                                }
+                                return Err(ERR_SCRIPT_GEN);
+                            },
+                        };
+                        file_lines.push(trim(&s).to_string());
+                        num_lines += 1;
+                        if let Some((every, callback)) = progress.as_mut() {
+                            if *every > 0 && num_lines % *every == 0 {
+                                callback(num_lines);
+                            }
+                        }
                     },
                 }
 
@@ -62,3 +506,196 @@ pub fn grab_trimmed_file_lines(file_name: &str, file_lines: &mut Vec<String>) ->
         },
     }
 }
+
+// This function writes the given lines to the named file, one per line, replacing anything
+// already in it. It returns an error if the file could not be written.
+pub fn write_file_lines(file_name: &Path, lines: &[String]) -> Result<(), u8> {
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    match std::fs::write(file_name, contents) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            match writeln!(std::io::stderr().lock(), "Error: the file {} could not be written", file_name.display()) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            Err(ERR_OUTPUT)
+        }
+    }
+}
+
+// This function returns a line of a file or recited play as the server sends it, with an escape
+// character put before it if it starts like a protocol line or with the escape character, so that
+// it cannot be mistaken for the end of the reply or an error
+pub fn escape_reply_line(line: &str) -> String {
+    if line.starts_with(PROTOCOL_PREFIX) || line.starts_with(REPLY_ESCAPE) {
+        format!("{}{}", REPLY_ESCAPE, line)
+    } else {
+        line.to_string()
+    }
+}
+
+// This function undoes escape_reply_line, returning a line of a reply as it was before it was sent
+pub fn unescape_reply_line(line: &str) -> &str {
+    line.strip_prefix(REPLY_ESCAPE).unwrap_or(line)
+}
+
+// The outcome of one attempt to connect to a lab3 server and agree on the protocol version
+enum Handshake {
+    Accepted(TcpStream, BufReader<TcpStream>),
+    Unavailable, // the server could not be reached, was busy, or hung up, which may pass
+    Rejected, // the server does not speak this protocol version, which will not change
+}
+
+// This function makes one attempt to connect to the lab3 server at the given address and send it
+// the protocol version
+fn handshake(address: &str) -> Handshake {
+    let mut stream = match TcpStream::connect(address) {
+        Ok(stream) => stream,
+        Err(_) => return Handshake::Unavailable,
+    };
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
+        Err(_) => return Handshake::Unavailable,
+    };
+    let mut reply = String::new();
+    if writeln!(stream, "{}", PROTOCOL_VERSION).is_err() || reader.read_line(&mut reply).is_err() {
+        return Handshake::Unavailable;
+    }
+    match reply.trim() {
+        HANDSHAKE_ACCEPTED => Handshake::Accepted(stream, reader),
+        BUSY | "" => Handshake::Unavailable,
+        _ => Handshake::Rejected,
+    }
+}
+
+// This function connects to the lab3 server at the given address and sends it the protocol
+// version, returning the stream to write requests to and a reader for the replies. A server that
+// does not accept the version is treated as one that could not be connected to. If the server
+// cannot be connected to, or says it is busy, the connection is retried up to the number of fetch
+// retries given, waiting a little longer before each retry up to a bounded delay. It returns None
+// if the connection ultimately fails.
+fn connect_to_server(address: &str) -> Option<(TcpStream, BufReader<TcpStream>)> {
+    let retries = FETCH_RETRIES.load(Ordering::SeqCst);
+    let mut delay_ms = RETRY_BASE_DELAY_MS;
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            std::thread::sleep(Duration::from_millis(delay_ms));
+            delay_ms = (delay_ms * 2).min(RETRY_MAX_DELAY_MS);
+        }
+        match handshake(address) {
+            Handshake::Accepted(stream, reader) => return Some((stream, reader)),
+            Handshake::Unavailable => {}, // retried if there are retries left
+            Handshake::Rejected => return None,
+        }
+    }
+    None
+}
+
+// This function reads a reply from a lab3 server, passing each of its lines to the given function
+// with any escaping removed. A reply is ended by the end of reply line. It returns the reason the
+// server gave if it replied with an error line, or says the connection closed if it ended before
+// the reply was finished, including when the server sent nothing at all.
+fn read_reply(reader: BufReader<TcpStream>, mut each_line: impl FnMut(&str)) -> Result<(), String> {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        if line == END_OF_REPLY {
+            return Ok(());
+        }
+        if let Some(reason) = line.strip_prefix(SERVER_ERROR_PREFIX) {
+            return Err(reason.trim().to_string());
+        }
+        each_line(unescape_reply_line(&line));
+    }
+    Err("the connection closed before the reply was finished".to_string())
+}
+
+// This function fetches the lines of a file from a lab3 server, given a name of the form
+// tcp://address/file. After connecting, the file name is sent to the server and every line it
+// replies with is trimmed and stored. It returns the same error as a file that could not be
+// opened if the fetch fails, which includes the server replying that it cannot serve the file
+// and the server closing the connection without finishing its reply.
+pub fn grab_remote_lines(file_name: &str, file_lines: &mut Vec<String>) -> Result<(), u8> {
+    grab_remote_lines_with_progress(file_name, file_lines, NO_PROGRESS, &mut |_| {})
+}
+
+// This function fetches the lines of a file from a lab3 server like grab_remote_lines, and calls
+// the given progress callback with the number of lines received so far each time another every
+// lines have been received. An interval of zero never calls it.
+pub fn grab_remote_lines_with_progress(
+    file_name: &str,
+    file_lines: &mut Vec<String>,
+    every: usize,
+    progress: &mut dyn FnMut(usize),
+) -> Result<(), u8> {
+    let open_failed = || {
+        match writeln!(std::io::stderr().lock(), "Error: script generation failed because the file {} could not be opened", file_name) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        Err(ERR_SCRIPT_GEN)
+    };
+    let (address, remote_file) = match file_name[TCP_PREFIX.len()..].split_once(PATH_SEPARATOR) {
+        Some((address, remote_file)) if !remote_file.is_empty() => (address, remote_file),
+        _ => return open_failed(),
+    };
+
+    let (mut stream, reader) = match connect_to_server(address) {
+        Some(connection) => connection,
+        None => return open_failed(),
+    };
+    if writeln!(stream, "{}", remote_file).is_err() {
+        return open_failed();
+    }
+    let mut lines = Vec::new();
+    let received = read_reply(reader, |line| {
+        lines.push(line.trim().to_string());
+        if every > 0 && lines.len() % every == 0 {
+            progress(lines.len());
+        }
+    });
+    if received.is_err() {
+        return open_failed();
+    }
+    trace_file_read(file_name, lines.len());
+    file_lines.append(&mut lines);
+    Ok(())
+}
+
+// This function asks the lab3 server at the given address to prepare and recite the named script
+// itself, and writes the transcript it sends back to the given writer. The server ends the
+// transcript with a sentinel line, or replies with an error line if the script is unknown or
+// could not be prepared. It returns an error if the server could not be reached, replied with an
+// error, or closed the connection before the transcript was finished.
+pub fn grab_remote_recitation(address: &str, script_name: &str, out: &mut dyn Write) -> Result<(), u8> {
+    let (mut stream, reader) = match connect_to_server(address) {
+        Some(connection) => connection,
+        None => {
+            match writeln!(std::io::stderr().lock(), "Error: could not connect to the server at {}", address) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            return Err(ERR_SCRIPT_GEN);
+        }
+    };
+    if writeln!(stream, "{} {}", GET_COMMAND, script_name).is_err() {
+        return Err(ERR_SCRIPT_GEN);
+    }
+    let received = read_reply(reader, |line| {
+        match writeln!(out, "{}", line) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+    });
+    if let Err(reason) = received {
+        match writeln!(std::io::stderr().lock(), "Error: the server could not recite {}: {}", script_name, reason) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        return Err(ERR_SCRIPT_GEN);
+    }
+    Ok(())
+}
@@ -0,0 +1,632 @@
+/*
+ * Author: Daniel Palmer
+ * Email: d.m.palmer@wustl.edu
+ * File: line_source.rs
+ * Summary: This file contains the LineSource trait, which is how the play reads the
+ * lines of its script, config, and part files, along with its implementations. The
+ * FsSource reads from the filesystem, the DirSource reads from the filesystem relative
+ * to a base directory, the MapSource serves in-memory "files", and the
+ * ZipSource reads the entries of a zip bundle, so that parsing does not depend on
+ * where the files come from.
+ *
+ */
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use super::declarations;
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50; // end of central directory record
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50; // central directory file header
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50; // local file header
+const EOCD_LEN: usize = 22;
+const MAX_ARCHIVE_COMMENT: usize = 0xFFFF;
+const EOCD_ENTRIES: usize = 10;
+const EOCD_DIRECTORY_OFFSET: usize = 16;
+const CENTRAL_HEADER_LEN: usize = 46;
+const CENTRAL_METHOD: usize = 10;
+const CENTRAL_COMPRESSED_SIZE: usize = 20;
+const CENTRAL_NAME_LEN: usize = 28;
+const CENTRAL_EXTRA_LEN: usize = 30;
+const CENTRAL_COMMENT_LEN: usize = 32;
+const CENTRAL_LOCAL_OFFSET: usize = 42;
+const LOCAL_HEADER_LEN: usize = 30;
+const LOCAL_NAME_LEN: usize = 26;
+const LOCAL_EXTRA_LEN: usize = 28;
+const STORED: u16 = 0; // compression method for entries that are not compressed
+const DIRECTORY_SUFFIX: char = '/';
+const UNIX_ROOT: char = '/';
+const WINDOWS_ROOT: char = '\\'; // also starts UNC paths such as \\server\share
+const DRIVE_SEPARATOR: char = ':';
+const PARENT_DIR: &str = "..";
+
+
+pub trait LineSource: Send + Sync {
+    // This function returns the trimmed lines of the named file, or an error if it could not be
+    // read
+    fn lines(&self, name: &str) -> Result<Vec<String>, u8>;
+
+    // This function returns the lines of the named file with only their ends trimmed, so that
+    // leading indentation is kept. Sources that cannot keep indentation return trimmed lines.
+    fn indented_lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        self.lines(name)
+    }
+
+    // This function returns the trimmed lines of the named file like lines, calling the given
+    // progress callback with the number of lines read so far each time another every lines have
+    // been read. Sources that do not read a file incrementally make the calls once it is read.
+    fn lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        let lines = self.lines(name)?;
+        report_read(lines.len(), every, progress);
+        Ok(lines)
+    }
+
+    // This function returns the lines of the named file like indented_lines, calling the given
+    // progress callback like lines_with_progress
+    fn indented_lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        let lines = self.indented_lines(name)?;
+        report_read(lines.len(), every, progress);
+        Ok(lines)
+    }
+}
+
+// This function calls the given progress callback for a file of the given number of lines that
+// has already been read, as if it had been called every lines while the file was read
+fn report_read(num_lines: usize, every: usize, progress: &mut dyn FnMut(usize)) {
+    if every > 0 {
+        for read in (every..=num_lines).step_by(every) {
+            progress(read);
+        }
+    }
+}
+
+// This function splits the given contents into lines, trimming each with the given function, and
+// calls the given progress callback with the number of lines split off so far each time another
+// every lines have been split off. An interval of zero never calls it.
+fn split_lines(contents: &str, trim: fn(&str) -> &str, every: usize, progress: &mut dyn FnMut(usize)) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in contents.lines() {
+        lines.push(trim(line).to_string());
+        if every > 0 && lines.len() % every == 0 {
+            progress(lines.len());
+        }
+    }
+    lines
+}
+
+// Sources are shared between the threads that prepare scenes and characters
+pub type SharedSource = Arc<dyn LineSource>;
+
+
+// Reads files from the filesystem, or from a lab3 server for names beginning with tcp://
+pub struct FsSource;
+
+impl LineSource for FsSource {
+    fn lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        let mut lines = Vec::new();
+        if name.starts_with(declarations::TCP_PREFIX) {
+            declarations::grab_remote_lines(name, &mut lines)?;
+        } else {
+            declarations::grab_trimmed_file_lines(name, &mut lines)?;
+        }
+        Ok(lines)
+    }
+
+    fn indented_lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        if name.starts_with(declarations::TCP_PREFIX) {
+            return self.lines(name);
+        }
+        let mut lines = Vec::new();
+        declarations::grab_end_trimmed_file_lines(name, &mut lines)?;
+        Ok(lines)
+    }
+
+    fn lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        let mut lines = Vec::new();
+        if name.starts_with(declarations::TCP_PREFIX) {
+            declarations::grab_remote_lines_with_progress(name, &mut lines, every, progress)?;
+        } else {
+            declarations::grab_trimmed_file_lines_with_progress(name, &mut lines, every, progress)?;
+        }
+        Ok(lines)
+    }
+
+    fn indented_lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        if name.starts_with(declarations::TCP_PREFIX) {
+            return self.lines_with_progress(name, every, progress);
+        }
+        let mut lines = Vec::new();
+        declarations::grab_end_trimmed_file_lines_with_progress(name, &mut lines, every, progress)?;
+        Ok(lines)
+    }
+}
+
+
+// Reads files from the filesystem relative to a base directory, such as the directory a server
+// serves scripts from. Names that would lead outside the base directory are refused, so that a
+// script cannot name part files the server does not serve.
+pub struct DirSource(pub PathBuf);
+
+impl DirSource {
+    // This function returns the path of the named file inside the base directory, or an error if
+    // the name leads outside it. Names of missing files are passed on so that opening them fails
+    // the same way it does for any other source.
+    fn resolve(&self, name: &str) -> Result<String, u8> {
+        match confined_path(&self.0, name) {
+            Ok(path) => Ok(path.to_string_lossy().to_string()),
+            Err(PathError::Missing) => Ok(self.0.join(name).to_string_lossy().to_string()),
+            Err(PathError::Outside) => {
+                match writeln!(std::io::stderr().lock(), "Error: script generation failed because the file {} is outside {}", name, self.0.display()) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                Err(declarations::ERR_SCRIPT_GEN)
+            }
+        }
+    }
+}
+
+impl LineSource for DirSource {
+    fn lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        FsSource.lines(&self.resolve(name)?)
+    }
+
+    fn indented_lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        FsSource.indented_lines(&self.resolve(name)?)
+    }
+
+    fn lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        FsSource.lines_with_progress(&self.resolve(name)?, every, progress)
+    }
+
+    fn indented_lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        FsSource.indented_lines_with_progress(&self.resolve(name)?, every, progress)
+    }
+}
+
+
+// Serves the contents of in-memory files keyed by file name
+pub struct MapSource(pub HashMap<String, String>);
+
+impl MapSource {
+    // This function returns the lines of the named file, trimming each with the given function and
+    // calling the given progress callback like split_lines
+    fn trimmed_lines(&self, name: &str, trim: fn(&str) -> &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        match self.0.get(name) {
+            Some(contents) => Ok(split_lines(contents, trim, every, progress)),
+            None => {
+                match writeln!(std::io::stderr().lock(), "Error: script generation failed because the file {} could not be opened", name) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                Err(declarations::ERR_SCRIPT_GEN)
+            }
+        }
+    }
+}
+
+impl LineSource for MapSource {
+    fn lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim, declarations::NO_PROGRESS, &mut |_| {})
+    }
+
+    fn indented_lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim_end, declarations::NO_PROGRESS, &mut |_| {})
+    }
+
+    fn lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim, every, progress)
+    }
+
+    fn indented_lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim_end, every, progress)
+    }
+}
+
+
+// Reads files from the entries of a zip archive, so that a whole play can be handed around as a
+// single bundle. Only entries stored without compression are supported, which is what zip -0
+// produces, so that no decompression code is needed.
+pub struct ZipSource {
+    path: String, // where the archive was read from
+    entries: HashMap<String, Vec<u8>>, // entry name to contents
+}
+
+impl ZipSource {
+    // This function reads the zip archive at the given path and loads the contents of its
+    // entries. It returns an error if the archive could not be read, is not a zip archive, or has
+    // compressed entries.
+    pub fn open(path: &str) -> Result<Self, u8> {
+        let parsed = match std::fs::read(path) {
+            Ok(bytes) => Self::parse(&bytes),
+            Err(_) => Err("it could not be opened"),
+        };
+        match parsed {
+            Ok(entries) => Ok(Self { path: path.to_string(), entries }),
+            Err(reason) => {
+                match writeln!(std::io::stderr().lock(), "Error: the bundle {} could not be read because {}", path, reason) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                Err(declarations::ERR_SCRIPT_GEN)
+            }
+        }
+    }
+
+    // This function finds the central directory of a zip archive through the record at its end
+    // and returns the contents of each file entry listed there, or why the archive could not be
+    // read
+    fn parse(bytes: &[u8]) -> Result<HashMap<String, Vec<u8>>, &'static str> {
+        let not_zip = "it is not a zip archive";
+        let last_start = bytes.len().checked_sub(EOCD_LEN).ok_or(not_zip)?;
+        let first_start = last_start.saturating_sub(MAX_ARCHIVE_COMMENT);
+        let eocd = (first_start..=last_start).rev()
+            .find(|&at| read_u32(bytes, at) == Some(EOCD_SIGNATURE))
+            .ok_or(not_zip)?;
+        let num_entries = read_u16(bytes, eocd + EOCD_ENTRIES).ok_or(not_zip)?;
+        let mut at = read_u32(bytes, eocd + EOCD_DIRECTORY_OFFSET).ok_or(not_zip)? as usize;
+
+        let mut entries = HashMap::new();
+        for _ in 0..num_entries {
+            if read_u32(bytes, at) != Some(CENTRAL_SIGNATURE) {
+                return Err(not_zip);
+            }
+            let method = read_u16(bytes, at + CENTRAL_METHOD).ok_or(not_zip)?;
+            let size = read_u32(bytes, at + CENTRAL_COMPRESSED_SIZE).ok_or(not_zip)? as usize;
+            let name_len = read_u16(bytes, at + CENTRAL_NAME_LEN).ok_or(not_zip)? as usize;
+            let extra_len = read_u16(bytes, at + CENTRAL_EXTRA_LEN).ok_or(not_zip)? as usize;
+            let comment_len = read_u16(bytes, at + CENTRAL_COMMENT_LEN).ok_or(not_zip)? as usize;
+            let local = read_u32(bytes, at + CENTRAL_LOCAL_OFFSET).ok_or(not_zip)? as usize;
+            let name_start = at + CENTRAL_HEADER_LEN;
+            let name = bytes.get(name_start..name_start + name_len).ok_or(not_zip)?;
+            let name = String::from_utf8_lossy(name).to_string();
+            at = name_start + name_len + extra_len + comment_len;
+
+            if name.ends_with(DIRECTORY_SUFFIX) {
+                continue;
+            }
+            if method != STORED {
+                return Err("it has compressed entries, and only uncompressed (stored) entries are supported");
+            }
+            if read_u32(bytes, local) != Some(LOCAL_SIGNATURE) {
+                return Err(not_zip);
+            }
+            let local_name_len = read_u16(bytes, local + LOCAL_NAME_LEN).ok_or(not_zip)? as usize;
+            let local_extra_len = read_u16(bytes, local + LOCAL_EXTRA_LEN).ok_or(not_zip)? as usize;
+            let data_start = local + LOCAL_HEADER_LEN + local_name_len + local_extra_len;
+            let data = bytes.get(data_start..data_start + size).ok_or(not_zip)?;
+            entries.insert(name, data.to_vec());
+        }
+        Ok(entries)
+    }
+
+    // This function returns the lines of the named entry, trimming each with the given function and
+    // calling the given progress callback like split_lines. The archive is traced as the file that
+    // was read.
+    fn trimmed_lines(&self, name: &str, trim: fn(&str) -> &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        let bytes = match self.entries.get(name) {
+            Some(bytes) => bytes,
+            None => {
+                match writeln!(std::io::stderr().lock(), "Error: script generation failed because the file {} is not in the bundle", name) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return Err(declarations::ERR_SCRIPT_GEN);
+            }
+        };
+        let contents = match std::str::from_utf8(bytes) {
+            Ok(s) => s.to_string(),
+            Err(_) if declarations::LOSSY_ON.load(Ordering::SeqCst) => String::from_utf8_lossy(bytes).to_string(),
+            Err(_) => {
+                match writeln!(std::io::stderr().lock(), "Error: script generation failed because the file {} contains invalid UTF-8", name) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return Err(declarations::ERR_SCRIPT_GEN);
+            }
+        };
+        let lines = split_lines(&contents, trim, every, progress);
+        declarations::trace_bundle_read(&self.path, name, lines.len());
+        Ok(lines)
+    }
+}
+
+impl LineSource for ZipSource {
+    fn lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim, declarations::NO_PROGRESS, &mut |_| {})
+    }
+
+    fn indented_lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim_end, declarations::NO_PROGRESS, &mut |_| {})
+    }
+
+    fn lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim, every, progress)
+    }
+
+    fn indented_lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        self.trimmed_lines(name, str::trim_end, every, progress)
+    }
+}
+
+
+// This function returns whether the given path is absolute on either Unix or Windows, so that a
+// script written on one resolves the same way on the other. Paths starting with / or \, which
+// includes UNC paths such as \\server\share, are absolute, as are paths starting with a drive
+// letter followed by :\ or :/. A drive letter without a separator, as in C:parts, is relative to
+// that drive's current directory, so it is not absolute.
+pub fn is_absolute_cross_platform(path: &str) -> bool {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(UNIX_ROOT | WINDOWS_ROOT), _, _) => true,
+        (Some(drive), Some(DRIVE_SEPARATOR), Some(UNIX_ROOT | WINDOWS_ROOT)) => drive.is_ascii_alphabetic(),
+        _ => std::path::Path::new(path).is_absolute(),
+    }
+}
+
+
+// Why a file name could not be found inside a base directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PathError {
+    Outside, // the name is absolute, climbs out with .., or leads out through a link
+    Missing, // the name stays inside but there is nothing there
+}
+
+// This function returns the path of the named file inside the given base directory, for serving
+// files to clients that must not see anything else. Names that are absolute on any platform or
+// have a .. part are refused without looking at the disk, and the path that is found is checked
+// to still be inside the base directory once links are followed.
+pub fn confined_path(base_dir: &Path, name: &str) -> Result<PathBuf, PathError> {
+    if is_absolute_cross_platform(name) || name.split([UNIX_ROOT, WINDOWS_ROOT]).any(|part| part == PARENT_DIR) {
+        return Err(PathError::Outside);
+    }
+    let base = base_dir.canonicalize().map_err(|_| PathError::Missing)?;
+    let path = base.join(name).canonicalize().map_err(|_| PathError::Missing)?;
+    if path.starts_with(&base) {
+        Ok(path)
+    } else {
+        Err(PathError::Outside)
+    }
+}
+
+
+// These functions read little endian integers from the given offset, returning None if the bytes
+// run out
+fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use crate::lab3::play::Play;
+    use crate::lab3::style::StyleConfig;
+    use crate::lab3::test_support::{lock_globals, map_source, recite_to_string, temp_dir, TWO_SCENE_PLAY};
+
+    #[test]
+    fn full_play_is_built_from_a_map_source_without_the_disk() {
+        let _globals = lock_globals();
+        for (name, _) in TWO_SCENE_PLAY {
+            assert!(!std::path::Path::new(name).exists(), "{} must not be on disk for this test", name);
+        }
+        let mut play = Play::with_source(map_source(TWO_SCENE_PLAY));
+        assert_eq!(play.prepare("script.txt"), Ok(()));
+        let expected = concat!(
+            "\n",
+            "Act I\n\n",
+            "[Enter Hamlet.]\n[Enter Ghost.]\n\n",
+            " Hamlet\nWho's there?\n\n",
+            " Ghost\nMark me.\n\n",
+            " Hamlet\nSpeak, I am bound to hear.\n\n",
+            " Ghost\nI am thy father's spirit.\n\n",
+            "[Exit Ghost.]\n\n\n",
+            "Act II\n\n",
+            "[Enter Horatio.]\n\n",
+            " Hamlet\nHoratio, or I do forget myself.\n\n",
+            " Horatio\nThe same, my lord.\n\n",
+            "[Exit Horatio.]\n[Exit Hamlet.]\n\n",
+        );
+        assert_eq!(recite_to_string(&mut play, &StyleConfig::default()), expected);
+    }
+
+    #[test]
+    fn confined_path_refuses_names_that_leave_the_base_directory() {
+        let _globals = lock_globals();
+        let dir = temp_dir("confined");
+        std::fs::create_dir_all(dir.join("base/parts")).unwrap();
+        std::fs::write(dir.join("base/script.txt"), "").unwrap();
+        std::fs::write(dir.join("base/parts/a.txt"), "").unwrap();
+        std::fs::write(dir.join("secret.txt"), "").unwrap();
+        let base = dir.join("base");
+        assert_eq!(confined_path(&base, "script.txt"), Ok(base.canonicalize().unwrap().join("script.txt")));
+        assert!(confined_path(&base, "parts/a.txt").is_ok());
+        assert_eq!(confined_path(&base, "../secret.txt"), Err(PathError::Outside));
+        assert_eq!(confined_path(&base, "parts/../../secret.txt"), Err(PathError::Outside));
+        assert_eq!(confined_path(&base, "parts\\..\\..\\secret.txt"), Err(PathError::Outside));
+        assert_eq!(confined_path(&base, &dir.join("secret.txt").to_string_lossy()), Err(PathError::Outside));
+        assert_eq!(confined_path(&base, "/etc/passwd"), Err(PathError::Outside));
+        assert_eq!(confined_path(&base, "C:\\Windows\\win.ini"), Err(PathError::Outside));
+        assert_eq!(confined_path(&base, "absent.txt"), Err(PathError::Missing));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confined_path_refuses_links_out_of_the_base_directory() {
+        let _globals = lock_globals();
+        let dir = temp_dir("confined_link");
+        std::fs::create_dir_all(dir.join("base")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("base/link.txt")).unwrap();
+        assert_eq!(confined_path(&dir.join("base"), "link.txt"), Err(PathError::Outside));
+    }
+
+    #[test]
+    fn missing_map_file_is_an_error() {
+        let _globals = lock_globals();
+        let source = MapSource(HashMap::new());
+        assert_eq!(source.lines("absent.txt"), Err(declarations::ERR_SCRIPT_GEN));
+    }
+
+    #[test]
+    fn part_file_with_invalid_utf8_fails_unless_lossy() {
+        let _globals = lock_globals();
+        let dir = temp_dir("lossy");
+        std::fs::write(dir.join("script.txt"), "[scene] Act I\nscene.txt\n").unwrap();
+        std::fs::write(dir.join("scene.txt"), "Hamlet hamlet.txt\n").unwrap();
+        std::fs::write(dir.join("hamlet.txt"), b"1 Who's th\xffere?\n").unwrap();
+        let source: SharedSource = Arc::new(DirSource(dir));
+        assert_eq!(Play::with_source(Arc::clone(&source)).prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+
+        declarations::LOSSY_ON.store(true, Ordering::SeqCst);
+        let mut play = Play::with_source(source);
+        assert_eq!(play.prepare("script.txt"), Ok(()));
+        assert!(recite_to_string(&mut play, &StyleConfig::default()).contains("Who's th\u{FFFD}ere?"));
+    }
+
+    #[test]
+    fn every_file_of_a_small_play_is_traced_once() {
+        let _globals = lock_globals();
+        declarations::TRACE_FILES_ON.store(true, Ordering::SeqCst);
+        let dir = temp_dir("trace");
+        for (name, contents) in TWO_SCENE_PLAY {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        let mut play = Play::with_source(Arc::new(DirSource(dir.clone())));
+        assert_eq!(play.prepare("script.txt"), Ok(()));
+        let base = dir.canonicalize().unwrap();
+        let mut expected: Vec<String> = TWO_SCENE_PLAY.iter().map(|(name, _)| base.join(name).to_string_lossy().into_owned()).collect();
+        expected.sort();
+        assert_eq!(declarations::take_files_read(), expected);
+        assert_eq!(declarations::file_read_trace("hamlet1.txt", 2), "Reading hamlet1.txt (2 lines)");
+    }
+
+    // This function returns a zip archive holding the given (name, contents) pairs as stored
+    // entries. The checksums are left as zero, since the ZipSource does not check them.
+    fn stored_zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut local = Vec::new();
+        let mut central = Vec::new();
+        for (name, contents) in files {
+            let offset = local.len() as u32;
+            let (name_len, size) = (name.len() as u16, contents.len() as u32);
+            local.extend(LOCAL_SIGNATURE.to_le_bytes());
+            local.extend([0; 14]); // version, flags, method, time, date, checksum
+            local.extend(size.to_le_bytes());
+            local.extend(size.to_le_bytes());
+            local.extend(name_len.to_le_bytes());
+            local.extend(0u16.to_le_bytes());
+            local.extend(name.as_bytes());
+            local.extend(contents.as_bytes());
+
+            central.extend(CENTRAL_SIGNATURE.to_le_bytes());
+            central.extend([0; CENTRAL_COMPRESSED_SIZE - 4]); // versions, flags, method, time, date, checksum
+            central.extend(size.to_le_bytes());
+            central.extend(size.to_le_bytes());
+            central.extend(name_len.to_le_bytes());
+            central.extend([0; CENTRAL_LOCAL_OFFSET - CENTRAL_EXTRA_LEN]); // extra, comment, disk, attributes
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+        let mut archive = local;
+        let directory_offset = archive.len() as u32;
+        let entries = files.len() as u16;
+        archive.extend(central.iter());
+        archive.extend(EOCD_SIGNATURE.to_le_bytes());
+        archive.extend([0; EOCD_ENTRIES - 6]); // disk numbers and entries on this disk
+        archive.extend(entries.to_le_bytes());
+        archive.extend(entries.to_le_bytes());
+        archive.extend((central.len() as u32).to_le_bytes());
+        archive.extend(directory_offset.to_le_bytes());
+        archive.extend(0u16.to_le_bytes());
+        archive
+    }
+
+    #[test]
+    fn full_play_is_recited_from_a_zip_bundle() {
+        let _globals = lock_globals();
+        let bundle = temp_dir("zip_play").join("play.zip");
+        std::fs::write(&bundle, stored_zip(TWO_SCENE_PLAY)).unwrap();
+        let bundle = bundle.to_string_lossy().into_owned();
+        let style = StyleConfig::default();
+
+        let mut zipped = Play::with_source(Arc::new(ZipSource::open(&bundle).expect("the bundle should open")));
+        assert_eq!(zipped.prepare("script.txt"), Ok(()));
+        let mut mapped = Play::with_source(map_source(TWO_SCENE_PLAY));
+        assert_eq!(mapped.prepare("script.txt"), Ok(()));
+        assert_eq!(recite_to_string(&mut zipped, &style), recite_to_string(&mut mapped, &style));
+    }
+
+    #[test]
+    fn files_read_from_a_zip_bundle_are_traced_as_the_bundle() {
+        let _globals = lock_globals();
+        let bundle = temp_dir("zip_trace").join("play.zip");
+        std::fs::write(&bundle, stored_zip(TWO_SCENE_PLAY)).unwrap();
+        let bundle = bundle.to_string_lossy().into_owned();
+        let mut play = Play::with_source(Arc::new(ZipSource::open(&bundle).expect("the bundle should open")));
+        assert_eq!(play.prepare("script.txt"), Ok(()));
+        assert_eq!(declarations::take_files_read(), vec![bundle]);
+    }
+
+    // A part file of seven indented lines, which is read with progress reported every two lines
+    const INDENTED_PART: &str = "1   One\n2   Two\n3   Three\n4   Four\n5   Five\n6   Six\n7   Seven\n";
+    const EVERY: usize = 2;
+
+    // This function reads the part file from the given source, with and without its indentation,
+    // and checks that the indentation was kept only when asked for and that progress was reported
+    // after lines 2, 4, and 6 each time
+    fn assert_progress_is_reported(source: &dyn LineSource, name: &str) {
+        let mut reported = Vec::new();
+        let lines = source.lines_with_progress(name, EVERY, &mut |read| reported.push(read)).unwrap();
+        assert_eq!((lines.len(), lines[0].as_str()), (7, "1   One"));
+        assert_eq!(reported, vec![2, 4, 6]);
+
+        let mut reported = Vec::new();
+        let lines = source.indented_lines_with_progress(name, EVERY, &mut |read| reported.push(read)).unwrap();
+        assert_eq!((lines.len(), lines[0].as_str()), (7, "  1   One"));
+        assert_eq!(reported, vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn progress_is_reported_every_few_lines_from_every_source() {
+        let _globals = lock_globals();
+        let indented = format!("  {}", INDENTED_PART.replace('\n', "\n  ").trim_end());
+        let dir = temp_dir("progress");
+        std::fs::write(dir.join("part.txt"), &indented).unwrap();
+        std::fs::write(dir.join("play.zip"), stored_zip(&[("part.txt", &indented)])).unwrap();
+
+        assert_progress_is_reported(&FsSource, &dir.join("part.txt").to_string_lossy());
+        assert_progress_is_reported(&DirSource(dir.clone()), "part.txt");
+        assert_progress_is_reported(map_source(&[("part.txt", &indented)]).as_ref(), "part.txt");
+        assert_progress_is_reported(&ZipSource::open(&dir.join("play.zip").to_string_lossy()).unwrap(), "part.txt");
+    }
+
+    #[test]
+    fn directory_given_as_a_script_is_not_read() {
+        let _globals = lock_globals();
+        let dir = temp_dir("directory_script");
+        std::fs::create_dir_all(dir.join("script.txt")).unwrap();
+        let mut play = Play::with_source(Arc::new(DirSource(dir.clone())));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+        let mut lines = Vec::new();
+        assert_eq!(declarations::grab_trimmed_file_lines(&dir.to_string_lossy(), &mut lines), Err(declarations::ERR_SCRIPT_GEN));
+        assert!(lines.is_empty());
+    }
+
+    #[test]
+    fn absolute_paths_are_recognized_for_either_platform() {
+        for path in ["/parts/hamlet.txt", r"C:\parts\hamlet.txt", "c:/parts/hamlet.txt", r"\\server\share\hamlet.txt"] {
+            assert!(is_absolute_cross_platform(path), "{}", path);
+        }
+        for path in ["hamlet.txt", "parts/hamlet.txt", r"parts\hamlet.txt", "C:parts", ""] {
+            assert!(!is_absolute_cross_platform(path), "{}", path);
+        }
+    }
+}
@@ -8,7 +8,12 @@
  */
 
 pub mod declarations;
+pub mod line_source;
 pub mod play;
 pub mod player;
 pub mod return_wrapper;
+pub mod rng;
 pub mod scene_fragment;
+pub mod style;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
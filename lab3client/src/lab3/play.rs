@@ -10,16 +10,28 @@
  * 
  */
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::Write;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::thread;
-use super::scene_fragment::SceneFragment;
+use std::time::{Duration, Instant};
+use super::scene_fragment::{SceneFragment, SpokenLine};
 use super::declarations;
+use super::line_source::{FsSource, LineSource, SharedSource};
+use super::style::{StyleConfig, wrap_text};
 
 
 type ScriptConfig = Vec<(bool, String)>;
+pub type Interlude<'a> = dyn FnMut(usize, usize, &mut dyn Write) + 'a; // called between scenes with their indices
 
 const SCENE_INDICATOR: &str = "[scene]";
+const INCLUDE_INDICATOR: &str = "[include]";
+const COMMENT_INDICATOR: &str = "[comment]";
+const INCLUDE_TOKENS: usize = 2;
+const TITLE_QUOTE: char = '"';
 const EMPTY: usize = 0;
 const SINGLE_TOKEN: usize = 1;
 const FIRST_TOKEN: usize = 0;
@@ -29,6 +41,21 @@ const CONFIG_FILE_BOOL: bool = false;
 const FIRST_FRAGMENT: usize = 0;
 const SECOND_FRAGMENT: usize = 1;
 const START: usize = 0;
+const FIRST_SCENE_NUMBER: usize = 1;
+const UNTITLED: &str = "(untitled)";
+const UNAVAILABLE: &str = "(unavailable)";
+const SINGLE_USE: usize = 1;
+const SEPARATOR_CHAR: char = '=';
+const SEPARATOR_WIDTH: usize = 40;
+const MIN_LINE_DENSITY: f64 = 0.1; // fewest lines per line number spanned before a scene is suspicious
+const MARKDOWN_SPECIAL: &str = "\\`*_[]<>#|~!"; // escaped wherever they appear
+const MARKDOWN_LIST_MARKERS: &str = "-+"; // escaped at the start of a line
+const MARKDOWN_ESCAPE: char = '\\';
+const MARKDOWN_BLOCK_SEPARATOR: &str = "\n\n";
+const MARKDOWN_LINE_BREAK: &str = "  \n";
+const COLUMN_SEPARATOR: &str = " | ";
+const WRITE_BACK_SCRIPT: &str = "script"; // the stem of the script file written by write_back
+const WRITE_BACK_SCENE: &str = "scene"; // the stem of each scene's config file, before its number
 
 
 macro_rules! poison_mutex_print {
@@ -41,87 +68,269 @@ macro_rules! poison_mutex_print {
 }
 
 
+// A difference between two editions of a play, found by comparing the scenes at the same position
+// and the lines in them by character and line number
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DiffEntry {
+    SceneCount { ours: usize, theirs: usize },
+    Added { scene: usize, character: String, number: usize, text: String },
+    Removed { scene: usize, character: String, number: usize, text: String },
+    Changed { scene: usize, character: String, number: usize, old: String, new: String },
+}
+
+
+// What a recitation produced, so that callers can check how much was recited and whether
+// anything went wrong along the way
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ReciteReport {
+    pub lines_spoken: usize, // lines printed, not counting names or stage directions
+    pub scenes: usize, // scenes recited, leaving out any that were skipped
+    pub warnings: usize, // warnings recorded while reciting
+}
+
+
 pub struct Play {
     fragments: Vec<Arc<Mutex<SceneFragment>>>,
+    source: SharedSource, // where the script, config, and part files are read from
+}
+
+
+impl Default for Play {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 
 impl Play {
     pub fn new() -> Self {
+        Self::with_source(Arc::new(FsSource))
+    }
+
+    // This function creates an empty play that reads its files from the given source rather than
+    // the filesystem
+    pub fn with_source(source: SharedSource) -> Self {
         Self {
             fragments: Vec::new(),
+            source,
+        }
+    }
+
+    // This function returns an independent copy of the prepared play that has not been recited
+    // yet, so that the same play can be recited several times, or on several threads at once,
+    // without preparing it again. A scene whose mutex was poisoned is left out.
+    pub fn fresh_copy(&self) -> Self {
+        let mut copy = Self::with_source(Arc::clone(&self.source));
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => copy.fragments.push(Arc::new(Mutex::new(f_guard.fresh_copy()))),
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+        copy
+    }
+
+    // This function combines this play with another into a new play whose scenes alternate between
+    // the two, starting with this play's first scene. Once the shorter play runs out of scenes the
+    // rest of the longer play's scenes follow in order. The new play reads files from this play's
+    // source.
+    pub fn interleave(self, other: Play) -> Play {
+        let mut combined = Self::with_source(self.source);
+        let mut mine = self.fragments.into_iter();
+        let mut theirs = other.fragments.into_iter();
+        loop {
+            match (mine.next(), theirs.next()) {
+                (None, None) => break,
+                (a, b) => combined.fragments.extend(a.into_iter().chain(b)),
+            }
+        }
+        combined
+    }
+
+    // This function consumes the play and returns its scenes in order, so that they can be
+    // rearranged or edited outside of the play
+    pub fn into_fragments(self) -> Vec<Arc<Mutex<SceneFragment>>> {
+        self.fragments
+    }
+
+    // This function creates a play made up of the given scenes in order, which reads any further
+    // files from the filesystem. The scenes can come from into_fragments on one or more plays.
+    pub fn from_fragments(fragments: Vec<Arc<Mutex<SceneFragment>>>) -> Self {
+        let mut play = Self::new();
+        play.fragments = fragments;
+        play
+    }
+
+    // This function removes the scene at the given index from the play. The entrances and exits
+    // around it are worked out again when the play is recited. It returns an error if there is no
+    // scene at that index.
+    pub fn remove_scene(&mut self, index: usize) -> Result<(), u8> {
+        if index >= self.fragments.len() {
+            return Err(Self::scene_index_error(index));
+        }
+        self.fragments.remove(index);
+        Ok(())
+    }
+
+    // This function inserts a scene into the play at the given index, shifting the scenes from
+    // that index on back by one. An index one past the last scene adds the scene at the end. It
+    // returns an error if the index is past that.
+    pub fn insert_scene(&mut self, index: usize, frag: SceneFragment) -> Result<(), u8> {
+        if index > self.fragments.len() {
+            return Err(Self::scene_index_error(index));
+        }
+        self.fragments.insert(index, Arc::new(Mutex::new(frag)));
+        Ok(())
+    }
+
+    // This function prints that there is no scene at the given index and returns the error for it
+    fn scene_index_error(index: usize) -> u8 {
+        match writeln!(std::io::stderr().lock(), "Error: there is no scene at index {}", index) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
         }
+        declarations::ERR_SCENE_INDEX
     }
 
-    // This function processes a passed in ScriptConfig. For each item in the ScriptConfig if it contains a scene title it updates the title and otherwise creates a new SceneFragment, adds it to the Play's fragments, and prepares the fragment with its associated file. If it fails, the error is propagated out and otherwise Ok(()) is returned
-    fn process_config(&mut self, script_config: &ScriptConfig) -> Result<(), u8> {
+    // This function processes a passed in ScriptConfig. Each scene title becomes the title of the
+    // next fragment, and each scene file gets a new SceneFragment prepared from it on its own
+    // thread, with its own copy of the files already read so that circular includes are caught.
+    // Prepared fragments are added to the Play's fragments in script order, and the progress
+    // callback, if any, is called as each one arrives. A script with more scenes than the maximum
+    // is refused before any thread is spawned, and if a prepare timeout was given the whole script
+    // must be prepared within it. If it fails, the error is propagated out and otherwise Ok(()) is
+    // returned
+    fn process_config(
+        &mut self,
+        script_config: &ScriptConfig,
+        includes: &HashSet<String>,
+        mut progress: Option<&mut dyn FnMut(usize, usize)>,
+    ) -> Result<(), u8> {
+        use std::sync::atomic::Ordering;
+        let max_scenes = declarations::MAX_SCENES.load(Ordering::SeqCst);
+        if script_config.iter().filter(|(is_scene, _)| !*is_scene).count() > max_scenes {
+            match writeln!(std::io::stderr().lock(), "Error: script exceeds maximum scene count {}", max_scenes) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            return Err(declarations::ERR_SCRIPT_GEN);
+        }
+        let timeout_ms = declarations::PREPARE_TIMEOUT_MS.load(Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
         let mut title  = String::new();
-        let mut thread_handles = Vec::new();
+        let mut pending_fragments = Vec::new();
         for tup in script_config {
             match tup {
                 (true, text) => { //Text is a new title
                     title = text.clone();
                 },
                 (false, text) => {
-                    let text = text.to_string();
+                    let thread_text = text.to_string();
                     let mut frag = SceneFragment::new(&title);
-                    let handle = thread::spawn( move || -> SceneFragment{
-                        frag.prepare(&text);
-                        frag
+                    let mut frag_includes = includes.clone();
+                    let frag_source = Arc::clone(&self.source);
+                    let (sender, receiver) = mpsc::channel();
+                    thread::spawn( move || {
+                        let start = Instant::now();
+                        let prepared = frag.prepare(&thread_text, &mut frag_includes, frag_source);
+                        frag.prepare_time = start.elapsed();
+                        match sender.send(prepared.map(|_| frag)) {
+                            Ok(_) => {}, //success
+                            Err(_) => {}, //the play stopped waiting for this fragment
+                        }
                     });
+                    pending_fragments.push((title.clone(), text, receiver));
                     title = "".to_string();
-
-                    thread_handles.push(handle);
                 }
             }
         }
-        for h in thread_handles {
-            match h.join() {
-                Err(_) => {
+
+        let total = pending_fragments.len();
+        for (prepared, (frag_title, frag_file, receiver)) in pending_fragments.into_iter().enumerate() {
+            let received = if timeout_ms == declarations::NO_TIMEOUT {
+                receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            };
+            match received {
+                Err(RecvTimeoutError::Disconnected) => {
                     return Err(declarations::ERR_SCRIPT_GEN)
-                } //thread panicked
-                Ok(frag) => {
+                } //thread panicked before sending its fragment
+                Err(RecvTimeoutError::Timeout) => {
+                    match writeln!(std::io::stderr().lock(), "Error: scene \"{}\" ({}) was not prepared within {} ms", frag_title, frag_file, timeout_ms) {
+                        Ok(_) => {}, //success
+                        Err(_) => {}, //fail
+                    }
+                    return Err(declarations::ERR_TIMEOUT)
+                }
+                Ok(Err(e)) => {
+                    return Err(e)
+                }
+                Ok(Ok(frag)) => {
                     self.fragments.push(Arc::new(Mutex::new(frag)));
+                    if let Some(callback) = progress.as_mut() {
+                        callback(prepared + 1, total);
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    // This function returns the contents of a quoted scene title on a [scene] line exactly as
+    // written, or None if the title is not quoted. An unterminated quote is complained about in
+    // whinge mode and also yields None so that the title is treated as unquoted.
+    fn quoted_title(line: &str) -> Option<String> {
+        let after_indicator = line[SCENE_INDICATOR.len()..].trim_start();
+        let inner = after_indicator.strip_prefix(TITLE_QUOTE)?;
+        match inner.find(TITLE_QUOTE) {
+            Some(end) => Some(inner[..end].to_string()),
+            None => {
+                declarations::whinge(&format!("Warning: unterminated quote in scene title {}, treating it as unquoted", after_indicator));
+                None
+            }
+        }
+    }
+
     // This function separates the tokens in the passed in line, creating a new scene if the first
-    // token is [scene] and there is a scene title after. Otherwise, treats the first token as a
-    // config file. In either success case an element containing the info is pushed to the passed
+    // token is [scene] and there is a scene title after. A quoted title is used verbatim, and
+    // otherwise the title's tokens are joined by single spaces. Otherwise, treats the first token as
+    // a config file. In either success case an element containing the info is pushed to the passed
     // in ScriptConfig, and in the event of an empty line or [scene] is the first token with
-    // nothing after nothing is pushed.
+    // nothing after nothing is pushed. A line starting with [comment] is a note from the author
+    // that is ignored, although it is echoed in whinge mode. A title ending in what looks like a
+    // file name is complained about in whinge mode, but is still used as it is.
     fn add_config(line: &str, script_config: &mut ScriptConfig) {
         let trimmed = line.trim();
         let tokens: Vec<&str> = trimmed.split_whitespace().collect();
         if tokens.len() == EMPTY {
             return;
         }
-        if tokens.len() == SINGLE_TOKEN && tokens[FIRST_TOKEN] == SCENE_INDICATOR {
-            use std::sync::atomic::Ordering;
-            if declarations::WHINGE_ON.load(Ordering::SeqCst){
-                match writeln!(std::io::stderr().lock(), "Warning: scene identified but has no title so has not been added") {
-                    Ok(_) => {}, //success
-                    Err(_) => {}, //fail
-                }
+        if tokens[FIRST_TOKEN] == COMMENT_INDICATOR {
+            if tokens.len() != SINGLE_TOKEN {
+                declarations::whinge(&format!("Note: {}", tokens[SECOND_TOKEN..].join(" ")));
             }
             return;
         }
+        if tokens.len() == SINGLE_TOKEN && tokens[FIRST_TOKEN] == SCENE_INDICATOR {
+            declarations::whinge("Warning: scene identified but has no title so has not been added");
+            return;
+        }
         if tokens[FIRST_TOKEN] == SCENE_INDICATOR {
-            let rest = tokens[SECOND_TOKEN..].join(" ");
-            script_config.push((NEW_SCENE_BOOL, rest));
+            let title = match Self::quoted_title(trimmed) {
+                Some(quoted) => quoted,
+                None => tokens[SECOND_TOKEN..].join(" "),
+            };
+            if title.split_whitespace().last().is_some_and(|last| last.ends_with(&declarations::part_file_extension())) {
+                declarations::whinge(&format!("Warning: scene title \"{}\" ends with something that looks like a file name; is the [scene] line malformed?", title));
+            }
+            script_config.push((NEW_SCENE_BOOL, title));
         } else {
             script_config.push((CONFIG_FILE_BOOL, tokens[FIRST_TOKEN].to_string()));
             if tokens.len() != SINGLE_TOKEN{
-                use std::sync::atomic::Ordering;
-                if declarations::WHINGE_ON.load(Ordering::SeqCst) {
-                    match writeln!(std::io::stderr().lock(), "Warning: there are additional tokens in the line \"{}\" that is being treated as a config file name", line){
-                        Ok(_) => {}, //success
-                        Err(_) => {}, //fail
-                    }
-                }
+                declarations::whinge(&format!("Warning: there are additional tokens in the line \"{}\" that is being treated as a config file name", line));
             }
         }
             
@@ -129,12 +338,38 @@ impl Play {
 
 
 
+    // This function returns the file named by an [include] line, or None if the line is not an
+    // include. An [include] with no file name is complained about in whinge mode and ignored, as
+    // are any tokens after the file name.
+    fn include_target(line: &str) -> Option<String> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() == EMPTY || tokens[FIRST_TOKEN] != INCLUDE_INDICATOR {
+            return None;
+        }
+        if tokens.len() == SINGLE_TOKEN {
+            declarations::whinge("Warning: include identified but has no file name so has been ignored");
+            return Some(String::new());
+        }
+        if tokens.len() > INCLUDE_TOKENS {
+            declarations::whinge(&format!("Warning: there are additional tokens after the included file in the line \"{}\"", line));
+        }
+        Some(tokens[SECOND_TOKEN].to_string())
+    }
+
     // This function reads a given script file name and populates the passed in 
-    // script_config with the relevant information from this config file. It propagates any errors
-    // out and otherwise returns Ok(())
-    fn read_config(script_file_name: &str, script_config: &mut ScriptConfig) -> Result<(), u8> {
-        let mut lines: Vec<String> = Vec::new();
-        declarations::grab_trimmed_file_lines(script_file_name, &mut lines)?;
+    // script_config with the relevant information from this config file. The lines of any file
+    // named by an [include] line are spliced in at that point. The file is recorded in includes
+    // so that a file including itself, directly or through other files, is reported as circular.
+    // Each include gets its own copy of the chain so that including the same file twice in a row
+    // is allowed. It propagates any errors out and otherwise returns Ok(())
+    fn read_config(
+        script_file_name: &str,
+        script_config: &mut ScriptConfig,
+        includes: &mut HashSet<String>,
+        source: &dyn LineSource,
+    ) -> Result<(), u8> {
+        declarations::check_circular_include(script_file_name, includes)?;
+        let lines = source.lines(script_file_name)?;
         if lines.len() == EMPTY {
             match writeln!(std::io::stderr().lock(), "Error: the script gen file must contain at least 1 line"){
                 Ok(_) => {}, //success
@@ -143,33 +378,82 @@ impl Play {
             return Err(declarations::ERR_SCRIPT_GEN);
         }
         for line in &lines {
-            Self::add_config(line, script_config);
+            match Self::include_target(line) {
+                Some(included) => {
+                    if !included.is_empty() {
+                        let mut chain = includes.clone();
+                        Self::read_config(&included, script_config, &mut chain, source)?;
+                    }
+                }
+                None => Self::add_config(line, script_config),
+            }
         }
         Ok(())
     }
 
 
+    // This function echoes each entry of a parsed ScriptConfig in a normalized form, SCENE: and the
+    // title for a new scene or FILE: and the path for a config file, so that it can be checked
+    // exactly what the parser extracted from the script
+    fn echo_config(script_config: &ScriptConfig) {
+        let mut echo = String::new();
+        for (is_scene, text) in script_config {
+            let kind = if *is_scene { "SCENE" } else { "FILE" };
+            echo.push_str(&format!("{}: {}\n", kind, text));
+        }
+        declarations::echo_config(&echo);
+    }
+
+
     // This method does the script generation for a given play. It uses the above functions to
     // populate the self Play with associated information.
     pub fn prepare(&mut self, script_file_name: &str) -> Result<(), u8> {
+        self.prepare_with_progress(script_file_name, None)
+    }
+
+    // This method does the script generation for a given play like prepare, calling the given
+    // progress callback, if any, with the number of scenes prepared so far and the total number of
+    // scenes each time a scene finishes preparing.
+    pub fn prepare_with_progress(&mut self, script_file_name: &str, progress: Option<&mut dyn FnMut(usize, usize)>) -> Result<(), u8> {
         let mut script_config: ScriptConfig = Default::default();
-        Self::read_config(script_file_name, &mut script_config)?;
-        self.process_config(&script_config)?;
+        let mut includes: HashSet<String> = HashSet::new();
+        Self::read_config(script_file_name, &mut script_config, &mut includes, self.source.as_ref())?;
+        use std::sync::atomic::Ordering;
+        if declarations::ECHO_CONFIG_ON.load(Ordering::SeqCst) {
+            Self::echo_config(&script_config);
+        }
+        self.process_config(&script_config, &includes, progress)?;
+        self.validate_line_density();
+        for (title, count) in self.title_counts() {
+            if count > SINGLE_USE {
+                declarations::whinge(&format!("Warning: scene title \"{}\" used {} times", title, count));
+            }
+        }
+        self.check_leading_title()
+    }
+
+    // This function checks that the play has at least one scene and that the first scene has a
+    // title, printing an error and returning its code if not. A poisoned first scene cannot be
+    // checked, so it is reported as a mutex error rather than a missing title.
+    fn check_leading_title(&self) -> Result<(), u8> {
         if self.fragments.len() != EMPTY {
             match self.fragments[FIRST_FRAGMENT].lock() {
                 Ok(ref frag_guard) => {
                     if !frag_guard.scene_title.is_empty() { 
                         Ok(()) 
                     } else {
-                        match writeln!(std::io::stderr().lock(), "Error: script generation failed") {
+                        match writeln!(std::io::stderr().lock(), "Error: the first scene must have a title (add a [scene] Title line before the first config file)") {
                             Ok(_) => {}, //success
                             Err(_) => {}, //fail
                         }
-                        Err(declarations::ERR_SCRIPT_GEN)
+                        Err(declarations::ERR_MISSING_LEADING_TITLE)
                     }
                 }
                 Err(_) => {
-                    poison_mutex_print!();
+                    match writeln!(std::io::stderr().lock(), "Error: the first scene's title could not be checked because its mutex was poisoned") {
+                        Ok(_) => {}, //success
+                        Err(_) => {}, //fail
+                    }
                     Err(declarations::ERR_MUTEX)
                 }
             }
@@ -183,56 +467,1793 @@ impl Play {
     }
 
 
-    // This function prints the script by iterating over each scene fragment and printing
-    // everything required for it, including character entrances, exits, and lines.
-    pub fn recite(&mut self) { 
-        let len = self.fragments.len();
-        for i in START..len {
-            // Generate disjoint slices of self.fragments so that you can get a mutable reference
-            // to the frag at index i and immutable references to the before and after frags
-            let (before, rest) = self.fragments.split_at_mut(i);
-            let (frag, after) = rest.split_at_mut(SECOND_FRAGMENT);
-
-            let prev_arc = if i > START {Some(&before[i-1])} else {None};
-            let next_arc = if i < len - 1 {Some(&after[FIRST_FRAGMENT])} else {None};
-
-            match frag[FIRST_FRAGMENT].lock() {
-                Ok(ref mut frag_guard) => {
-                    if let Some(p) = prev_arc {
-                        match p.lock() {
-                            Ok(ref p_guard) => {
-                                frag_guard.enter(p_guard);
-                            }
-                            Err(_) => {
-                                poison_mutex_print!();
-                            }
+    // This function complains in whinge mode about each scene whose lines are spread thinly over
+    // the range of line numbers it spans, such as lines numbered 1 to 5 followed by line 5000,
+    // since that almost always means a line number was mistyped
+    pub fn validate_line_density(&self) {
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => {
+                    let lines = f_guard.spoken_lines();
+                    if let (Some(first), Some(last)) = (lines.first(), lines.last()) {
+                        let span = (last.number - first.number).saturating_add(1);
+                        if (lines.len() as f64) / (span as f64) < MIN_LINE_DENSITY {
+                            declarations::whinge(&format!("Warning: scene \"{}\" spans lines {}..{} but has only {} lines", f_guard.scene_title, first.number, last.number, lines.len()));
                         }
-                    } else {
-                        frag_guard.enter_all();
                     }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+    }
+
+    // This function returns how many scenes use each title, in the order the titles first appear.
+    // Fragments without a title are not counted.
+    pub fn title_counts(&self) -> Vec<(String, usize)> {
+        let mut counts: Vec<(String, usize)> = Vec::new();
+        for title in self.scene_titles() {
+            if title.is_empty() {
+                continue;
+            }
+            match counts.iter_mut().find(|(t, _)| *t == title) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((title, SINGLE_USE)),
+            }
+        }
+        counts
+    }
+
+    // This function marks each scene whose title is used by more than one scene with which use of
+    // the title it is, counting from 1, so that the repeated titles are numbered when recited
+    pub fn number_duplicate_scenes(&mut self) {
+        let repeated: HashSet<String> = self.title_counts()
+            .into_iter()
+            .filter(|(_, count)| *count > SINGLE_USE)
+            .map(|(title, _)| title)
+            .collect();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref mut f_guard) => {
+                    if repeated.contains(&f_guard.scene_title) {
+                        let occurrence = seen.entry(f_guard.scene_title.clone()).or_default();
+                        *occurrence += 1;
+                        f_guard.title_occurrence = Some(*occurrence);
+                    }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+    }
+
+    // This function returns how long the scene took to prepare and recite, or None if timings
+    // were not requested
+    fn timing_report(frag: &SceneFragment) -> Option<String> {
+        use std::sync::atomic::Ordering;
+        declarations::TIMINGS_ON.load(Ordering::SeqCst)
+            .then(|| format!("Scene \"{}\": prepared in {:?}, recited in {:?}", frag.scene_title, frag.prepare_time, frag.recite_time))
+    }
+
+    // This function returns the title of every scene fragment in order. Fragments without a title
+    // are included as empty strings so that indices line up with the fragments, and a fragment
+    // whose mutex was poisoned is reported as (unavailable).
+    pub fn scene_titles(&self) -> Vec<String> {
+        self.fragments.iter()
+            .map(|f| {
+                match f.lock() {
+                    Ok(ref f_guard) => f_guard.scene_title.clone(),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        UNAVAILABLE.to_string()
+                    }
+                }
+            })
+            .collect()
+    }
 
-                    frag_guard.recite();
+    // This function reorders the scene fragments by title so that two versions of a play with the
+    // same scenes in different orders recite identically. Each title is read once under its lock,
+    // and the sort is stable so scenes with equal titles keep their original relative order.
+    pub fn sort_canonical(&mut self) {
+        let mut order: Vec<(String, Arc<Mutex<SceneFragment>>)> = self.scene_titles()
+            .into_iter()
+            .zip(self.fragments.drain(..))
+            .collect();
+        order.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.fragments = order.into_iter().map(|(_, frag)| frag).collect();
+    }
 
-                    if let Some(n) = next_arc {
-                        match n.lock() {
-                            Ok(ref n_guard) => {
-                                frag_guard.exit(n_guard);
-                            }
-                            Err(_) => {
-                                poison_mutex_print!();
-                            }
-                        }
-                    } else {
-                        frag_guard.exit_all();
+    // This function returns every spoken line of the play as a flat list of records, ordered by
+    // scene and then as each scene would recite them. The records are collected up front since
+    // each scene and character has to be locked to read them.
+    pub fn lines_iter(&self) -> Vec<SpokenLine> {
+        let mut spoken = Vec::new();
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => spoken.extend(f_guard.spoken_lines()),
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+        spoken
+    }
+
+    // This function returns a 64 bit fingerprint of the parsed play, hashed from the scene,
+    // character, line number, and text of every spoken line in recitation order. Plays that
+    // recite the same lines get the same fingerprint however their files are laid out, since lines
+    // are trimmed when they are read, so it can tell whether a script has really changed.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for line in self.lines_iter() {
+            (&line.scene, &line.character, line.number, &line.text).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    // This function returns the spoken lines of each scene, in scene order
+    fn scene_lines(&self) -> Vec<Vec<SpokenLine>> {
+        self.fragments.iter()
+            .map(|f| {
+                match f.lock() {
+                    Ok(ref f_guard) => f_guard.spoken_lines(),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        Vec::new()
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // This function keys the given lines of a scene by line number and character, joining the
+    // text of lines a character speaks more than once with the same number
+    fn keyed_lines(lines: Option<&Vec<SpokenLine>>) -> BTreeMap<(usize, String), String> {
+        let mut keyed: BTreeMap<(usize, String), String> = BTreeMap::new();
+        for line in lines.into_iter().flatten() {
+            keyed.entry((line.number, line.character.clone()))
+                .and_modify(|text| {
+                    text.push('\n');
+                    text.push_str(&line.text);
+                })
+                .or_insert_with(|| line.text.clone());
+        }
+        keyed
+    }
+
+    // This function compares this play with another edition of it. Scenes are matched up by their
+    // position, and the lines in each pair of scenes by character and line number, so a line whose
+    // number changed shows up as removed and added rather than moved. A different number of scenes
+    // is reported first, and every line of a scene only one play has is added or removed. The
+    // differences are returned in scene order and then in line number order.
+    pub fn diff(&self, other: &Play) -> Vec<DiffEntry> {
+        let ours = self.scene_lines();
+        let theirs = other.scene_lines();
+        let mut entries = Vec::new();
+        if ours.len() != theirs.len() {
+            entries.push(DiffEntry::SceneCount { ours: ours.len(), theirs: theirs.len() });
+        }
+        for scene in START..ours.len().max(theirs.len()) {
+            let old_lines = Self::keyed_lines(ours.get(scene));
+            let new_lines = Self::keyed_lines(theirs.get(scene));
+            let keys: BTreeSet<&(usize, String)> = old_lines.keys().chain(new_lines.keys()).collect();
+            for key in keys {
+                let (number, character) = (key.0, key.1.clone());
+                match (old_lines.get(key), new_lines.get(key)) {
+                    (Some(old), Some(new)) if old != new => {
+                        entries.push(DiffEntry::Changed { scene, character, number, old: old.clone(), new: new.clone() });
+                    }
+                    (Some(old), None) => {
+                        entries.push(DiffEntry::Removed { scene, character, number, text: old.clone() });
+                    }
+                    (None, Some(new)) => {
+                        entries.push(DiffEntry::Added { scene, character, number, text: new.clone() });
+                    }
+                    _ => {}, //the line is the same in both
+                }
+            }
+        }
+        entries
+    }
+
+    // This function prints the differences between this play and another edition of it to the
+    // given writer, one per line, or says that there are none
+    pub fn print_diff(&self, other: &Play, out: &mut dyn Write) {
+        let entries = self.diff(other);
+        if entries.is_empty() {
+            match writeln!(out, "No differences") {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+        for entry in entries {
+            match writeln!(out, "{}", entry) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+    }
+
+    // This function keys the given lines of a scene by line number, giving each the name of its
+    // speaker before its text. The lines of several characters who share a number are joined.
+    fn numbered_lines(lines: Option<&Vec<SpokenLine>>) -> BTreeMap<usize, String> {
+        let mut numbered: BTreeMap<usize, String> = BTreeMap::new();
+        for line in lines.into_iter().flatten() {
+            let spoken = format!("{}: {}", line.character, line.text);
+            numbered.entry(line.number)
+                .and_modify(|text| {
+                    text.push('\n');
+                    text.push_str(&spoken);
+                })
+                .or_insert(spoken);
+        }
+        numbered
+    }
+
+    // This function prints one row of the side by side layout. Each side is wrapped to the column
+    // width, and the left column is padded so that the right one lines up. A side with fewer
+    // wrapped lines is left blank below them, without trailing spaces.
+    fn print_columns(left: &str, right: &str, width: usize, out: &mut dyn Write) {
+        let left = wrap_text(left, width);
+        let right = wrap_text(right, width);
+        for row in START..left.len().max(right.len()) {
+            let left_text = left.get(row).map(String::as_str).unwrap_or_default();
+            let right_text = right.get(row).map(String::as_str).unwrap_or_default();
+            let row = format!("{:<width$}{}{}", left_text, COLUMN_SEPARATOR, right_text, width = width);
+            match writeln!(out, "{}", row.trim_end()) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+    }
+
+    // This function recites this play and a parallel one, such as a translation, side by side in
+    // two columns of the given width, with this play on the left. Scenes are matched up by their
+    // position and lines by their number, so a line that only one play has leaves the other
+    // column blank. Each scene starts with both titles.
+    pub fn side_by_side(&self, other: &Play, width: usize, out: &mut dyn Write) {
+        let (our_titles, their_titles) = (self.scene_titles(), other.scene_titles());
+        let (ours, theirs) = (self.scene_lines(), other.scene_lines());
+        for scene in START..ours.len().max(theirs.len()) {
+            match writeln!(out) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            let our_title = our_titles.get(scene).map(String::as_str).unwrap_or_default();
+            let their_title = their_titles.get(scene).map(String::as_str).unwrap_or_default();
+            Self::print_columns(our_title, their_title, width, out);
+            match writeln!(out) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            let our_lines = Self::numbered_lines(ours.get(scene));
+            let their_lines = Self::numbered_lines(theirs.get(scene));
+            let numbers: BTreeSet<&usize> = our_lines.keys().chain(their_lines.keys()).collect();
+            for number in numbers {
+                let left = our_lines.get(number).map(String::as_str).unwrap_or_default();
+                let right = their_lines.get(number).map(String::as_str).unwrap_or_default();
+                Self::print_columns(left, right, width, out);
+            }
+        }
+    }
+
+    // This function writes the play back out into the given directory, creating it if needed, as a
+    // script file along with a config file for each scene and a part file for each character in
+    // it. Titles with runs of spaces are quoted so that they survive being read again, and file
+    // names in the script are relative to the directory, so the play reads back the same from
+    // there. This way a messy hand edited script can be tidied into a canonical layout. It
+    // returns an error if any file could not be written.
+    pub fn write_back(&self, dir: &str) -> Result<(), u8> {
+        let dir = std::path::Path::new(dir);
+        if std::fs::create_dir_all(dir).is_err() {
+            match writeln!(std::io::stderr().lock(), "Error: the directory {} could not be created", dir.display()) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            return Err(declarations::ERR_OUTPUT);
+        }
+        let mut script = Vec::new();
+        for (i, f) in self.fragments.iter().enumerate() {
+            let f_guard = match f.lock() {
+                Ok(f_guard) => f_guard,
+                Err(_) => {
+                    poison_mutex_print!();
+                    return Err(declarations::ERR_MUTEX);
+                }
+            };
+            let title = &f_guard.scene_title;
+            if !title.is_empty() {
+                let normalized = title.split_whitespace().collect::<Vec<&str>>().join(" ");
+                if *title == normalized {
+                    script.push(format!("{} {}", SCENE_INDICATOR, title));
+                } else {
+                    script.push(format!("{} {}{}{}", SCENE_INDICATOR, TITLE_QUOTE, title, TITLE_QUOTE));
+                }
+            }
+            let stem = format!("{}{}", WRITE_BACK_SCENE, i + FIRST_SCENE_NUMBER);
+            script.push(f_guard.write_back(dir, &stem)?);
+        }
+        let script_file = format!("{}{}", WRITE_BACK_SCRIPT, declarations::part_file_extension());
+        declarations::write_file_lines(&dir.join(script_file), &script)
+    }
+
+    // This function returns the warnings raised while preparing and reciting the play so far,
+    // clearing them so that later calls only return new warnings
+    pub fn take_warnings(&self) -> Vec<String> {
+        declarations::take_warnings()
+    }
+
+    // This function prints the characters present in each scene to the given writer, in the order
+    // they are announced, for planning the blocking of rehearsals
+    pub fn print_blocking(&self, w: &mut dyn Write) {
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => {
+                    match writeln!(w, "Scene \"{}\": {}", f_guard.scene_title, f_guard.character_names().join(", ")) {
+                        Ok(_) => {}, //success
+                        Err(_) => {}, //fail
+                    }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+    }
+
+    // This function returns, for each scene in order, the scene's title and the names of its
+    // characters in the order they speak, without the text of their lines
+    pub fn speaking_order(&self) -> Vec<(String, Vec<String>)> {
+        self.fragments.iter()
+            .filter_map(|f| {
+                match f.lock() {
+                    Ok(ref f_guard) => Some((f_guard.scene_title.clone(), f_guard.speaking_order())),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // This function prints the order the characters speak in each scene to the given writer, for
+    // pacing analysis
+    pub fn print_speaking_order(&self, w: &mut dyn Write) {
+        for (title, speakers) in self.speaking_order() {
+            match writeln!(w, "Scene \"{}\": {}", title, speakers.join(", ")) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+    }
+
+    // This function returns how many lines each character speaks across the whole play, with the
+    // counts for a character appearing in several scenes summed under their name. The result is
+    // sorted from most lines to fewest, and characters with the same count are sorted by name.
+    pub fn line_counts(&self) -> Vec<(String, usize)> {
+        let mut totals: HashMap<String, usize> = HashMap::new();
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => {
+                    for (name, count) in f_guard.line_counts() {
+                        *totals.entry(name).or_default() += count;
+                    }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+        let mut counts: Vec<(String, usize)> = totals.into_iter().collect();
+        counts.sort_by(|(a_name, a_count), (b_name, b_count)| b_count.cmp(a_count).then_with(|| a_name.cmp(b_name)));
+        counts
+    }
+
+    // This function prints how many lines each character speaks across the whole play to the given
+    // writer, from most lines to fewest
+    pub fn print_line_counts(&self, w: &mut dyn Write) {
+        for (name, count) in self.line_counts() {
+            match writeln!(w, "{}: {}", name, count) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+    }
+
+    // This function returns the scene and character of every line with the given number, in the
+    // order the scenes are recited
+    pub fn find_line(&self, number: usize) -> Vec<(String, String)> {
+        let mut found = Vec::new();
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => {
+                    for name in f_guard.characters_with_line(number) {
+                        found.push((f_guard.scene_title.clone(), name));
                     }
                 }
                 Err(_) => {
                     poison_mutex_print!();
                 }
             }
+        }
+        found
+    }
+
+    // This function prints the scene and character of every line with the given number to the
+    // given writer, or that there is no such line
+    pub fn print_find_line(&self, number: usize, w: &mut dyn Write) {
+        let found = self.find_line(number);
+        if found.is_empty() {
+            match writeln!(w, "Line {} not found", number) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+        for (scene, character) in found {
+            match writeln!(w, "Line {}: scene \"{}\", {}", number, scene, character) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+    }
 
+    // This function prints the longest line in each scene to the given writer along with who speaks
+    // it and its length, for planning how the play is typeset
+    pub fn print_longest_lines(&self, w: &mut dyn Write) {
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => {
+                    let result = match f_guard.longest_line() {
+                        Some((name, len, text)) => writeln!(w, "Scene \"{}\": {} ({} characters): {}", f_guard.scene_title, name, len, text),
+                        None => writeln!(w, "Scene \"{}\": no lines", f_guard.scene_title),
+                    };
+                    match result {
+                        Ok(_) => {}, //success
+                        Err(_) => {}, //fail
+                    }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
         }
+    }
 
+    // This function speaks the whole part of the named character to the given writer as a part
+    // sheet, leaving out scene titles, stage directions, and every other character. The part is
+    // spoken scene by scene in the order the scenes are recited. Each scene is spoken from a fresh
+    // copy so that the play can still be recited afterwards.
+    pub fn speak_part(&self, name: &str, w: &mut dyn Write) {
+        for f in &self.fragments {
+            match f.lock() {
+                Ok(ref f_guard) => f_guard.fresh_copy().speak_part(name, w),
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
     }
 
+    // This function prints a numbered table of contents of the scenes in the play to the given
+    // writer, labelling scenes without a title as (untitled).
+    pub fn print_toc(&self, w: &mut dyn Write) {
+        for (i, title) in self.scene_titles().iter().enumerate() {
+            let title = if title.is_empty() { UNTITLED } else { title };
+            match writeln!(w, "{}. {}", i + FIRST_SCENE_NUMBER, title) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+    }
+
+    // This function writes the play to the given writer as Markdown, laid out the same way it is
+    // recited. Each scene title is a level two heading, stage directions are in italics, and each
+    // speech is a paragraph starting with the speaker's name in bold, with a line break between
+    // its lines. Characters that mean something in Markdown are escaped. It returns an error if
+    // the writer fails.
+    pub fn to_markdown(&self, w: &mut dyn Write) -> Result<(), u8> {
+        let casts = self.casts();
+        let mut blocks = Vec::new();
+        for (i, f) in self.fragments.iter().enumerate() {
+            let f_guard = match f.lock() {
+                Ok(f_guard) => f_guard,
+                Err(_) => {
+                    poison_mutex_print!();
+                    return Err(declarations::ERR_MUTEX);
+                }
+            };
+            if !f_guard.scene_title.trim().is_empty() {
+                blocks.push(format!("## {}", Self::escape_markdown(&f_guard.display_title())));
+            }
+            let previous_cast = i.checked_sub(SECOND_FRAGMENT).map(|p| &casts[p]);
+            for direction in f_guard.entrances(previous_cast, None) {
+                blocks.push(format!("*{}.*", Self::escape_markdown(&direction)));
+            }
+
+            let mut speaker: Option<String> = None;
+            let mut speech = String::new();
+            for line in f_guard.spoken_lines() {
+                if speaker.as_ref() == Some(&line.character) {
+                    speech.push_str(MARKDOWN_LINE_BREAK);
+                } else {
+                    if speaker.is_some() {
+                        blocks.push(std::mem::take(&mut speech));
+                    }
+                    speech = format!("**{}**{}", Self::escape_markdown(&line.character), MARKDOWN_LINE_BREAK);
+                    speaker = Some(line.character.clone());
+                }
+                speech.push_str(&Self::escape_markdown(&line.text));
+            }
+            if speaker.is_some() {
+                blocks.push(speech);
+            }
+
+            for direction in f_guard.exits(casts.get(i + 1), None) {
+                blocks.push(format!("*{}.*", Self::escape_markdown(&direction)));
+            }
+        }
+
+        match writeln!(w, "{}", blocks.join(MARKDOWN_BLOCK_SEPARATOR)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(declarations::ERR_OUTPUT),
+        }
+    }
+
+    // This function escapes the characters in text that Markdown would otherwise treat as
+    // formatting, including a leading list marker
+    fn escape_markdown(text: &str) -> String {
+        let mut escaped = String::new();
+        for (i, c) in text.chars().enumerate() {
+            if MARKDOWN_SPECIAL.contains(c) || (i == START && MARKDOWN_LIST_MARKERS.contains(c)) {
+                escaped.push(MARKDOWN_ESCAPE);
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    // This function writes statistics about the play to the given writer as a JSON object, for
+    // tools such as dashboards to read. It gives the number of scenes, the number of distinct
+    // characters, how many lines each character speaks from most to fewest, the total number of
+    // lines, and the fingerprint. The fingerprint is written as a string, since it can be too
+    // large for a JSON number to hold exactly. It returns an error if the writer fails.
+    pub fn to_stats_json(&self, w: &mut dyn Write) -> Result<(), u8> {
+        let counts = self.line_counts();
+        let total: usize = counts.iter().map(|(_, count)| count).sum();
+        let characters: Vec<String> = counts.iter()
+            .map(|(name, count)| format!("\"{}\": {}", Self::escape_json(name), count))
+            .collect();
+        let json = format!(
+            "{{\"scenes\": {}, \"characters\": {}, \"line_counts\": {{{}}}, \"total_lines\": {}, \"fingerprint\": \"{}\"}}",
+            self.fragments.len(), counts.len(), characters.join(", "), total, self.fingerprint());
+        match writeln!(w, "{}", json) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(declarations::ERR_OUTPUT),
+        }
+    }
+
+    // This function escapes text so that it can be written inside a JSON string, escaping quotes
+    // and backslashes and writing control characters as unicode escapes
+    fn escape_json(text: &str) -> String {
+        let mut escaped = String::new();
+        for c in text.chars() {
+            match c {
+                '"' | '\\' => {
+                    escaped.push('\\');
+                    escaped.push(c);
+                }
+                c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+
+    // This function pauses reciting until the user presses enter, telling them which scene comes
+    // next. If stdin is not a terminal, or the prompt cannot be read, reciting carries on without
+    // pausing.
+    fn wait_for_next_page(next_scene: usize) {
+        use std::io::IsTerminal;
+        let stdin = std::io::stdin();
+        if !stdin.is_terminal() {
+            return;
+        }
+        match write!(std::io::stderr().lock(), "-- press enter for scene {} --", next_scene) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        let mut response = String::new();
+        match stdin.read_line(&mut response) {
+            Ok(_) => {}, //the user is ready for the next page
+            Err(_) => {}, //carry on without pausing
+        }
+    }
+
+    // This function returns the names of the characters in each scene, in scene order, so that
+    // the entrances and exits between neighboring scenes can be worked out before reciting
+    fn casts(&self) -> Vec<HashSet<String>> {
+        self.fragments.iter()
+            .map(|f| {
+                match f.lock() {
+                    Ok(ref f_guard) => f_guard.character_names().into_iter().collect(),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        HashSet::new()
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // This function returns the indices of the scenes to recite, in order. If the style asks for
+    // empty scenes to be skipped, scenes where nobody has a line are left out, and the scenes on
+    // either side of them become neighbors for working out entrances and exits.
+    fn recited_scenes(&self, style: &StyleConfig) -> Vec<usize> {
+        self.fragments.iter()
+            .enumerate()
+            .filter(|(_, f)| {
+                !style.skip_empty_scenes || match f.lock() {
+                    Ok(ref f_guard) => f_guard.has_lines(),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        false
+                    }
+                }
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // This function prints whatever goes between a scene and the one before it, pausing first if
+    // the style gives a page size and a page has been filled, and then printing a separator if the
+    // style asks for one. Nothing is printed before the first scene.
+    fn between_scenes(index: usize, style: &StyleConfig, out: &mut dyn Write) {
+        if index == START {
+            return;
+        }
+        if let Some(page_scenes) = style.page_scenes {
+            if index % page_scenes == START {
+                Self::wait_for_next_page(index + FIRST_SCENE_NUMBER);
+            }
+        }
+        if style.separators {
+            match writeln!(out, "{}", SEPARATOR_CHAR.to_string().repeat(SEPARATOR_WIDTH)) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+    }
+
+    // This function recites a single scene to the given writer, including the characters entering
+    // who were not in the previous scene's cast and the characters exiting who are not in the next
+    // scene's cast. Everyone enters at the start of the play and exits at the end. It returns the
+    // number after the last line recited, and prints how long the scene took to prepare and
+    // recite to stderr if timings were requested. If the style asks for markers, the scene is
+    // surrounded by @@SCENE and @@ENDSCENE lines giving its number, so that tools reading the
+    // output can split it into scenes. Each line's text is passed through the given transform. If
+    // a last line number is given and the scene gets past it, reciting stops there and everyone
+    // in the scene exits, since the play ends with it.
+    #[allow(clippy::too_many_arguments)]
+    fn recite_scene(
+        frag: &mut SceneFragment,
+        index: usize,
+        previous_cast: Option<&HashSet<String>>,
+        next_cast: Option<&HashSet<String>>,
+        style: &StyleConfig,
+        first_line_number: usize,
+        last_line: Option<usize>,
+        transform: &mut dyn FnMut(&str) -> String,
+        out: &mut dyn Write,
+    ) -> usize {
+        let only = style.only.as_deref();
+        if style.markers {
+            match writeln!(out, "@@SCENE {} {}@@", index + FIRST_SCENE_NUMBER, frag.display_title()) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+        match previous_cast {
+            Some(cast) => frag.enter(cast, only, out),
+            None => frag.enter_all(only, out),
+        }
+
+        let start = Instant::now();
+        let next_line_number = frag.recite(style, only, first_line_number, last_line, transform, out);
+        frag.recite_time = start.elapsed();
+
+        let stopped = last_line.is_some_and(|last| next_line_number > last);
+        match next_cast {
+            Some(cast) if !stopped => frag.exit(cast, only, out),
+            _ => frag.exit_all(only, out),
+        }
+        if style.markers {
+            match writeln!(out, "@@ENDSCENE {}@@", index + FIRST_SCENE_NUMBER) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+
+        if let Some(timing) = Self::timing_report(frag) {
+            match writeln!(std::io::stderr().lock(), "{}", timing) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+        next_line_number
+    }
+
+    // This function prints the script by iterating over each scene fragment and printing
+    // everything required for it, including character entrances, exits, and lines, laid out in
+    // the given style, to the given writer. If the style names a single character, only that
+    // character's lines and stage directions are printed. If timings were requested, how long each
+    // scene took to prepare and recite is printed to stderr. If an output line limit was given,
+    // reciting stops once it is reached and a note is printed to stderr. If the style asks for
+    // separators, a rule line is printed between consecutive scenes, and if it gives a page size,
+    // reciting pauses for the user after that many scenes. Each scene's lines are numbered from
+    // the configured base line number unless numbering continues across scenes, in which case
+    // missing lines are found using the numbering of the whole play. If a line to recite until was
+    // given, reciting stops once that line of the whole play has been spoken, counting lines as
+    // continuous numbering would, and everyone on stage exits. In parallel recite mode the scenes
+    // are recited at the same time, unless numbering continues across scenes or there is an
+    // output line limit or a line to recite until, since those depend on the scenes before. If the
+    // style asks for it, scenes where nobody has a line are skipped entirely. It returns a report
+    // of how many lines and scenes were recited and how many warnings were raised while reciting.
+    pub fn recite(&mut self, style: &StyleConfig, out: &mut dyn Write) -> ReciteReport {
+        self.recite_with_hooks(style, out, None, None)
+    }
+
+    // This method recites the script like recite, passing the text of each line through the given
+    // transform, if any, before it is spoken. This lets lines be rewritten as they are recited,
+    // such as to censor them or expand placeholders. The transform is called in the order the
+    // lines are spoken, so scenes are never recited in parallel when one is given.
+    pub fn recite_with_transform(
+        &mut self,
+        style: &StyleConfig,
+        out: &mut dyn Write,
+        transform: Option<&mut dyn FnMut(&str) -> String>,
+    ) -> ReciteReport {
+        self.recite_with_hooks(style, out, transform, None)
+    }
+
+    // This method recites the script like recite, calling the given interlude at each boundary
+    // between scenes with the indices of the scenes before and after it and the writer. It is
+    // called after the exits of one scene and before anything else is printed for the next, so
+    // that text such as an intermission note can be put between them. Scenes that are skipped do
+    // not get an interlude of their own, and none follows the last scene recited.
+    pub fn recite_with_interlude(&mut self, style: &StyleConfig, out: &mut dyn Write, interlude: &mut Interlude) -> ReciteReport {
+        self.recite_with_hooks(style, out, None, Some(interlude))
+    }
+
+    // This method does the reciting for recite and the methods that hook into it, with an optional
+    // transform for each line and an optional interlude between scenes
+    fn recite_with_hooks(
+        &mut self,
+        style: &StyleConfig,
+        out: &mut dyn Write,
+        transform: Option<&mut dyn FnMut(&str) -> String>,
+        interlude: Option<&mut Interlude>,
+    ) -> ReciteReport {
+        use std::sync::atomic::Ordering;
+        let warnings_before = declarations::warning_count();
+        let truncated_before = declarations::output_truncated(); // an earlier recital already said so
+        let continuous = declarations::CONTINUOUS_NUMBERING_ON.load(Ordering::SeqCst);
+        let until = declarations::UNTIL_ON.load(Ordering::SeqCst).then(|| declarations::UNTIL_LINE.load(Ordering::SeqCst));
+        let mut no_interlude = |_: usize, _: usize, _: &mut dyn Write| {};
+        let interlude: &mut Interlude = match interlude {
+            Some(interlude) => interlude,
+            None => &mut no_interlude,
+        };
+        if transform.is_none() && declarations::PARALLEL_RECITE_ON.load(Ordering::SeqCst) && !continuous && until.is_none()
+            && declarations::MAX_OUTPUT_LINES.load(Ordering::SeqCst) == declarations::NO_LIMIT {
+            let mut report = self.recite_parallel(style, out, interlude);
+            report.warnings = declarations::warning_count().saturating_sub(warnings_before);
+            return report;
+        }
+        let mut identity = |text: &str| text.to_string();
+        let transform: &mut dyn FnMut(&str) -> String = match transform {
+            Some(transform) => transform,
+            None => &mut identity,
+        };
+
+        let casts = self.casts();
+        let scenes = self.recited_scenes(style);
+        let base = declarations::LINE_BASE.load(Ordering::SeqCst);
+        let mut next_line_number = base;
+        let mut play_line_number = base; // where each scene would start if numbering were continuous
+        let mut report = ReciteReport::default();
+        for (position, &i) in scenes.iter().enumerate() {
+            if declarations::output_truncated() {
+                break;
+            }
+            if let Some(p) = position.checked_sub(SECOND_FRAGMENT) {
+                interlude(scenes[p], i, out);
+            }
+            Self::between_scenes(position, style, out);
+            match self.fragments[i].lock() {
+                Ok(ref mut frag_guard) => {
+                    if !continuous {
+                        next_line_number = base;
+                    }
+                    let scene_start = next_line_number;
+                    let last_line = until.map(|last| last.saturating_add(scene_start).saturating_sub(play_line_number));
+                    let previous_cast = position.checked_sub(SECOND_FRAGMENT).map(|p| &casts[scenes[p]]);
+                    let next_cast = scenes.get(position + 1).map(|&n| &casts[n]);
+                    next_line_number = Self::recite_scene(frag_guard, i, previous_cast, next_cast, style, scene_start, last_line, transform, out);
+                    report.lines_spoken += frag_guard.lines_spoken;
+                    report.scenes += 1;
+                    play_line_number += next_line_number - scene_start;
+                    if until.is_some_and(|last| play_line_number > last) {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+
+        if !truncated_before && declarations::output_truncated() {
+            match writeln!(std::io::stderr().lock(), "Output truncated at {} lines", declarations::MAX_OUTPUT_LINES.load(Ordering::SeqCst)) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+        report.warnings = declarations::warning_count().saturating_sub(warnings_before);
+        report
+    }
+
+    // This function recites every scene on its own thread into its own buffer, working out each
+    // scene's entrances and exits from the casts of its neighbors beforehand so that the threads
+    // do not depend on each other. The buffers are then written to the given writer in scene
+    // order, so the output is the same as reciting the scenes one after another, with the given
+    // interlude called between them as they are written. The report it returns counts lines and
+    // scenes but leaves the warnings to the caller.
+    fn recite_parallel(&mut self, style: &StyleConfig, out: &mut dyn Write, interlude: &mut Interlude) -> ReciteReport {
+        use std::sync::atomic::Ordering;
+        let base = declarations::LINE_BASE.load(Ordering::SeqCst);
+        let casts = self.casts();
+        let scenes = self.recited_scenes(style);
+        let mut handles = Vec::new();
+        for (position, &i) in scenes.iter().enumerate() {
+            let thread_frag = Arc::clone(&self.fragments[i]);
+            let previous_cast = position.checked_sub(SECOND_FRAGMENT).map(|p| casts[scenes[p]].clone());
+            let next_cast = scenes.get(position + 1).map(|&n| casts[n].clone());
+            let thread_style = style.clone();
+            handles.push(thread::spawn(move || -> (Vec<u8>, usize) {
+                let mut buffer = Vec::new();
+                match thread_frag.lock() {
+                    Ok(ref mut frag_guard) => {
+                        Self::recite_scene(frag_guard, i, previous_cast.as_ref(), next_cast.as_ref(), &thread_style, base, None, &mut |text: &str| text.to_string(), &mut buffer);
+                        (buffer, frag_guard.lines_spoken)
+                    }
+                    Err(_) => {
+                        poison_mutex_print!();
+                        (buffer, EMPTY)
+                    }
+                }
+            }));
+        }
+
+        let mut report = ReciteReport::default();
+        for (position, h) in handles.into_iter().enumerate() {
+            if let Some(p) = position.checked_sub(SECOND_FRAGMENT) {
+                interlude(scenes[p], scenes[position], out);
+            }
+            Self::between_scenes(position, style, out);
+            match h.join() {
+                Ok((buffer, lines_spoken)) => {
+                    match out.write_all(&buffer) {
+                        Ok(_) => {}, //success
+                        Err(_) => {}, //fail
+                    }
+                    report.lines_spoken += lines_spoken;
+                    report.scenes += 1;
+                }
+                Err(_) => {
+                    match writeln!(std::io::stderr().lock(), "Error: a scene could not be recited") {
+                        Ok(_) => {}, //success
+                        Err(_) => {}, //fail
+                    }
+                }
+            }
+        }
+        report
+    }
+
+}
+
+
+impl fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffEntry::SceneCount { ours, theirs } => {
+                write!(f, "Scene count: {} in this play, {} in the other", ours, theirs)
+            }
+            DiffEntry::Added { scene, character, number, text } => {
+                write!(f, "Scene {}: + {} line {}: {}", scene + FIRST_SCENE_NUMBER, character, number, text)
+            }
+            DiffEntry::Removed { scene, character, number, text } => {
+                write!(f, "Scene {}: - {} line {}: {}", scene + FIRST_SCENE_NUMBER, character, number, text)
+            }
+            DiffEntry::Changed { scene, character, number, old, new } => {
+                write!(f, "Scene {}: ~ {} line {}: \"{}\" became \"{}\"", scene + FIRST_SCENE_NUMBER, character, number, old, new)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::collections::HashMap;
+    use crate::lab3::line_source::DirSource;
+    use crate::lab3::test_support::{lock_globals, map_source, prepared_play, recite_to_string, temp_dir, TWO_SCENE_PLAY};
+
+    // This function prepares the named script on another thread and returns the result, or None if
+    // preparing it did not finish within a second
+    fn prepare_within_a_second(files: &[(&str, &str)], script: &str) -> Option<Result<(), u8>> {
+        let source = map_source(files);
+        let script = script.to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut play = Play::with_source(source);
+            let _ = sender.send(play.prepare(&script));
+        });
+        receiver.recv_timeout(Duration::from_secs(1)).ok()
+    }
+
+    // Serves in-memory files, sleeping before returning any file given a delay
+    struct SlowSource {
+        files: crate::lab3::line_source::MapSource,
+        delays_ms: HashMap<String, u64>,
+    }
+
+    impl LineSource for SlowSource {
+        fn lines(&self, name: &str) -> Result<Vec<String>, u8> {
+            if let Some(delay) = self.delays_ms.get(name) {
+                thread::sleep(Duration::from_millis(*delay));
+            }
+            self.files.lines(name)
+        }
+    }
+
+    #[test]
+    fn prepare_timeout_is_one_deadline_for_the_whole_script() {
+        let _globals = lock_globals();
+        let files: HashMap<String, String> = [
+            ("script.txt", "[scene] One\none.txt\n[scene] Two\ntwo.txt\n[scene] Three\nthree.txt\n"),
+            ("one.txt", "A a.txt\n"),
+            ("two.txt", "A a.txt\n"),
+            ("three.txt", "A a.txt\n"),
+            ("a.txt", "1 Line.\n"),
+        ].iter().map(|(name, contents)| (name.to_string(), contents.to_string())).collect();
+        // each scene on its own is quicker than the timeout, but the last finishes well after it
+        let delays_ms = [("one.txt", 300), ("two.txt", 600), ("three.txt", 900)].iter()
+            .map(|(name, delay)| (name.to_string(), *delay))
+            .collect();
+        let source = Arc::new(SlowSource { files: crate::lab3::line_source::MapSource(files), delays_ms });
+        declarations::PREPARE_TIMEOUT_MS.store(400, std::sync::atomic::Ordering::SeqCst);
+        let start = Instant::now();
+        let mut play = Play::with_source(source);
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_TIMEOUT));
+        assert!(start.elapsed() < Duration::from_millis(800), "timed out after {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn prepare_timeout_allows_a_script_that_finishes_in_time() {
+        let _globals = lock_globals();
+        declarations::PREPARE_TIMEOUT_MS.store(5000, std::sync::atomic::Ordering::SeqCst);
+        let mut play = Play::with_source(map_source(TWO_SCENE_PLAY));
+        assert_eq!(play.prepare("script.txt"), Ok(()));
+    }
+
+    #[test]
+    fn script_listing_itself_as_a_scene_errors_instead_of_hanging() {
+        let _globals = lock_globals();
+        let files = [("script.txt", "[scene] Loop\nscript.txt\n")];
+        assert_eq!(prepare_within_a_second(&files, "script.txt"), Some(Err(declarations::ERR_SCRIPT_GEN)));
+    }
+
+    #[test]
+    fn script_including_itself_errors_instead_of_hanging() {
+        let _globals = lock_globals();
+        let files = [("script.txt", "[include] script.txt\n[scene] Loop\nscene.txt\n")];
+        assert_eq!(prepare_within_a_second(&files, "script.txt"), Some(Err(declarations::ERR_SCRIPT_GEN)));
+    }
+
+    #[test]
+    fn timings_are_reported_only_when_requested() {
+        let _globals = lock_globals();
+        let play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        let first = play.fragments[FIRST_FRAGMENT].lock().unwrap();
+        assert_eq!(Play::timing_report(&first), None);
+        declarations::TIMINGS_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let report = Play::timing_report(&first).expect("timings were requested");
+        assert!(report.starts_with("Scene \"Act I\": prepared in "), "{}", report);
+        assert!(report.contains(", recited in "), "{}", report);
+    }
+
+    #[test]
+    fn missing_part_file_is_skipped_under_the_flag() {
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nscene3.txt\n"),
+            ("scene1.txt", "Hamlet hamlet.txt\nGhost missing.txt\n"),
+            ("scene2.txt", "Ghost missing.txt\n"),
+            ("scene3.txt", "Horatio horatio.txt\n"),
+            ("hamlet.txt", "1 Who's there?\n"),
+            ("horatio.txt", "1 My lord.\n"),
+        ];
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+
+        declarations::SKIP_MISSING_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert_eq!(transcript, concat!(
+            "\n",
+            "Act I\n\n",
+            "[Enter Hamlet.]\n\n",
+            " Hamlet\nWho's there?\n\n",
+            "[Exit Hamlet.]\n\n\n",
+            "Act II\n\n\n",
+            "Act III\n\n",
+            "[Enter Horatio.]\n\n",
+            " Horatio\nMy lord.\n\n",
+            "[Exit Horatio.]\n\n",
+        ));
+    }
+
+    // This function returns the config entries parsed from one script line and the warnings they
+    // raised
+    fn parse_config_line(line: &str) -> (ScriptConfig, Vec<String>) {
+        let mut script_config = ScriptConfig::new();
+        Play::add_config(line, &mut script_config);
+        (script_config, declarations::take_warnings())
+    }
+
+    #[test]
+    fn quoted_unquoted_and_unterminated_scene_titles() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(parse_config_line("[scene] \"  The Storm  \""), (vec![(NEW_SCENE_BOOL, "  The Storm  ".to_string())], vec![]));
+        assert_eq!(parse_config_line("[scene]   The    Storm  "), (vec![(NEW_SCENE_BOOL, "The Storm".to_string())], vec![]));
+
+        let (script_config, warnings) = parse_config_line("[scene] \"The   Storm");
+        assert_eq!(script_config, vec![(NEW_SCENE_BOOL, "\"The Storm".to_string())]);
+        assert_eq!(warnings, vec!["Warning: unterminated quote in scene title \"The   Storm, treating it as unquoted".to_string()]);
+    }
+
+    // This function poisons the mutex of the given scene fragment by panicking while it is held
+    fn poison(fragment: &Arc<Mutex<SceneFragment>>) {
+        let fragment = Arc::clone(fragment);
+        let _ = thread::spawn(move || {
+            let _guard = fragment.lock();
+            panic!("poisoning the scene fragment");
+        }).join();
+    }
+
+    #[test]
+    fn toc_numbers_every_scene_and_marks_unavailable_ones() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nscene1.txt\n");
+        let play = prepared_play(&files, "script.txt");
+        let mut out = Vec::new();
+        play.print_toc(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "1. Act I\n2. Act II\n3. Act III\n");
+
+        poison(&play.fragments[1]);
+        let mut out = Vec::new();
+        play.print_toc(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "1. Act I\n2. (unavailable)\n3. Act III\n");
+    }
+
+    #[test]
+    fn script_without_a_leading_title_is_its_own_error() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "scene1.txt\n[scene] Act II\nscene2.txt\n");
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_MISSING_LEADING_TITLE));
+    }
+
+    #[test]
+    fn poisoned_first_scene_is_a_mutex_error() {
+        let _globals = lock_globals();
+        let play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        assert_eq!(play.check_leading_title(), Ok(()));
+        poison(&play.fragments[FIRST_FRAGMENT]);
+        assert_eq!(play.check_leading_title(), Err(declarations::ERR_MUTEX));
+    }
+
+    #[test]
+    fn included_header_adds_its_scene_and_config_file() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[include] header.txt\n[scene] Act II\nscene2.txt\n");
+        files.push(("header.txt", "[scene] Act I\nscene1.txt\n"));
+        let included = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        let inline = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        assert_eq!(included, inline);
+    }
+
+    #[test]
+    fn scenes_in_different_orders_recite_identically_in_canonical_order() {
+        let _globals = lock_globals();
+        let mut reordered = TWO_SCENE_PLAY.to_vec();
+        reordered[0] = ("script.txt", "[scene] Act II\nscene2.txt\n[scene] Act I\nscene1.txt\n");
+        let original_order = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        let swapped_order = recite_to_string(&mut prepared_play(&reordered, "script.txt"), &StyleConfig::default());
+        assert_ne!(original_order, swapped_order);
+
+        let mut original = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        let mut swapped = prepared_play(&reordered, "script.txt");
+        original.sort_canonical();
+        swapped.sort_canonical();
+        assert_eq!(swapped.scene_titles(), vec!["Act I", "Act II"]);
+        assert_eq!(recite_to_string(&mut original, &StyleConfig::default()), recite_to_string(&mut swapped, &StyleConfig::default()));
+    }
+
+    #[test]
+    fn lines_are_flattened_by_scene_then_number_then_name() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[6] = ("horatio.txt", "1 Hail to your lordship.\n");
+        let spoken = |scene: &str, character: &str, number, text: &str| SpokenLine {
+            scene: scene.to_string(), character: character.to_string(), number, text: text.to_string(),
+        };
+        assert_eq!(prepared_play(&files, "script.txt").lines_iter(), vec![
+            spoken("Act I", "Hamlet", 1, "Who's there?"),
+            spoken("Act I", "Ghost", 2, "Mark me."),
+            spoken("Act I", "Hamlet", 3, "Speak, I am bound to hear."),
+            spoken("Act I", "Ghost", 4, "I am thy father's spirit."),
+            spoken("Act II", "Hamlet", 1, "Horatio, or I do forget myself."),
+            spoken("Act II", "Horatio", 1, "Hail to your lordship."),
+        ]);
+    }
+
+    #[test]
+    fn recitation_stops_at_the_output_line_limit() {
+        let _globals = lock_globals();
+        declarations::MAX_OUTPUT_LINES.store(3, std::sync::atomic::Ordering::SeqCst);
+        let transcript = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        assert_eq!(transcript, "\nAct I\n\n[Enter Hamlet.]\n[Enter Ghost.]\n\n Hamlet\nWho's there?\n\n\n");
+        assert!(declarations::output_truncated());
+    }
+
+    #[test]
+    fn blocking_lists_each_scene_cast_in_sorted_order() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[2] = ("scene2.txt", "Horatio horatio.txt\n-Guard guard.txt\nHamlet hamlet2.txt\n");
+        files.push(("guard.txt", "3 Give you good night.\n"));
+        let mut out = Vec::new();
+        prepared_play(&files, "script.txt").print_blocking(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "Scene \"Act I\": Hamlet, Ghost\nScene \"Act II\": Hamlet, Horatio, Guard\n");
+    }
+
+    #[test]
+    fn line_counts_of_a_character_in_two_scenes_add_up() {
+        let _globals = lock_globals();
+        let mut out = Vec::new();
+        prepared_play(TWO_SCENE_PLAY, "script.txt").print_line_counts(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "Hamlet: 3\nGhost: 2\nHoratio: 1\n");
+    }
+
+    #[test]
+    fn sparse_scene_is_complained_about_and_dense_scene_is_not() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        prepared_play(TWO_SCENE_PLAY, "script.txt").validate_line_density();
+        assert!(!declarations::take_warnings().iter().any(|w| w.contains("spans lines")));
+
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[4] = ("ghost.txt", "2 Mark me.\n5000 I am thy father's spirit.\n");
+        prepared_play(&files, "script.txt");
+        let warnings = declarations::take_warnings();
+        assert!(warnings.contains(&"Warning: scene \"Act I\" spans lines 1..5000 but has only 4 lines".to_string()), "{:?}", warnings);
+        assert!(!warnings.iter().any(|w| w.contains("\"Act II\" spans")));
+    }
+
+    #[test]
+    fn separators_appear_only_between_scenes() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nscene1.txt\n");
+        let rule = "=".repeat(SEPARATOR_WIDTH);
+        let plain = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(!plain.contains(&rule));
+
+        let style = StyleConfig { separators: true, ..StyleConfig::default() };
+        let separated = recite_to_string(&mut prepared_play(&files, "script.txt"), &style);
+        let scenes: Vec<&str> = separated.split(&format!("{}\n", rule)).collect();
+        assert_eq!(scenes.len(), 3);
+        assert!(scenes[0].starts_with("\nAct I\n") && scenes[1].starts_with("\nAct II\n") && scenes[2].starts_with("\nAct III\n"));
+        assert_eq!(scenes.concat(), plain);
+    }
+
+    #[test]
+    fn fresh_copies_recited_in_parallel_give_identical_transcripts() {
+        let _globals = lock_globals();
+        let play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        let style = StyleConfig::default();
+        let expected = recite_to_string(&mut play.fresh_copy(), &style);
+        let (first, second) = thread::scope(|scope| {
+            let first = scope.spawn(|| recite_to_string(&mut play.fresh_copy(), &style));
+            let second = scope.spawn(|| recite_to_string(&mut play.fresh_copy(), &style));
+            (first.join().unwrap(), second.join().unwrap())
+        });
+        assert_eq!(first, expected);
+        assert_eq!(second, expected);
+    }
+
+    // A writer whose bytes can still be read after it has been handed over as the warning sink
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // This function recites a play with a sparse scene in whinge mode with warnings sent to a
+    // buffer, and returns the warnings written by the time the recitation completed and after they
+    // were flushed
+    fn warnings_during_and_after_recital() -> (String, String) {
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let sink = SharedBuffer::default();
+        declarations::set_warning_sink(Box::new(sink.clone()));
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[4] = ("ghost.txt", "2 Mark me.\n5000 I am thy father's spirit.\n");
+        recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        let during = sink.contents();
+        declarations::flush_deferred_warnings();
+        declarations::set_warning_sink(Box::new(std::io::stderr()));
+        (during, sink.contents())
+    }
+
+    #[test]
+    fn deferred_warnings_are_all_written_after_the_recitation() {
+        let _globals = lock_globals();
+        let (during, immediate) = warnings_during_and_after_recital();
+        assert!(immediate.contains("spans lines 1..5000"), "{}", immediate);
+        assert_eq!(during, immediate);
+
+        declarations::reset_options();
+        declarations::DEFER_WARNINGS_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let (during, deferred) = warnings_during_and_after_recital();
+        assert_eq!(during, "");
+        assert_eq!(deferred, immediate);
+    }
+
+    #[test]
+    fn warnings_go_to_a_file_given_as_the_sink() {
+        let _globals = lock_globals();
+        let path = temp_dir("warnings_file").join("warnings.txt");
+        declarations::set_warning_sink(Box::new(std::fs::File::create(&path).unwrap()));
+        declarations::record_warning("Warning: written to the file");
+        declarations::set_warning_sink(Box::new(std::io::stderr()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Warning: written to the file\n");
+    }
+
+    #[test]
+    fn continuous_numbering_finds_gaps_across_scene_boundaries() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let files = [
+            ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n"),
+            ("scene1.txt", "Hamlet hamlet1.txt\n"),
+            ("scene2.txt", "Horatio horatio.txt\n"),
+            ("hamlet1.txt", "0 Who's there?\n1 Stand, and unfold yourself.\n"),
+            ("horatio.txt", "3 Friends to this ground.\n4 And liegemen to the Dane.\n"),
+        ];
+        recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert_eq!(declarations::take_warnings(), vec!["Warning: missing line 0", "Warning: missing line 1", "Warning: missing line 2"]);
+
+        declarations::CONTINUOUS_NUMBERING_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert_eq!(declarations::take_warnings(), vec!["Warning: missing line 2"]);
+    }
+
+    #[test]
+    fn repeated_titles_are_warned_about_and_numbered_on_request() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[scene] A room in the castle\nscene1.txt\n[scene] A platform\nscene2.txt\n[scene] A room in the castle\nscene1.txt\n");
+        let mut play = prepared_play(&files, "script.txt");
+        assert!(declarations::take_warnings().contains(&"Warning: scene title \"A room in the castle\" used 2 times".to_string()));
+        let plain = recite_to_string(&mut play.fresh_copy(), &StyleConfig::default());
+        assert!(plain.contains("\nA room in the castle\n") && !plain.contains("(1)"));
+
+        play.number_duplicate_scenes();
+        let numbered = recite_to_string(&mut play, &StyleConfig::default());
+        assert!(numbered.contains("\nA room in the castle (1)\n") && numbered.contains("\nA room in the castle (2)\n"), "{}", numbered);
+        assert!(numbered.contains("\nA platform\n"));
+    }
+
+    #[test]
+    fn part_sheet_has_a_character_lines_from_every_scene() {
+        let _globals = lock_globals();
+        let mut play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        let mut out = Vec::new();
+        play.speak_part("Hamlet", &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), " Hamlet\nWho's there?\nSpeak, I am bound to hear.\n Hamlet\nHoratio, or I do forget myself.\n");
+        assert!(recite_to_string(&mut play, &StyleConfig::default()).contains("Speak, I am bound to hear."));
+    }
+
+    #[test]
+    fn paging_without_a_terminal_recites_everything_without_waiting() {
+        use std::io::IsTerminal;
+        // With a terminal on stdin the pages really would wait for enter
+        if std::io::stdin().is_terminal() {
+            return;
+        }
+        let _globals = lock_globals();
+        let plain = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        let paged = StyleConfig { page_scenes: Some(1), ..StyleConfig::default() };
+        assert_eq!(recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &paged), plain);
+    }
+
+    #[test]
+    fn shared_line_number_is_found_for_both_characters() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[6] = ("horatio.txt", "1 Hail to your lordship.\n");
+        let play = prepared_play(&files, "script.txt");
+        let act = |scene: &str, character: &str| (scene.to_string(), character.to_string());
+        assert_eq!(play.find_line(1), vec![act("Act I", "Hamlet"), act("Act II", "Hamlet"), act("Act II", "Horatio")]);
+        assert_eq!(play.find_line(4), vec![act("Act I", "Ghost")]);
+        assert!(play.find_line(99).is_empty());
+    }
+
+    #[test]
+    fn interleaved_plays_alternate_scenes_and_append_the_rest() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files.push(("other.txt", "[scene] B1\nscene1.txt\n[scene] B2\nscene2.txt\n[scene] B3\nscene1.txt\n"));
+        files[0] = ("script.txt", "[scene] A1\nscene1.txt\n[scene] A2\nscene2.txt\n");
+        let combined = prepared_play(&files, "script.txt").interleave(prepared_play(&files, "other.txt"));
+        assert_eq!(combined.scene_titles(), vec!["A1", "B1", "A2", "B2", "B3"]);
+        let transcript = recite_to_string(&mut combined.fresh_copy(), &StyleConfig::default());
+        let positions: Vec<usize> = ["A1", "B1", "A2", "B2", "B3"].iter()
+            .map(|title| transcript.find(&format!("\n{}\n", title)).expect("every scene should be recited"))
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "{}", transcript);
+    }
+
+    #[test]
+    fn longest_line_of_each_scene_prefers_the_earliest_tie() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[6] = ("horatio.txt", "0 Hail to your lordship, sir.\n");
+        files[5] = ("hamlet2.txt", "1 Horatio, or I do forget me.\n");
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nscene3.txt\n");
+        files.push(("scene3.txt", "Guard guard.txt\n"));
+        files.push(("guard.txt", ""));
+        let mut out = Vec::new();
+        prepared_play(&files, "script.txt").print_longest_lines(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "Scene \"Act I\": Hamlet (26 characters): Speak, I am bound to hear.\n\
+            Scene \"Act II\": Horatio (27 characters): Hail to your lordship, sir.\n\
+            Scene \"Act III\": no lines\n");
+    }
+
+    #[test]
+    fn progress_is_reported_once_per_scene_with_increasing_counts() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nscene1.txt\n");
+        let mut reported = Vec::new();
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare_with_progress("script.txt", Some(&mut |done, total| reported.push((done, total)))), Ok(()));
+        assert_eq!(reported, vec![(1, 3), (2, 3), (3, 3)]);
+    }
+
+    #[test]
+    fn fingerprint_ignores_whitespace_but_not_words() {
+        let _globals = lock_globals();
+        let fingerprint = prepared_play(TWO_SCENE_PLAY, "script.txt").fingerprint();
+        let mut spaced = TWO_SCENE_PLAY.to_vec();
+        spaced[3] = ("hamlet1.txt", "  1   Who's there?  \n\n3 Speak, I am bound to hear.\t\n");
+        assert_eq!(prepared_play(&spaced, "script.txt").fingerprint(), fingerprint);
+
+        let mut reworded = TWO_SCENE_PLAY.to_vec();
+        reworded[3] = ("hamlet1.txt", "1 Who goes there?\n3 Speak, I am bound to hear.\n");
+        assert_ne!(prepared_play(&reworded, "script.txt").fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn parallel_recite_is_byte_identical_to_sequential_recite() {
+        let _globals = lock_globals();
+        let play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        let style = StyleConfig::default();
+        let mut sequential = Vec::new();
+        play.fresh_copy().recite(&style, &mut sequential);
+        declarations::PARALLEL_RECITE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut parallel = Vec::new();
+        play.fresh_copy().recite(&style, &mut parallel);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn removing_a_middle_scene_changes_the_transitions_around_it() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nscene1.txt\n");
+        let mut play = prepared_play(&files, "script.txt");
+        assert!(recite_to_string(&mut play.fresh_copy(), &StyleConfig::default()).contains("[Exit Ghost.]\n\n\nAct II\n"));
+
+        assert_eq!(play.remove_scene(3), Err(declarations::ERR_SCENE_INDEX));
+        let middle = Arc::clone(&play.fragments[1]);
+        assert_eq!(play.remove_scene(1), Ok(()));
+        let transcript = recite_to_string(&mut play.fresh_copy(), &StyleConfig::default());
+        assert!(transcript.contains("I am thy father's spirit.\n\n\n\nAct III\n\n\n Hamlet\n"), "{}", transcript);
+        assert!(!transcript.contains("Horatio"));
+
+        let middle = Arc::try_unwrap(middle).ok().unwrap().into_inner().unwrap();
+        assert_eq!(play.insert_scene(3, middle.fresh_copy()), Err(declarations::ERR_SCENE_INDEX));
+        assert_eq!(play.insert_scene(1, middle), Ok(()));
+        assert_eq!(play.scene_titles(), vec!["Act I", "Act II", "Act III"]);
+    }
+
+    #[test]
+    fn comments_never_make_scenes_and_are_echoed_only_in_whinge_mode() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[comment] the battlements\n[scene] Act I\nscene1.txt\n[comment]\n[scene] Act II\nscene2.txt\n");
+        assert_eq!(prepared_play(&files, "script.txt").scene_titles(), vec!["Act I", "Act II"]);
+        assert!(declarations::take_warnings().is_empty());
+
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        prepared_play(&files, "script.txt");
+        let notes: Vec<String> = declarations::take_warnings().into_iter().filter(|w| w.starts_with("Note:")).collect();
+        assert_eq!(notes, vec!["Note: the battlements"]);
+    }
+
+    #[test]
+    fn markdown_of_a_two_character_scene() {
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt\n"),
+            ("hamlet.txt", "1 Stay! *Speak*\n2 - I charge thee\n"),
+            ("horatio.txt", "3 'Tis gone.\n"),
+        ];
+        let mut out = Vec::new();
+        assert_eq!(prepared_play(&files, "script.txt").to_markdown(&mut out), Ok(()));
+        assert_eq!(String::from_utf8(out).unwrap(), "## Act I\n\n*Enter Hamlet.*\n\n*Enter Horatio.*\n\n\
+            **Hamlet**  \nStay\\! \\*Speak\\*  \n\\- I charge thee\n\n**Horatio**  \n'Tis gone.\n\n\
+            *Exit Horatio.*\n\n*Exit Hamlet.*\n");
+    }
+
+    #[test]
+    fn echoed_config_lists_scenes_and_files_in_order() {
+        let _globals = lock_globals();
+        declarations::ECHO_CONFIG_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\nscene2.txt\n[scene] Act II\nscene2.txt\n");
+        prepared_play(&files, "script.txt");
+        let echo = declarations::take_config_echo();
+        assert!(echo.starts_with("SCENE: Act I\nFILE: scene1.txt\nFILE: scene2.txt\nSCENE: Act II\nFILE: scene2.txt\n"), "{}", echo);
+    }
+
+    #[test]
+    fn markers_split_the_output_into_scenes() {
+        let _globals = lock_globals();
+        let plain = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        assert!(!plain.contains("@@"));
+
+        let style = StyleConfig { markers: true, ..StyleConfig::default() };
+        let marked = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &style);
+        let mut chunks = Vec::new();
+        let mut current: Option<(String, String)> = None;
+        for line in marked.lines() {
+            if let Some(start) = line.strip_prefix("@@SCENE ") {
+                assert!(current.is_none(), "scene started inside another");
+                current = Some((start.trim_end_matches("@@").to_string(), String::new()));
+            } else if line.starts_with("@@ENDSCENE ") {
+                chunks.push(current.take().expect("scene ended without starting"));
+            } else {
+                let (_, body) = current.as_mut().expect("every line is inside a scene");
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+        assert_eq!(chunks.iter().map(|(heading, _)| heading.as_str()).collect::<Vec<_>>(), vec!["1 Act I", "2 Act II"]);
+        assert!(chunks[0].1.contains("[Enter Ghost.]") && chunks[0].1.contains("[Exit Ghost.]") && !chunks[0].1.contains("Horatio"));
+        assert!(chunks[1].1.contains("[Enter Horatio.]") && chunks[1].1.contains("[Exit Hamlet.]"));
+        assert_eq!(chunks.iter().map(|(_, body)| body.as_str()).collect::<String>(), plain);
+    }
+
+    #[test]
+    fn speaking_order_follows_the_recitation() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[2] = ("scene2.txt", "Hamlet hamlet2.txt\nHoratio horatio.txt\nMarcellus marcellus.txt\n");
+        files[5] = ("hamlet2.txt", "1 Horatio, or I do forget myself.\n3 Sir, my good friend.\n");
+        files.push(("marcellus.txt", "4 My good lord.\n"));
+        let mut play = prepared_play(&files, "script.txt");
+        let mut out = Vec::new();
+        play.print_speaking_order(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "Scene \"Act I\": Hamlet, Ghost, Hamlet, Ghost\n",
+            "Scene \"Act II\": Hamlet, Horatio, Hamlet, Marcellus\n",
+        ));
+        assert!(recite_to_string(&mut play, &StyleConfig::default()).contains("Sir, my good friend.\n\n Marcellus\nMy good lord.\n"));
+    }
+
+    #[test]
+    fn title_ending_in_a_file_name_is_complained_about_but_kept() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(parse_config_line("[scene] The Storm scene1.txt"), (
+            vec![(NEW_SCENE_BOOL, "The Storm scene1.txt".to_string())],
+            vec!["Warning: scene title \"The Storm scene1.txt\" ends with something that looks like a file name; is the [scene] line malformed?".to_string()],
+        ));
+        assert_eq!(parse_config_line("[scene] The Storm"), (vec![(NEW_SCENE_BOOL, "The Storm".to_string())], vec![]));
+    }
+
+    #[test]
+    fn plays_differing_in_one_line_have_one_change() {
+        let _globals = lock_globals();
+        let play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        assert!(play.diff(&prepared_play(TWO_SCENE_PLAY, "script.txt")).is_empty());
+
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[6] = ("horatio.txt", "2 The same, my good lord.\n");
+        assert_eq!(play.diff(&prepared_play(&files, "script.txt")), vec![DiffEntry::Changed {
+            scene: 1, character: "Horatio".to_string(), number: 2,
+            old: "The same, my lord.".to_string(), new: "The same, my good lord.".to_string(),
+        }]);
+
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\n");
+        assert_eq!(play.diff(&prepared_play(&files, "script.txt"))[0], DiffEntry::SceneCount { ours: 2, theirs: 1 });
+    }
+
+    #[test]
+    fn script_with_more_scenes_than_the_limit_errors_before_reading_them() {
+        let _globals = lock_globals();
+        declarations::MAX_SCENES.store(2, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(Play::with_source(map_source(TWO_SCENE_PLAY)).prepare("script.txt"), Ok(()));
+
+        let script = "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nmissing.txt\n";
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", script);
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+        assert!(play.scene_titles().is_empty());
+    }
+
+    #[test]
+    fn transform_uppercases_the_text_of_every_line() {
+        let _globals = lock_globals();
+        let plain = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        let mut out = Vec::new();
+        let mut shout = |text: &str| text.to_uppercase();
+        prepared_play(TWO_SCENE_PLAY, "script.txt").recite_with_transform(&StyleConfig::default(), &mut out, Some(&mut shout));
+        let shouted = String::from_utf8(out).unwrap();
+        assert!(shouted.contains(" Hamlet\nWHO'S THERE?\n\n Ghost\nMARK ME.\n"), "{}", shouted);
+        assert!(shouted.contains("\nAct II\n\n[Enter Horatio.]\n"));
+        let expected: String = plain.lines()
+            .map(|line| if line.starts_with(' ') || line.starts_with('[') || line.starts_with("Act") { line.to_string() } else { line.to_uppercase() })
+            .map(|line| line + "\n")
+            .collect();
+        assert_eq!(shouted, expected);
+    }
+
+    #[test]
+    fn character_named_in_another_case_stays_on_stage_when_case_is_ignored() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[2] = ("scene2.txt", "HAMLET hamlet2.txt\nHoratio horatio.txt\n");
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Exit Hamlet.]") && transcript.contains("[Enter HAMLET.]"));
+
+        declarations::IGNORE_NAME_CASE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Exit Ghost.]\n\n\nAct II\n\n[Enter Horatio.]\n\n HAMLET\n"), "{}", transcript);
+        assert!(!transcript.contains("[Exit Hamlet.]") && !transcript.contains("[Enter HAMLET.]"));
+        assert!(transcript.ends_with("[Exit Horatio.]\n[Exit HAMLET.]\n\n"));
+    }
+
+    #[test]
+    fn skipped_empty_middle_scene_does_not_keep_its_cast_on_stage() {
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Dumb show\nscene2.txt\n[scene] Act III\nscene3.txt\n"),
+            ("scene1.txt", "Hamlet hamlet.txt\n"),
+            ("scene2.txt", "Hamlet silent.txt\nGhost silent.txt\n"),
+            ("scene3.txt", "Ghost ghost.txt\n"),
+            ("hamlet.txt", "0 Who's there?\n"),
+            ("ghost.txt", "1 Mark me.\n"),
+            ("silent.txt", ""),
+        ];
+        assert!(recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default()).contains("\nDumb show\n"));
+        let style = StyleConfig { skip_empty_scenes: true, ..StyleConfig::default() };
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &style);
+        assert_eq!(transcript, concat!(
+            "\n",
+            "Act I\n\n",
+            "[Enter Hamlet.]\n\n",
+            " Hamlet\nWho's there?\n\n",
+            "[Exit Hamlet.]\n\n\n",
+            "Act III\n\n",
+            "[Enter Ghost.]\n\n",
+            " Ghost\nMark me.\n\n",
+            "[Exit Ghost.]\n\n",
+        ));
+    }
+
+    #[test]
+    fn report_counts_the_lines_scenes_and_warnings_of_a_recitation() {
+        let _globals = lock_globals();
+        let mut out = Vec::new();
+        let report = prepared_play(TWO_SCENE_PLAY, "script.txt").recite(&StyleConfig::default(), &mut out);
+        assert_eq!(report, ReciteReport { lines_spoken: 6, scenes: 2, warnings: 0 });
+
+        declarations::WHINGE_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        declarations::take_warnings();
+        let report = play.recite(&StyleConfig::default(), &mut out);
+        assert_eq!(report, ReciteReport { lines_spoken: 6, scenes: 2, warnings: 2 });
+        assert_eq!(declarations::take_warnings(), vec!["Warning: missing line 0", "Warning: missing line 0"]);
+    }
+
+    #[test]
+    fn parallel_plays_line_up_by_line_number_in_columns() {
+        let _globals = lock_globals();
+        let english = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\n"),
+            ("hamlet.txt", "0 Who's there?\n1 Stand and unfold yourself.\n3 Long live the king!\n"),
+        ];
+        let german = [
+            ("script.txt", "[scene] Erster Akt\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\n"),
+            ("hamlet.txt", "0 Wer da?\n1 Steht und gebt Euch kund.\n2 Nur ein Wort.\n"),
+        ];
+        let mut out = Vec::new();
+        prepared_play(&english, "script.txt").side_by_side(&prepared_play(&german, "script.txt"), 16, &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "\n\
+            Act I            | Erster Akt\n\
+            \n\
+            Hamlet: Who's    | Hamlet: Wer da?\n\
+            there?           |\n\
+            Hamlet: Stand    | Hamlet: Steht\n\
+            and unfold       | und gebt Euch\n\
+            yourself.        | kund.\n\
+            \x20                | Hamlet: Nur ein\n\
+            \x20                | Wort.\n\
+            Hamlet: Long     |\n\
+            live the king!   |\n");
+    }
+
+    #[test]
+    fn written_back_play_prepares_to_the_same_fingerprint() {
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] \"Act   I\"\nscene1.txt\n[scene] Act II\nscene2.txt\n"),
+            ("scene1.txt", "  Hamlet   hamlet1.txt\nGhost ghost.txt\n"),
+            ("scene2.txt", "Hamlet hamlet2.txt\nHoratio horatio.txt\n"),
+            ("hamlet1.txt", "3   Speak, I am bound to hear.\n\n1 Who's there?\n"),
+            ("ghost.txt", "4 I am thy father's spirit.\n2 Mark me.\n"),
+            ("hamlet2.txt", "1 Horatio, or I do forget myself.\n"),
+            ("horatio.txt", "2 The same, my lord.\n"),
+        ];
+        let play = prepared_play(&files, "script.txt");
+        let dir = temp_dir("write_back");
+        assert_eq!(play.write_back(&dir.to_string_lossy()), Ok(()));
+
+        let mut again = Play::with_source(Arc::new(DirSource(dir)));
+        let script = format!("{}{}", WRITE_BACK_SCRIPT, declarations::part_file_extension());
+        assert_eq!(again.prepare(&script), Ok(()));
+        assert_eq!(again.fingerprint(), play.fingerprint());
+        let style = StyleConfig::default();
+        assert_eq!(recite_to_string(&mut again, &style), recite_to_string(&mut play.fresh_copy(), &style));
+    }
+
+    #[test]
+    fn recitation_stops_after_the_until_line_with_everyone_exiting() {
+        let _globals = lock_globals();
+        declarations::UNTIL_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        declarations::UNTIL_LINE.store(2, std::sync::atomic::Ordering::SeqCst);
+        let transcript = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        assert_eq!(transcript, concat!(
+            "\n",
+            "Act I\n\n",
+            "[Enter Hamlet.]\n[Enter Ghost.]\n\n",
+            " Hamlet\nWho's there?\n\n",
+            " Ghost\nMark me.\n\n",
+            "[Exit Ghost.]\n[Exit Hamlet.]\n\n",
+        ));
+
+        let mut continued = TWO_SCENE_PLAY.to_vec();
+        continued[5] = ("hamlet2.txt", "5 Horatio, or I do forget myself.\n");
+        continued[6] = ("horatio.txt", "6 The same, my lord.\n");
+        declarations::CONTINUOUS_NUMBERING_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        declarations::UNTIL_LINE.store(5, std::sync::atomic::Ordering::SeqCst);
+        let transcript = recite_to_string(&mut prepared_play(&continued, "script.txt"), &StyleConfig::default());
+        assert!(transcript.ends_with(concat!(
+            "\n",
+            "Act II\n\n",
+            "[Enter Horatio.]\n\n",
+            " Hamlet\nHoratio, or I do forget myself.\n\n",
+            "[Exit Horatio.]\n[Exit Hamlet.]\n\n",
+        )), "{}", transcript);
+    }
+
+    #[test]
+    fn huge_until_line_recites_the_whole_play() {
+        let _globals = lock_globals();
+        declarations::LINE_BASE.store(1, std::sync::atomic::Ordering::SeqCst);
+        let expected = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        declarations::UNTIL_ON.store(true, std::sync::atomic::Ordering::SeqCst);
+        declarations::UNTIL_LINE.store(usize::MAX, std::sync::atomic::Ordering::SeqCst);
+        let transcript = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        assert_eq!(transcript, expected);
+        assert!(transcript.contains("I am thy father's spirit.") && transcript.contains("The same, my lord."));
+    }
+
+    #[test]
+    fn interlude_is_called_between_each_pair_of_scenes_with_their_indices() {
+        let _globals = lock_globals();
+        let mut files = TWO_SCENE_PLAY.to_vec();
+        files[0] = ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n[scene] Act III\nscene2.txt\n");
+        let mut play = prepared_play(&files, "script.txt");
+        let mut boundaries = Vec::new();
+        let mut interlude = |previous: usize, next: usize, out: &mut dyn Write| {
+            boundaries.push((previous, next));
+            match writeln!(out, "[Music plays.]") {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        };
+        let mut out = Vec::new();
+        play.recite_with_interlude(&StyleConfig::default(), &mut out, &mut interlude);
+        assert_eq!(boundaries, vec![(0, 1), (1, 2)]);
+        let transcript = String::from_utf8(out).unwrap();
+        assert_eq!(transcript.matches("[Music plays.]").count(), 2);
+        assert!(transcript.contains("[Exit Ghost.]\n\n[Music plays.]\n\nAct II\n\n[Enter Horatio.]\n"), "{}", transcript);
+    }
+
+    #[test]
+    fn stats_json_counts_a_known_play() {
+        let _globals = lock_globals();
+        let play = prepared_play(TWO_SCENE_PLAY, "script.txt");
+        let mut out = Vec::new();
+        assert_eq!(play.to_stats_json(&mut out), Ok(()));
+        assert_eq!(String::from_utf8(out).unwrap(), format!(
+            "{{\"scenes\": 2, \"characters\": 3, \"line_counts\": {{\"Hamlet\": 3, \"Ghost\": 2, \"Horatio\": 1}}, \"total_lines\": 6, \"fingerprint\": \"{}\"}}\n",
+            play.fingerprint()));
+    }
+
+    #[test]
+    fn fragments_round_trip_into_a_play_that_recites_the_same() {
+        let _globals = lock_globals();
+        let expected = recite_to_string(&mut prepared_play(TWO_SCENE_PLAY, "script.txt"), &StyleConfig::default());
+        let fragments = prepared_play(TWO_SCENE_PLAY, "script.txt").into_fragments();
+        assert_eq!(fragments.len(), 2);
+        let mut rebuilt = Play::from_fragments(fragments);
+        assert_eq!(recite_to_string(&mut rebuilt, &StyleConfig::default()), expected);
+    }
 }
@@ -10,20 +10,31 @@
  */
 
 use std::cmp::Ordering;
+use std::fmt;
 use std::io::Write;
 
 use super::declarations;
+use super::line_source::LineSource;
+use super::style::{name_color, truncate_text, wrap_text, BlankLinePolicy, StyleConfig, COLOR_RESET};
 
 const EMPTY: usize = 0;
 const FIRST_LINE: usize = 0;
+const SECOND_LINE: usize = 1;
+const LINE_PAIR: usize = 2;
+const ASIDE_MARKER: char = '*';
+const ASIDE_PREFIX: &str = "(aside) ";
+const DISPLAY_INDENT: &str = "  ";
+pub const DEFAULT_PRIORITY: usize = usize::MAX; // sorts after any explicit priority
 
 
-pub type PlayLines = Vec<(usize, String)>; // (line number, string)
+pub type PlayLines = Vec<(usize, String, bool)>; // (line number, string, is an aside)
 
 pub struct Player {
-    pub name: String,
+    name: String,
     lines: PlayLines,
     line_index: usize,
+    priority: usize,
+    silent_entrance: bool, // entrances and exits are announced collectively rather than by name
 }
 
 impl Player {
@@ -32,71 +43,304 @@ impl Player {
             name: name.to_string(),
             lines: PlayLines::new(),
             line_index: EMPTY,
+            priority: DEFAULT_PRIORITY,
+            silent_entrance: false,
         }
     }
 
+    // This method returns an independent copy of the character's parsed lines that has not spoken
+    // any of them yet, so that it can be recited separately from this one
+    pub fn fresh_copy(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            lines: self.lines.clone(),
+            line_index: EMPTY,
+            priority: self.priority,
+            silent_entrance: self.silent_entrance,
+        }
+    }
+
+    // This method returns the character's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // This method sets the character's announcement priority. Characters with a lower priority
+    // are announced first regardless of when they first speak
+    pub fn set_priority(&mut self, priority: usize) {
+        self.priority = priority;
+    }
+
+    // This method marks the character as entering and exiting silently, so that they are covered
+    // by a collective stage direction rather than being announced by name
+    pub fn set_silent_entrance(&mut self, silent_entrance: bool) {
+        self.silent_entrance = silent_entrance;
+    }
+
+    // This method returns whether the character enters and exits silently
+    pub fn is_silent_entrance(&self) -> bool {
+        self.silent_entrance
+    }
+
     // This method parses a line to add to a Player's lines, separating the line number from the
-    // content before adding tuple containing these items into the Player's lines. It raises
-    // warnings if parsing fails and the line should not be added
+    // content before adding tuple containing these items into the Player's lines. A line that
+    // does not start with a number continues the previous line, so it is appended to that line's
+    // text on a new line. A line number followed by * marks the line as an aside, and a line
+    // number with nothing after it is an intentionally empty line. Blank lines are ignored. In
+    // preserve indent mode the indentation of the text after the line number, and of continuation
+    // lines, is kept. If inline comments are being stripped, everything from the first comment
+    // marker on is dropped before the line is parsed, even if the marker is inside quotes. Line
+    // numbers are read in the configured number base, which is decimal by default. It
+    // raises warnings if parsing fails and the line should not be added
     fn add_script_line(&mut self, unparsed_line: &str) {
-        if unparsed_line.len() > 0{
-            if let Some((first_token, rest)) = unparsed_line.split_once(char::is_whitespace) {
-                let first_token_trim = first_token.trim();
-                let rest_trim = rest.trim();
-
-                match first_token_trim.parse::<usize>() {
-                    Ok(num) => self.lines.push((num, rest_trim.to_string())),
-                    Err(_) => {
-                        use std::sync::atomic::Ordering;
-                        if declarations::WHINGE_ON.load(Ordering::SeqCst) {
-                            match writeln!(std::io::stderr().lock(), "Warning: {} does not contain a valid usize value", first_token_trim) {
-                                Ok(_) => {},// success
-                                Err(_) => {},//fail
-                            }
-                        }
-                    },
-                }
-            } else {
-                use std::sync::atomic::Ordering;
-                if declarations::WHINGE_ON.load(Ordering::SeqCst) {
-                    match writeln!(std::io::stderr().lock(), "Warning: line contains only a single token and is invalid") {
-                        Ok(_) => {}, // success
-                        Err(_) => {}, //fail
+        use std::sync::atomic::Ordering;
+        let preserve_indent = declarations::PRESERVE_INDENT_ON.load(Ordering::SeqCst);
+        let marker = declarations::comment_marker();
+        let unparsed_line = match unparsed_line.find(&marker) {
+            Some(at) if declarations::STRIP_INLINE_COMMENTS_ON.load(Ordering::SeqCst) => &unparsed_line[..at],
+            _ => unparsed_line,
+        };
+        let unparsed_line = if preserve_indent { unparsed_line.trim_end() } else { unparsed_line.trim() };
+        if !unparsed_line.trim_start().is_empty() {
+            let (first_token, rest) = match unparsed_line.trim_start().split_once(char::is_whitespace) {
+                Some((first_token, rest)) => (first_token, Some(if preserve_indent { rest } else { rest.trim() })),
+                None => (unparsed_line.trim_start(), None),
+            };
+            let (number_token, is_aside) = match first_token.strip_suffix(ASIDE_MARKER) {
+                Some(number_token) => (number_token, true),
+                None => (first_token, false),
+            };
+
+            let base = declarations::NUMBER_BASE.load(Ordering::SeqCst);
+            match (usize::from_str_radix(number_token, base), rest) {
+                (Ok(num), rest_trim) => self.lines.push((num, rest_trim.unwrap_or_default().to_string(), is_aside)),
+                (Err(_), _) => {
+                    match self.lines.last_mut() {
+                        Some((_, text, _)) => {
+                            text.push('\n');
+                            text.push_str(unparsed_line);
+                        },
+                        None => {
+                            declarations::whinge(&format!("Warning: {} does not contain a valid usize value and there is no previous line to continue", first_token));
+                        },
                     }
-                }
+                },
             }
         }
     }
 
-    // This method adds the lines from a character's part file into the character's Player struct
-    // lines field
-    pub fn prepare(&mut self, file_name: &str) {
-        let mut lines: Vec<String> = Vec::new();
-        if let Err(_) = declarations::grab_trimmed_file_lines(file_name, &mut lines){
-            panic!("Failed to read file");
-        }
+    // This method adds the lines from a character's part file, read from the given source, into
+    // the character's Player struct lines field. The lines are sorted by number afterwards, but in
+    // require sorted mode a part file whose lines are not already in ascending order is an error,
+    // reported at the first pair that is out of order. If the part file could not be read or was
+    // out of order the error is propagated out and otherwise Ok(()) is returned. In preserve indent
+    // mode the lines are read with their leading indentation. Line numbers used
+    // more than once are complained about in whinge mode, but every line is kept. A numbered line
+    // with no text may be a line that was cut short, so it is an error in strict mode and is
+    // complained about in whinge mode, and is otherwise recited as an empty line. In progress mode
+    // a message is printed to stderr every PROGRESS_INTERVAL lines while a part file is read.
+    pub fn prepare(&mut self, file_name: &str, source: &dyn LineSource) -> Result<(), u8> {
+        use std::sync::atomic::Ordering;
+        let preserve_indent = declarations::PRESERVE_INDENT_ON.load(Ordering::SeqCst);
+        let lines = if declarations::PROGRESS_ON.load(Ordering::SeqCst) {
+            let mut report = |read: usize| {
+                match writeln!(std::io::stderr().lock(), "Read {} lines of {}...", read, file_name) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+            };
+            if preserve_indent {
+                source.indented_lines_with_progress(file_name, declarations::PROGRESS_INTERVAL, &mut report)?
+            } else {
+                source.lines_with_progress(file_name, declarations::PROGRESS_INTERVAL, &mut report)?
+            }
+        } else if preserve_indent {
+            source.indented_lines(file_name)?
+        } else {
+            source.lines(file_name)?
+        };
         for line in &lines {
             self.add_script_line(line);
         }
+        for (number, _, _) in self.lines.iter().filter(|(_, text, _)| text.trim().is_empty()) {
+            if declarations::STRICT_ON.load(Ordering::SeqCst) {
+                match writeln!(std::io::stderr().lock(), "Error: part file {} has line {} with no text", file_name, number) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return Err(declarations::ERR_SCRIPT_GEN);
+            }
+            declarations::whinge(&format!("Warning: part file {} has line {} with no text", file_name, number));
+        }
+        if declarations::REQUIRE_SORTED_ON.load(Ordering::SeqCst) {
+            if let Some(pair) = self.lines.windows(LINE_PAIR).find(|pair| pair[FIRST_LINE].0 > pair[SECOND_LINE].0) {
+                match writeln!(std::io::stderr().lock(), "Error: part file {} has line {} after line {}", file_name, pair[SECOND_LINE].0, pair[FIRST_LINE].0) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return Err(declarations::ERR_SCRIPT_GEN);
+            }
+        }
+        self.lines.sort();
+        for number in self.duplicate_line_numbers() {
+            declarations::whinge(&format!("Warning: character {} has duplicate line number {}", self.name, number));
+        }
+        Ok(())
+    }
+
+    // This method returns each line number that appears more than once in the character's lines,
+    // once each and in ascending order. The lines must already be sorted so that lines sharing a
+    // number are next to each other.
+    pub fn duplicate_line_numbers(&self) -> Vec<usize> {
+        let mut duplicates: Vec<usize> = Vec::new();
+        for pair in self.lines.windows(LINE_PAIR) {
+            let number = pair[FIRST_LINE].0;
+            if number == pair[SECOND_LINE].0 && duplicates.last() != Some(&number) {
+                duplicates.push(number);
+            }
+        }
+        duplicates
+    }
+
+    // This method combines another part for the same character into this one, appending its lines
+    // and re-sorting so they are spoken in order. Lines sharing a number are both kept.
+    pub fn merge(&mut self, other: Player) {
+        self.lines.extend(other.lines);
         self.lines.sort();
     }
 
     // This method speaks the character's next line. If the character was not previously speaking,
-    // it introduces the character by printing their name before printing the desired line
-    pub fn speak(&mut self, recent_player: &mut String) {
-        if self.line_index < self.lines.len() {
-            if *recent_player != self.name {
-                *recent_player = self.name.clone();
-                match writeln!(std::io::stdout().lock(), "\n {}", self.name){
-                    Ok(_) => {}, //success
-                    Err(_) => {}, //fail
-                }
+    // it introduces the character by printing their name before printing the desired line, marking
+    // it if it is an aside. The style's blank line policy decides whether a blank line is printed
+    // first, and the line is cut short with an ellipsis if the style has a truncate width and then
+    // word wrapped if the style has a wrap width. Only the printed text is shortened, never the
+    // stored line. Nothing is printed once the output line limit has been reached. The text of the
+    // line is passed through the given transform before it is marked, truncated, wrapped, and
+    // written to the given writer. It returns whether a line was spoken. Being asked to speak with
+    // no lines left means the scene has lost track of who speaks next, so it is complained about in
+    // whinge mode rather than passed over silently.
+    pub fn speak(
+        &mut self,
+        recent_player: &mut String,
+        style: &StyleConfig,
+        transform: &mut dyn FnMut(&str) -> String,
+        out: &mut dyn Write,
+    ) -> bool {
+        if self.line_index >= self.lines.len() {
+            declarations::whinge(&format!("Warning: character {} was asked to speak but has no lines left", self.name));
+            return false;
+        }
+        if !declarations::count_output_line() {
+            return false;
+        }
+        let new_speaker = *recent_player != self.name;
+        let blank_line = match style.blank_lines {
+            BlankLinePolicy::OnSpeakerChange => new_speaker,
+            BlankLinePolicy::Always => true,
+            BlankLinePolicy::Never => false,
+        };
+        if blank_line {
+            match writeln!(out) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
             }
-            let (_, line) = &self.lines[self.line_index];
-            match writeln!(std::io::stdout().lock(), "{}", line) {
+        }
+        if new_speaker {
+            *recent_player = self.name.clone();
+            self.introduce(out, style.color);
+        }
+        let (_, line, is_aside) = &self.lines[self.line_index];
+        let line = transform(line);
+        let line = if *is_aside { format!("{}{}", ASIDE_PREFIX, line) } else { line };
+        let line = match style.truncate_width {
+            Some(width) => truncate_text(&line, width),
+            None => line,
+        };
+        let text = match style.wrap_width {
+            Some(width) => wrap_text(&line, width).join("\n"),
+            None => line,
+        };
+        match writeln!(out, "{}", text) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        self.line_index += 1;
+        true
+    }
+
+    // This method speaks the character's whole part to the given writer, printing their name once
+    // and then every one of their lines in order, with asides marked. Afterwards the character has
+    // no lines left to speak.
+    pub fn speak_all(&mut self, w: &mut dyn Write) {
+        if self.lines.is_empty() {
+            return;
+        }
+        self.introduce(w, false);
+        for index in FIRST_LINE..self.lines.len() {
+            match writeln!(w, "{}", self.line_text(index)) {
                 Ok(_) => {}, //success
                 Err(_) => {}, //fail
             }
+        }
+        self.line_index = self.lines.len();
+    }
+
+    // This method prints the character's name to introduce them when they start speaking, in the
+    // character's color if asked to
+    fn introduce(&self, w: &mut dyn Write, color: bool) {
+        let result = if color {
+            writeln!(w, " {}{}{}", name_color(&self.name), self.name, COLOR_RESET)
+        } else {
+            writeln!(w, " {}", self.name)
+        };
+        match result {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+    }
+
+    // This method returns the text of the line at the given index as it is spoken, with the aside
+    // prefix if it is an aside
+    fn line_text(&self, index: usize) -> String {
+        let (_, line, is_aside) = &self.lines[index];
+        if *is_aside { format!("{}{}", ASIDE_PREFIX, line) } else { line.clone() }
+    }
+
+    // This method returns the character's parsed lines in line number order
+    pub fn lines(&self) -> &PlayLines {
+        &self.lines
+    }
+
+    // This method returns whether the character has a line with the given number
+    pub fn has_line(&self, number: usize) -> bool {
+        self.lines.iter().any(|(n, _, _)| *n == number)
+    }
+
+    // This method returns the character's announcement priority
+    pub fn priority(&self) -> usize {
+        self.priority
+    }
+
+    // This method returns the character's lines as they would be written in a part file, as the
+    // line number, a * if it is an aside, and the text, in line number order. Continuation lines
+    // follow the line they continue, and an empty line is just its number.
+    pub fn part_file_lines(&self) -> Vec<String> {
+        self.lines.iter()
+            .map(|(number, text, is_aside)| {
+                let marker = if *is_aside { ASIDE_MARKER.to_string() } else { String::new() };
+                format!("{}{} {}", number, marker, text).trim_end().to_string()
+            })
+            .collect()
+    }
+
+    // This method returns how many lines the character speaks
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    // This method moves past the character's next line without printing it
+    pub fn skip_line(&mut self) {
+        if self.line_index < self.lines.len() {
             self.line_index += 1;
         }
     }
@@ -105,7 +349,7 @@ impl Player {
     // exists and None otherwise 
     pub fn next_line(&self) -> Option<usize> {
         if self.line_index < self.lines.len() {
-            let (line_num, _) = &self.lines[self.line_index];
+            let (line_num, _, _) = &self.lines[self.line_index];
             Some(*line_num)
         } else {
             None
@@ -116,15 +360,17 @@ impl Player {
 
 impl PartialEq for Player {
     fn eq(&self, other: &Self) -> bool {
-        let self_silent = self.lines.len() == 0;
-        let other_silent = other.lines.len() == 0;
-        if self_silent && other_silent {
+        let self_silent = self.lines.is_empty();
+        let other_silent = other.lines.is_empty();
+        if self.priority != other.priority {
+            false
+        } else if self_silent && other_silent {
             true
         } else if self_silent || other_silent {
             false
         } else {
-            let (self_first, _) = self.lines[FIRST_LINE];
-            let (other_first, _) = other.lines[FIRST_LINE];
+            let (self_first, _, _) = self.lines[FIRST_LINE];
+            let (other_first, _, _) = other.lines[FIRST_LINE];
             self_first == other_first
         }
     }
@@ -140,20 +386,43 @@ impl PartialOrd for Player {
     }
 }
 
+// Players are ordered by priority first, and players of equal priority by their first line
 impl Ord for Player {
     fn cmp(&self, other: &Self) -> Ordering {
-        let self_silent = self.lines.len() == 0;
-        let other_silent = other.lines.len() == 0;
+        if self.priority != other.priority {
+            return self.priority.cmp(&other.priority);
+        }
+        let self_silent = self.lines.is_empty();
+        let other_silent = other.lines.is_empty();
 
         match (self_silent, other_silent) {
             (true, true) => Ordering::Equal,
             (true, false) => Ordering::Less,
             (false, true) => Ordering::Greater,
             (false, false) => {
-                let (self_first, _) = self.lines[FIRST_LINE];
-                let (other_first, _) = other.lines[FIRST_LINE];
+                let (self_first, _, _) = self.lines[FIRST_LINE];
+                let (other_first, _, _) = other.lines[FIRST_LINE];
                 self_first.cmp(&other_first)
             },
         }
     }
 }
+
+
+// Players are displayed for debugging as their name on its own line followed by one indented line
+// per spoken line, giving its number, a * if it is an aside, and its text. Continuation lines of
+// a line are indented the same amount:
+// Hamlet
+//   1 Who is there?
+//   4* I will watch.
+//   and wait.
+impl fmt::Display for Player {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        for (number, text, is_aside) in &self.lines {
+            let marker = if *is_aside { ASIDE_MARKER.to_string() } else { String::new() };
+            writeln!(f, "{}{}{} {}", DISPLAY_INDENT, number, marker, text.replace('\n', &format!("\n{}", DISPLAY_INDENT)))?;
+        }
+        Ok(())
+    }
+}
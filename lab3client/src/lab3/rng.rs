@@ -0,0 +1,46 @@
+/*
+ * Author: Daniel Palmer
+ * Email: d.m.palmer@wustl.edu
+ * File: rng.rs
+ * Summary: This file contains the Xorshift struct, a small deterministic pseudo
+ * random number generator. It is used where the program needs reproducible
+ * randomness from a seed given on the command line, without pulling in a crate.
+ *
+ */
+
+// xorshift64 never leaves the all zero state, so a seed of zero is replaced by this value
+const ZERO_SEED_REPLACEMENT: u64 = 0x9E37_79B9_7F4A_7C15;
+const SHIFT_A: u32 = 13;
+const SHIFT_B: u32 = 7;
+const SHIFT_C: u32 = 17;
+const SECOND_INDEX: usize = 1;
+
+
+pub struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: if seed == 0 { ZERO_SEED_REPLACEMENT } else { seed },
+        }
+    }
+
+    // This function advances the generator and returns the next value
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << SHIFT_A;
+        self.state ^= self.state >> SHIFT_B;
+        self.state ^= self.state << SHIFT_C;
+        self.state
+    }
+
+    // This function shuffles a slice in place with a Fisher-Yates shuffle, so the same seed always
+    // produces the same order
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (SECOND_INDEX..items.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
@@ -8,25 +8,41 @@
  *
  */
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
 use std::cmp::Ordering;
+use std::fmt;
 use std::thread;
+use std::time::Duration;
 
-use super::player::Player;
+use super::player::{Player, DEFAULT_PRIORITY};
+use super::rng::Xorshift;
+use super::style::StyleConfig;
 use super::declarations;
+use super::line_source::{LineSource, SharedSource};
 
 
-type PlayConfig = Vec<(String, String)>; // (character name, associated text file)
+type PlayConfig = Vec<(String, String, usize, bool)>; // (character name, associated text file, priority, enters silently)
 
 const CHARACTER_NAME: usize = 0;
 const CHARACTER_FILE: usize = 1;
+const CHARACTER_PRIORITY: usize = 2;
 const CONFIG_LINE_TOKENS: usize = 2;
-const MIN_CONFIG_LINES: usize = 2;
-const FIRST_LINE: usize = 0;
-const EMPTY: usize = 0;
+const NAME_ONLY_TOKENS: usize = 1;
+const PRIORITY_LINE_TOKENS: usize = 3;
+const COMMENT_INDICATOR: &str = "[comment]";
+const MIN_CONFIG_ENTRIES: usize = 1;
+const FIRST_CHARACTER: usize = 0;
 const EXPECTED_NUM_SPEAKERS: usize = 1;
+const NO_LINES: usize = 0;
+const MAX_REPORTED_GAP: usize = 1000; // the most missing lines reported one by one
+const UNAVAILABLE: &str = "(unavailable)";
+const SILENT_MARKER: char = '-';
+const CONFIG_QUOTE: char = '"'; // groups a name or part file containing spaces into one token
+const ENTER: &str = "Enter";
+const EXIT: &str = "Exit";
 
 macro_rules! poison_mutex_print {
     () => {
@@ -38,8 +54,22 @@ macro_rules! poison_mutex_print {
 }
 
 
+// A single spoken line of the play along with where it comes from
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SpokenLine {
+    pub scene: String,
+    pub character: String,
+    pub number: usize,
+    pub text: String,
+}
+
+
 pub struct SceneFragment {
     pub scene_title: String,
+    pub prepare_time: Duration,
+    pub recite_time: Duration,
+    pub lines_spoken: usize, // how many lines were printed the last time the scene was recited
+    pub title_occurrence: Option<usize>, // which use of a repeated title this is, when numbering them
     characters: Vec<Arc<Mutex<Player>>>,
 }
 
@@ -48,142 +78,414 @@ impl SceneFragment {
     pub fn new(title: &str) -> Self {
         Self {
             scene_title: title.to_string(),
+            prepare_time: Duration::ZERO,
+            recite_time: Duration::ZERO,
+            lines_spoken: NO_LINES,
+            title_occurrence: None,
             characters: Vec::new(),
         }
     }
 
+    // This function returns the title as it is printed when reciting, followed by which use of
+    // the title this is if repeated titles are being numbered
+    pub fn display_title(&self) -> String {
+        match self.title_occurrence {
+            Some(n) => format!("{} ({})", self.scene_title, n),
+            None => self.scene_title.clone(),
+        }
+    }
+
+    // This function returns an independent copy of the scene, with each character copied into a
+    // new lock rather than sharing this scene's, so that the copy can be recited on another
+    // thread without affecting this one. A character whose mutex was poisoned is left out.
+    pub fn fresh_copy(&self) -> Self {
+        let mut copy = Self::new(&self.scene_title);
+        copy.prepare_time = self.prepare_time;
+        copy.title_occurrence = self.title_occurrence;
+        for c in &self.characters {
+            match c.lock() {
+                Ok(ref c_guard) => copy.characters.push(Arc::new(Mutex::new(c_guard.fresh_copy()))),
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+        copy
+    }
+
     // This function processes a passed in PlayConfig. For each item in the PlayConfig it creates a
     // Player, adds it to the Play's characters, and prepares the character with its associated
-    // text file. 
+    // text file. If a part file cannot be read and skip missing mode is on, that character is
+    // dropped from the scene with a warning, and otherwise the error names the scene and character
+    // the part file belongs to.
     // If it fails the error is propagated out and otherwise Ok(()) is returned
-    fn process_config(&mut self, play_config: &PlayConfig) -> Result<(), u8> {
+    fn process_config(&mut self, play_config: &PlayConfig, source: &SharedSource) -> Result<(), u8> {
         let mut thread_handles = Vec::new();
-        for tup in play_config {
-            match tup {
-                (name, file) => {
-                    let file = file.to_string();
-                    let mut character = Player::new(&name);
-                    let handle = thread::spawn( move || -> Player{
-                        character.prepare(&file);
-                        character
-                    });
-                    thread_handles.push(handle);
-                }
-            }
+        for (name, file, priority, silent_entrance) in play_config {
+            let thread_file = file.to_string();
+            let thread_source = Arc::clone(source);
+            let mut character = Player::new(name);
+            character.set_priority(*priority);
+            character.set_silent_entrance(*silent_entrance);
+            let handle = thread::spawn( move || -> Result<Player, u8> {
+                character.prepare(&thread_file, thread_source.as_ref())?;
+                Ok(character)
+            });
+            thread_handles.push((name, file, handle));
         }
-        for h in thread_handles {
+        for (name, file, h) in thread_handles {
             match h.join() {
                 Err(_) => {
                     return Err(declarations::ERR_SCRIPT_GEN)
                 }
-                Ok(c) => {
-                    self.characters.push(Arc::new(Mutex::new(c)));
+                Ok(Err(e)) => {
+                    use std::sync::atomic::Ordering;
+                    if !declarations::SKIP_MISSING_ON.load(Ordering::SeqCst) {
+                        match writeln!(std::io::stderr().lock(), "{}", self.part_file_error(name, file)) {
+                            Ok(_) => {}, //success
+                            Err(_) => {}, //fail
+                        }
+                        return Err(e);
+                    }
+                    declarations::record_warning(&format!("Warning: skipping character {}: file {} unreadable", name, file));
+                }
+                Ok(Ok(c)) => {
+                    self.add_character(c);
                 }
             }
         }
         Ok(())
     }
 
+    // This function returns the error printed when the given character's part file could not be
+    // read, naming the scene and character so that the bad line of the config can be found
+    fn part_file_error(&self, name: &str, file: &str) -> String {
+        format!("Error: scene \"{}\" character \"{}\": cannot open part file {}", self.scene_title, name, file)
+    }
+
+    // This function adds a prepared Player to the scene. If a character with the same name is
+    // already in the scene, the new part is merged into the existing Player instead so that a
+    // character's lines can be split across several part files.
+    fn add_character(&mut self, character: Player) {
+        for existing in &self.characters {
+            match existing.lock() {
+                Ok(ref mut existing_guard) => {
+                    if existing_guard.name() == character.name() {
+                        declarations::whinge(&format!("Warning: merging multiple part files for character {}", character.name()));
+                        existing_guard.merge(character);
+                        return;
+                    }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+        self.characters.push(Arc::new(Mutex::new(character)));
+    }
+
     // This function splits the passed in line into two separate tokens and adds them as a tuple to
-    // the passed in PlayConfig. If the tokens could not be properly extracted and whinge mode is
-    // on it complains, but if there were at least two tokens (the minimum amount) it adds the
-    // line.
+    // the passed in PlayConfig. An optional third token gives the character's announcement
+    // priority, and characters without one get the default priority. A name starting with - marks
+    // a character, such as an attendant, who enters and exits without being announced by name. If
+    // part files are being inferred, a line with only a name is accepted and the part file is the
+    // name followed by the part file extension. If the tokens could not be properly extracted and
+    // whinge mode is on it complains, but if there were at least two tokens (the minimum amount) it
+    // adds the line. A line whose name looks like a file name while its file does not is also
+    // complained about, since the two were probably swapped, but it is added as written. Names and
+    // part files containing spaces can be written in double quotes. A line starting with [comment]
+    // is a note from the author that is ignored, as it is in the script, although it is echoed in
+    // whinge mode.
     fn add_config(line: &str, play_config: &mut PlayConfig) {
-        let delimited_tokens: Vec<&str> = line.split_whitespace().collect();
-        if delimited_tokens.len() != CONFIG_LINE_TOKENS {
-            use std::sync::atomic::Ordering;
-            if declarations::WHINGE_ON.load(Ordering::SeqCst) {
-                match writeln!(std::io::stderr().lock(), "Warning: there were not exactly two distinct tokens in the line {}", line) {
-                    Ok(_) => {}, //success
-                    Err(_) => {}, //fail
-                }
+        use std::sync::atomic::Ordering;
+        let tokens = Self::config_tokens(line);
+        if tokens.first().is_some_and(|first| first == COMMENT_INDICATOR) {
+            if tokens.len() > NAME_ONLY_TOKENS {
+                declarations::whinge(&format!("Note: {}", tokens[CHARACTER_FILE..].join(" ")));
             }
+            return;
+        }
+        let delimited_tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        let priority = if delimited_tokens.len() == PRIORITY_LINE_TOKENS {
+            delimited_tokens[CHARACTER_PRIORITY].parse::<usize>().ok()
+        } else {
+            None
+        };
+        let inferred = delimited_tokens.len() == NAME_ONLY_TOKENS && declarations::INFER_PART_FILES_ON.load(Ordering::SeqCst);
+        if delimited_tokens.len() != CONFIG_LINE_TOKENS && priority.is_none() && !inferred {
+            declarations::whinge(&format!("Warning: there were not exactly two distinct tokens in the line {}", line));
         }
-        if delimited_tokens.len() >= CONFIG_LINE_TOKENS {
+        if delimited_tokens.len() >= CONFIG_LINE_TOKENS || inferred {
+            let (name, silent_entrance) = match delimited_tokens[CHARACTER_NAME].strip_prefix(SILENT_MARKER) {
+                Some(name) if !name.is_empty() => (name, true),
+                _ => (delimited_tokens[CHARACTER_NAME], false),
+            };
+            if !inferred {
+                let extension = declarations::part_file_extension();
+                let looks_like_file = |token: &str| token.len() > extension.len() && token.ends_with(&extension);
+                if looks_like_file(delimited_tokens[CHARACTER_NAME]) && !looks_like_file(delimited_tokens[CHARACTER_FILE]) {
+                    declarations::whinge(&format!("Warning: the config line {} names a file before a character; are they reversed?", line));
+                }
+            }
+            let part_file = if inferred {
+                format!("{}{}", name, declarations::part_file_extension())
+            } else {
+                delimited_tokens[CHARACTER_FILE].to_string()
+            };
             play_config.push((
-                    delimited_tokens[CHARACTER_NAME].to_string(),
-                    delimited_tokens[CHARACTER_FILE].to_string()
+                    name.to_string(),
+                    part_file,
+                    priority.unwrap_or(DEFAULT_PRIORITY),
+                    silent_entrance
                     ));
         }
     }
 
 
+    // This function splits a config line into whitespace separated tokens, where whitespace
+    // inside double quotes is part of the token and the quotes themselves are dropped, so
+    // hamlet "my part.txt" is the two tokens hamlet and my part.txt. A quote that is never closed
+    // is complained about in whinge mode, and the rest of the line is taken as quoted.
+    fn config_tokens(line: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        let mut current = String::new();
+        let mut in_token = false;
+        let mut quoted = false;
+        for ch in line.chars() {
+            if ch == CONFIG_QUOTE {
+                quoted = !quoted;
+                in_token = true;
+            } else if ch.is_whitespace() && !quoted {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+            } else {
+                current.push(ch);
+                in_token = true;
+            }
+        }
+        if quoted {
+            declarations::whinge(&format!("Warning: unterminated quote in the config line {}", line));
+        }
+        if in_token {
+            tokens.push(current);
+        }
+        tokens
+    }
+
+    // This function returns a name or part file as it should be written in a config file, in
+    // double quotes if it contains whitespace so that it is read back as a single token
+    fn config_token(token: &str) -> String {
+        if token.chars().any(char::is_whitespace) {
+            format!("{}{}{}", CONFIG_QUOTE, token, CONFIG_QUOTE)
+        } else {
+            token.to_string()
+        }
+    }
+
 
     // This function reads a given config file name and populates the passed in title and
-    // play_config with the relevant information from this config file. It propagates any errors
-    // out and otherwise returns Ok(())
-    fn read_config(config_file_name: &str, play_config: &mut PlayConfig) -> Result<(), u8> {
-        let mut lines: Vec<String> = Vec::new();
-        declarations::grab_trimmed_file_lines(config_file_name, &mut lines)?;
-        if lines.len() < MIN_CONFIG_LINES {
+    // play_config with the relevant information from this config file. The config must yield at
+    // least one character entry, no matter how many lines it has. The file is checked
+    // against the includes already read by the play so that a cycle is reported rather than
+    // followed, and a part file shared by several characters is complained about in whinge mode.
+    // It propagates any errors out and otherwise returns Ok(())
+    fn read_config(
+        config_file_name: &str,
+        play_config: &mut PlayConfig,
+        includes: &mut HashSet<String>,
+        source: &dyn LineSource,
+    ) -> Result<(), u8> {
+        declarations::check_circular_include(config_file_name, includes)?;
+        let lines = source.lines(config_file_name)?;
+        for line in &lines {
+            Self::add_config(line, play_config);
+        }
+        if play_config.len() < MIN_CONFIG_ENTRIES {
             match writeln!(std::io::stderr().lock(), "Error: the config file must contain at least one character and associated text file") {
                 Ok(_) => {}, //success
                 Err(_) => {},//fail
             }
             return Err(declarations::ERR_SCRIPT_GEN);
         }
-        for line in &lines {
-            Self::add_config(line, play_config);
+
+        // Two characters reading from the same part file would speak the same lines, which is
+        // almost always a mistake in the config
+        let mut part_files: HashSet<&str> = HashSet::new();
+        let mut reported: HashSet<&str> = HashSet::new();
+        for (_, file, _, _) in play_config.iter() {
+            if !part_files.insert(file) && reported.insert(file) {
+                declarations::whinge(&format!("Warning: part file {} used by multiple characters in this scene", file));
+            }
         }
         Ok(())
     }
 
 
+    // This function echoes each entry of a parsed PlayConfig in a normalized form under the name
+    // of the config file it came from, giving the priority only if one was set and marking
+    // characters who enter silently. Scenes are prepared at the same time, so each config is
+    // echoed in one piece but the configs may come in any order.
+    fn echo_config(config_file_name: &str, play_config: &PlayConfig) {
+        let mut echo = format!("CONFIG: {}\n", config_file_name);
+        for (name, file, priority, silent_entrance) in play_config {
+            echo.push_str(&format!("  CHARACTER: {} PART: {}", name, file));
+            if *priority != DEFAULT_PRIORITY {
+                echo.push_str(&format!(" PRIORITY: {}", priority));
+            }
+            if *silent_entrance {
+                echo.push_str(" SILENT");
+            }
+            echo.push('\n');
+        }
+        declarations::echo_config(&echo);
+    }
+
+
     // This method does the script generation for a given scene. It uses the above functions to
-    // populate the self Play with associated information.
-    pub fn prepare(&mut self, config_file_name: &str)  {
+    // populate the self Play with associated information. A scene that ends up with no characters
+    // is complained about in whinge mode and is an error in strict mode. If a seed was given,
+    // characters who rank equally are shuffled with it after sorting, and in whinge mode it is
+    // pointed out if someone other than the first character announced speaks first or if several
+    // characters share a first line. All files are read from the given source. If the config or a
+    // part file could not be read, or the scene is empty in strict mode, the error is propagated
+    // out and otherwise Ok(()) is returned.
+    pub fn prepare(&mut self, config_file_name: &str, includes: &mut HashSet<String>, source: SharedSource) -> Result<(), u8> {
         let mut play_config: PlayConfig = Default::default();
-        if let Err(_) = Self::read_config(config_file_name, &mut play_config){
-            panic!("Failed to read config");
+        Self::read_config(config_file_name, &mut play_config, includes, source.as_ref())?;
+        use std::sync::atomic::Ordering;
+        if declarations::ECHO_CONFIG_ON.load(Ordering::SeqCst) {
+            Self::echo_config(config_file_name, &play_config);
         }
-        if let Err(_) = self.process_config(&play_config){
-            panic!("Failed to process config");
+        self.process_config(&play_config, &source)?;
+        if self.characters.is_empty() {
+            if declarations::STRICT_ON.load(Ordering::SeqCst) {
+                match writeln!(std::io::stderr().lock(), "Error: scene \"{}\" has no characters", self.scene_title) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return Err(declarations::ERR_SCRIPT_GEN);
+            }
+            declarations::whinge(&format!("Warning: scene \"{}\" has no characters", self.scene_title));
         }
         self.characters.sort_by(SceneFragment::compare_players);
+
+        if declarations::SHUFFLE_ON.load(Ordering::SeqCst) {
+            let mut rng = Xorshift::new(declarations::SHUFFLE_SEED.load(Ordering::SeqCst));
+            self.shuffle_ties(&mut rng);
+        }
+        self.check_first_speaker();
+        self.check_shared_first_lines();
+        Ok(())
     }
 
+    // This function complains in whinge mode if the first character to speak in the scene is not
+    // the first one named when everyone enters, which happens when a character with no lines is
+    // sorted ahead of the speakers. Characters who enter silently are not named, so they are
+    // passed over.
+    fn check_first_speaker(&self) {
+        let first_announced = self.cast().into_iter()
+            .find(|(_, silent_entrance)| !silent_entrance)
+            .map(|(name, _)| name);
+        let first_speaker = self.next_speakers()
+            .and_then(|(_, speakers)| {
+                match speakers[FIRST_CHARACTER].lock() {
+                    Ok(ref c_guard) => Some(c_guard.name().to_string()),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        None
+                    }
+                }
+            });
+        if let (Some(announced), Some(speaker)) = (first_announced, first_speaker) {
+            if announced != speaker {
+                declarations::whinge(&format!("Warning: scene \"{}\" announces {} first but {} speaks first", self.scene_title, announced, speaker));
+            }
+        }
+    }
 
-    // This method prints the play line by line by finding the player that has the next line and
-    // printing it out.
-    pub fn recite(&mut self) {
-        let mut next_line_number = FIRST_LINE;
-        let mut cur_speaker = String::new();
-        loop {
-            let min_line_number = match self.characters
-                .iter()
-                .filter_map(|c| {
-                    match c.lock() {
-                        Ok(ref c_guard) => c_guard.next_line(),
-                        Err(_) => {
-                            poison_mutex_print!();
-                            None
-                        }
+    // This function complains in whinge mode about each group of characters in the scene whose
+    // first lines have the same number, since the order they are announced and speak in then
+    // depends on how the tie was broken. Characters are listed in their sorted order, and
+    // characters with no lines are passed over.
+    fn check_shared_first_lines(&self) {
+        let mut by_first_line: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for c in &self.characters {
+            match c.lock() {
+                Ok(ref c_guard) => {
+                    if let Some((number, _, _)) = c_guard.lines().first() {
+                        by_first_line.entry(*number).or_default().push(c_guard.name().to_string());
                     }
-                })
-                .min(){
-                Some(n) => n,
-                None => break,
-            };
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
+            }
+        }
+        for (number, names) in by_first_line {
+            if names.len() > EXPECTED_NUM_SPEAKERS {
+                declarations::whinge(&format!("Warning: characters {} share first line {} in scene \"{}\"", names.join(", "), number, self.scene_title));
+            }
+        }
+    }
+
+
+    // This method prints the play line by line by finding the player that has the next line and
+    // printing it out in the given style. If only one character is being recited, the other
+    // characters' lines are passed over without being printed. Reciting stops early once the
+    // output line limit has been reached. Lines are expected to be numbered from the given line
+    // number, and the number after the last line recited is returned so that numbering can carry
+    // on into the next scene. Each line's text is passed through the given transform before it is
+    // spoken, and the scene is written to the given writer. How many lines were printed is kept in
+    // lines_spoken. If a last line number is given, reciting stops once no line up to it is left,
+    // and the number after it is returned. A character's name is printed before the first of a run
+    // of lines they speak with nobody else printed in between, so a run of consecutive lines gets
+    // one name, and the name is printed again after anyone else speaks, including a character
+    // sharing a line number with them.
+    pub fn recite(
+        &mut self,
+        style: &StyleConfig,
+        only: Option<&str>,
+        first_line_number: usize,
+        last_line: Option<usize>,
+        transform: &mut dyn FnMut(&str) -> String,
+        out: &mut dyn Write,
+    ) -> usize {
+        let mut next_line_number = first_line_number;
+        let mut cur_speaker = String::new(); // the last character printed, for the whole scene
+        let mut lines_spoken = NO_LINES;
+        while let Some((min_line_number, speakers)) = self.next_speakers() {
+            if declarations::output_truncated() {
+                break;
+            }
+            if let Some(last) = last_line.filter(|last| min_line_number > *last) {
+                next_line_number = next_line_number.max(last.saturating_add(1));
+                break;
+            }
             
-            // Skip over any missing line numbers, complaining if whinge mode is on
+            // Skip over any missing line numbers, complaining if whinge mode is on. A gap too
+            // large to be a few missing lines is jumped over with a single complaint
+            if min_line_number.saturating_sub(next_line_number) > MAX_REPORTED_GAP {
+                declarations::whinge(&format!("Warning: large line-number gap (> {}) near line {}, skipping", MAX_REPORTED_GAP, next_line_number));
+                next_line_number = min_line_number;
+            }
             while min_line_number > next_line_number {
-                use std::sync::atomic::Ordering;
-                if declarations::WHINGE_ON.load(Ordering::SeqCst) {
-                    match writeln!(std::io::stderr().lock(), "Warning: missing line {}", next_line_number) {
-                        Ok(_) => {}, //success
-                        Err(_) => {}, //fail
-                    }
-                }
+                declarations::whinge(&format!("Warning: missing line {}", next_line_number));
                 next_line_number += 1;
             }
 
-            let mut num_speakers = EMPTY;
-            for c in &self.characters {
+            let mut speaker_names = Vec::new();
+            for c in speakers {
                 match c.lock() {
                     Ok(ref mut c_guard) => {
-                        if c_guard.next_line() == Some(min_line_number) {
-                            c_guard.speak(&mut cur_speaker);
-                            num_speakers += 1;
+                        speaker_names.push(c_guard.name().to_string());
+                        if Self::is_shown(only, c_guard.name()) {
+                            if c_guard.speak(&mut cur_speaker, style, transform, out) {
+                                lines_spoken += 1;
+                            }
+                        } else {
+                            c_guard.skip_line();
                         }
                     }
                     Err(_) => {
@@ -192,141 +494,376 @@ impl SceneFragment {
                 }
             }
             
-            if num_speakers != EXPECTED_NUM_SPEAKERS {
-                use std::sync::atomic::Ordering;
-                if declarations::WHINGE_ON.load(Ordering::SeqCst) {
-                    match writeln!(std::io::stderr().lock(), "Warning: there are {} characters who have a line with number {}", num_speakers, min_line_number) {
-                        Ok(_) => {}, //success
-                        Err(_) => {}, //fail
-                    }
-                }
+            if speaker_names.len() != EXPECTED_NUM_SPEAKERS {
+                declarations::whinge(&format!("Warning: line {} spoken by {}", min_line_number, speaker_names.join(", ")));
             }
-            next_line_number += 1;
+            next_line_number = next_line_number.saturating_add(1);
         }
+        self.lines_spoken = lines_spoken;
+        next_line_number
     }
 
-    // This function announces all characters in self but not in other for scene transitions
-    pub fn enter(&self, other: &Self) {
-        if !self.scene_title.trim().is_empty(){
-            match writeln!(std::io::stdout().lock(), "\n{}\n", self.scene_title){
-                Ok(_) => {}, //success
-                Err(_) => {}, //fail
-            }
-        }
-        let other_names: HashSet<String> = other.characters.iter()
-            .filter_map(|c| {
+    // This function returns the lowest line number that any character has yet to speak, along
+    // with every character who speaks it in the order they are sorted, or None once everyone has
+    // finished. Everyone is gathered before any of them speak, since speaking advances their line
+    // index.
+    fn next_speakers(&self) -> Option<(usize, Vec<&Arc<Mutex<Player>>>)> {
+        let next_lines: Vec<(Option<usize>, &Arc<Mutex<Player>>)> = self.characters.iter()
+            .map(|c| {
                 match c.lock() {
-                    Ok(ref c_guard) => Some(c_guard.name.clone()),
+                    Ok(ref c_guard) => (c_guard.next_line(), c),
                     Err(_) => {
                         poison_mutex_print!();
-                        None
+                        (None, c)
                     }
                 }
             })
             .collect();
-        for name in self.characters.iter().filter_map(|c| {
+        let min_line_number = next_lines.iter().filter_map(|(next, _)| *next).min()?;
+        let speakers = next_lines.into_iter()
+            .filter(|(next, _)| *next == Some(min_line_number))
+            .map(|(_, c)| c)
+            .collect();
+        Some((min_line_number, speakers))
+    }
+
+    // This function returns the names of the characters in the order they speak, choosing the
+    // speakers of each line the same way reciting does. A character who speaks several lines in a
+    // row is only listed once for them. It works on a fresh copy, so the scene can still be
+    // recited afterwards.
+    pub fn speaking_order(&self) -> Vec<String> {
+        let scene = self.fresh_copy();
+        let mut order: Vec<String> = Vec::new();
+        while let Some((_, speakers)) = scene.next_speakers() {
+            for c in speakers {
+                match c.lock() {
+                    Ok(ref mut c_guard) => {
+                        if order.last().map(String::as_str) != Some(c_guard.name()) {
+                            order.push(c_guard.name().to_string());
+                        }
+                        c_guard.skip_line();
+                    }
+                    Err(_) => {
+                        poison_mutex_print!();
+                    }
+                }
+            }
+        }
+        order
+    }
+
+    // This function returns every line spoken in the scene in recitation order, which is by line
+    // number and then by character name for lines that share a number
+    pub fn spoken_lines(&self) -> Vec<SpokenLine> {
+        let mut spoken = Vec::new();
+        for c in &self.characters {
             match c.lock() {
-                Ok(ref c_guard) => Some(c_guard.name.clone()),
+                Ok(ref c_guard) => {
+                    for (number, text, _) in c_guard.lines() {
+                        spoken.push(SpokenLine {
+                            scene: self.scene_title.clone(),
+                            character: c_guard.name().to_string(),
+                            number: *number,
+                            text: text.clone(),
+                        });
+                    }
+                }
                 Err(_) => {
                     poison_mutex_print!();
-                    None
-                }
-            }
-        }) {
-            if !other_names.contains(&name) {
-                match writeln!(std::io::stdout().lock(), "[Enter {}.]", name) {
-                    Ok(_) => {}, //success
-                    Err(_) => {}, //fail
                 }
             }
         }
-        
+        spoken.sort_by(|a, b| a.number.cmp(&b.number).then_with(|| a.character.cmp(&b.character)));
+        spoken
     }
-    // This function announces the entrance of all characters in self
-    pub fn enter_all(&self) {
-        if !self.scene_title.trim().is_empty(){
-            match writeln!(std::io::stdout().lock(), "\n{}\n", self.scene_title){
-                Ok(_) => {}, //success
-                Err(_) => {}, //fail
+
+    // This function writes the scene back out as a config file named with the given stem and the
+    // part file extension, along with a part file for each character named after the stem and the
+    // character, all in the given directory. Characters keep their priorities and silent
+    // entrances. The characters of a file name that could not appear in one are replaced with _,
+    // and a character whose file name is already taken gets their position added to it. The
+    // config file's name is returned.
+    pub fn write_back(&self, dir: &Path, stem: &str) -> Result<String, u8> {
+        let extension = declarations::part_file_extension();
+        let mut config = Vec::new();
+        let mut part_files: HashSet<String> = HashSet::new();
+        for (position, c) in self.characters.iter().enumerate() {
+            let c_guard = match c.lock() {
+                Ok(c_guard) => c_guard,
+                Err(_) => {
+                    poison_mutex_print!();
+                    return Err(declarations::ERR_MUTEX);
+                }
+            };
+            let safe_name: String = c_guard.name().chars()
+                .map(|ch| if ch.is_alphanumeric() || ch == '-' || ch == '_' { ch } else { '_' })
+                .collect();
+            let mut part_file = format!("{}_{}{}", stem, safe_name, extension);
+            if part_files.contains(&part_file) {
+                part_file = format!("{}_{}_{}{}", stem, safe_name, position, extension);
             }
+            declarations::write_file_lines(&dir.join(&part_file), &c_guard.part_file_lines())?;
+
+            let marker = if c_guard.is_silent_entrance() { SILENT_MARKER.to_string() } else { String::new() };
+            let mut entry = format!("{}{} {}", marker, Self::config_token(c_guard.name()), part_file);
+            if c_guard.priority() != DEFAULT_PRIORITY {
+                entry.push_str(&format!(" {}", c_guard.priority()));
+            }
+            config.push(entry);
+            part_files.insert(part_file);
         }
-        for name in self.characters.iter().filter_map(|c| {
+        let config_file = format!("{}{}", stem, extension);
+        declarations::write_file_lines(&dir.join(&config_file), &config)?;
+        Ok(config_file)
+    }
+
+    // This function returns whether any character in the scene has a line to speak
+    pub fn has_lines(&self) -> bool {
+        self.characters.iter().any(|c| {
             match c.lock() {
-                Ok(ref c_guard) => Some(c_guard.name.clone()),
+                Ok(ref c_guard) => c_guard.line_count() > NO_LINES,
                 Err(_) => {
                     poison_mutex_print!();
-                    None
+                    false
                 }
             }
-        }) {
-            match writeln!(std::io::stdout().lock(), "[Enter {}.]", name) {
-                Ok(_) => {}, //success
-                Err(_) => {}, //fail
+        })
+    }
+
+    // This function returns the names of the characters in the scene in their sorted order, which
+    // is the order they are announced in. Silent characters are included.
+    pub fn character_names(&self) -> Vec<String> {
+        self.characters.iter()
+            .filter_map(|c| {
+                match c.lock() {
+                    Ok(ref c_guard) => Some(c_guard.name().to_string()),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // This function speaks the whole part of the named character in this scene to the given
+    // writer, if the character is in the scene
+    pub fn speak_part(&self, name: &str, w: &mut dyn Write) {
+        for c in &self.characters {
+            match c.lock() {
+                Ok(ref mut c_guard) => {
+                    if c_guard.name() == name {
+                        c_guard.speak_all(w);
+                    }
+                }
+                Err(_) => {
+                    poison_mutex_print!();
+                }
             }
         }
     }
 
-    // This function announces the exit of characters in self but not in other. This is so
-    // that only the characters who are actually exiting are announced as such.
-    pub fn exit(&self, other: &Self) {
-        let other_names: HashSet<String> = other.characters.iter()
+    // This function returns the names of the characters in the scene who have a line with the
+    // given number, in the order they are announced in
+    pub fn characters_with_line(&self, number: usize) -> Vec<String> {
+        self.characters.iter()
             .filter_map(|c| {
                 match c.lock() {
-                    Ok(ref c_guard) => Some(c_guard.name.clone()),
+                    Ok(ref c_guard) => c_guard.has_line(number).then(|| c_guard.name().to_string()),
                     Err(_) => {
                         poison_mutex_print!();
                         None
                     }
                 }
             })
-            .collect();
-        match writeln!(std::io::stdout().lock()) {
-            Ok(_) => {}, //success
-            Err(_) => {}, //fail
-        }
-        for name in self.characters.iter().rev().filter_map(|c| {
+            .collect()
+    }
+
+    // This function returns the character, length in characters, and text of the longest line in
+    // the scene, or None if nobody in the scene speaks. If several lines are equally long, the one
+    // with the earliest line number is returned.
+    pub fn longest_line(&self) -> Option<(String, usize, String)> {
+        let mut longest: Option<(usize, usize, String, String)> = None; // (length, number, character, text)
+        for c in &self.characters {
             match c.lock() {
-                Ok(ref c_guard) => Some(c_guard.name.clone()),
+                Ok(ref c_guard) => {
+                    for (number, text, _) in c_guard.lines() {
+                        let len = text.chars().count();
+                        let is_longer = match &longest {
+                            Some((best_len, best_number, _, _)) => len > *best_len || (len == *best_len && number < best_number),
+                            None => true,
+                        };
+                        if is_longer {
+                            longest = Some((len, *number, c_guard.name().to_string(), text.clone()));
+                        }
+                    }
+                }
                 Err(_) => {
                     poison_mutex_print!();
-                    None
                 }
             }
-        }) {
-            if !other_names.contains(&name) {
-                match writeln!(std::io::stdout().lock(), "[Exit {}.]", name){
-                    Ok(_) => {}, //success
-                    Err(_) => {}, //fail
+        }
+        longest.map(|(len, _, name, text)| (name, len, text))
+    }
+
+    // This function returns each character in the scene along with how many lines they speak, in
+    // the order they are announced in
+    pub fn line_counts(&self) -> Vec<(String, usize)> {
+        self.characters.iter()
+            .filter_map(|c| {
+                match c.lock() {
+                    Ok(ref c_guard) => Some((c_guard.name().to_string(), c_guard.line_count())),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        None
+                    }
                 }
+            })
+            .collect()
+    }
+
+    // This function returns whether a character's lines and stage directions are shown when
+    // reciting only the given character, which is every character if no name is given
+    fn is_shown(only: Option<&str>, name: &str) -> bool {
+        match only {
+            Some(only_name) => only_name == name,
+            None => true,
+        }
+    }
+
+    // This function returns the names of the characters in the scene in the order they are
+    // announced in, along with whether each enters and exits silently
+    fn cast(&self) -> Vec<(String, bool)> {
+        self.characters.iter()
+            .filter_map(|c| {
+                match c.lock() {
+                    Ok(ref c_guard) => Some((c_guard.name().to_string(), c_guard.is_silent_entrance())),
+                    Err(_) => {
+                        poison_mutex_print!();
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // This function returns the stage directions such as "Enter Hamlet" for each of the given
+    // characters who is shown. Characters who enter and exit silently are not named, and instead
+    // a single collective stage direction comes after the others if any of them are shown.
+    fn directions(action: &str, cast: Vec<(String, bool)>, only: Option<&str>) -> Vec<String> {
+        let mut directions = Vec::new();
+        let mut any_silent = false;
+        for (name, silent_entrance) in cast {
+            if !Self::is_shown(only, &name) {
+                continue;
+            }
+            if silent_entrance {
+                any_silent = true;
+            } else {
+                directions.push(format!("{} {}", action, name));
             }
         }
-        match writeln!(std::io::stdout().lock()) {
-            Ok(_) => {}, //success
-            Err(_) => {}, //fail
+        if any_silent {
+            directions.push(format!("{} {}", action, declarations::silent_entrance_label()));
         }
+        directions
     }
 
-    // This function announces the exit of all characters in self
-    pub fn exit_all(&self) {
-        match writeln!(std::io::stdout().lock()) {
-            Ok(_) => {}, //success
-            Err(_) => {}, //fail
+    // This function returns whether the named character is in the given cast. If name case is
+    // being ignored, names that only differ in case are the same character.
+    fn in_cast(cast: &HashSet<String>, name: &str) -> bool {
+        use std::sync::atomic::Ordering;
+        if declarations::IGNORE_NAME_CASE_ON.load(Ordering::SeqCst) {
+            let folded = name.to_lowercase();
+            cast.iter().any(|member| member.to_lowercase() == folded)
+        } else {
+            cast.contains(name)
         }
-        for name in self.characters.iter().rev().filter_map(|c| {
-            match c.lock() {
-                Ok(ref c_guard) => Some(c_guard.name.clone()),
-                Err(_) => {
-                    poison_mutex_print!();
-                    None
+    }
+
+    // This function returns the entrances at the start of the scene, which are the characters not
+    // in the cast of the previous scene, or everyone if this is the first scene
+    pub fn entrances(&self, previous_cast: Option<&HashSet<String>>, only: Option<&str>) -> Vec<String> {
+        let entering = self.cast().into_iter()
+            .filter(|(name, _)| previous_cast.is_none_or(|cast| !Self::in_cast(cast, name)))
+            .collect();
+        Self::directions(ENTER, entering, only)
+    }
+
+    // This function returns the exits at the end of the scene, in the reverse of the order the
+    // characters entered, which are the characters not in the cast of the next scene, or everyone
+    // if this is the last scene
+    pub fn exits(&self, next_cast: Option<&HashSet<String>>, only: Option<&str>) -> Vec<String> {
+        let exiting = self.cast().into_iter().rev()
+            .filter(|(name, _)| next_cast.is_none_or(|cast| !Self::in_cast(cast, name)))
+            .collect();
+        Self::directions(EXIT, exiting, only)
+    }
+
+    // This function prints the given stage directions in brackets, such as [Enter Hamlet.]
+    fn announce(directions: Vec<String>, out: &mut dyn Write) {
+        for direction in directions {
+            if declarations::count_output_line() {
+                match writeln!(out, "[{}.]", direction) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
                 }
             }
-        }) {
-            match writeln!(std::io::stdout().lock(), "[Exit {}.]", name) {
+        }
+    }
+
+    // This function announces all characters in self but not in the cast of the previous scene for
+    // scene transitions. If only one character is being recited, only that character is announced.
+    pub fn enter(&self, previous_cast: &HashSet<String>, only: Option<&str>, out: &mut dyn Write) {
+        if !self.scene_title.trim().is_empty(){
+            match writeln!(out, "\n{}\n", self.display_title()){
                 Ok(_) => {}, //success
                 Err(_) => {}, //fail
             }
         }
-        match writeln!(std::io::stdout().lock()) {
+        Self::announce(self.entrances(Some(previous_cast), only), out);
+    }
+    // This function announces the entrance of all characters in self, or only the given character
+    pub fn enter_all(&self, only: Option<&str>, out: &mut dyn Write) {
+        if !self.scene_title.trim().is_empty(){
+            match writeln!(out, "\n{}\n", self.display_title()){
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+        Self::announce(self.entrances(None, only), out);
+    }
+
+    // This function announces the exit of characters in self but not in the cast of the next
+    // scene. This is so that only the characters who are actually exiting are announced as such.
+    // If only one character is being recited, only that character is announced. A scene left
+    // with no characters prints nothing, so its title runs straight into the next scene.
+    pub fn exit(&self, next_cast: &HashSet<String>, only: Option<&str>, out: &mut dyn Write) {
+        if self.characters.is_empty() {
+            return;
+        }
+        match writeln!(out) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        Self::announce(self.exits(Some(next_cast), only), out);
+        match writeln!(out) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+    }
+
+    // This function announces the exit of all characters in self, or only the given character
+    pub fn exit_all(&self, only: Option<&str>, out: &mut dyn Write) {
+        if self.characters.is_empty() {
+            return;
+        }
+        match writeln!(out) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        Self::announce(self.exits(None, only), out);
+        match writeln!(out) {
             Ok(_) => {}, //success
             Err(_) => {}, //fail
         }
@@ -335,7 +872,7 @@ impl SceneFragment {
     // This function returns the ordering between two players in a thread safe way by acquiring the
     // required mutexes and then calling the partial comparison implentation for the underlying
     // Player
-    pub fn compare_players(a: &Arc<Mutex<Player>>, b: &Arc<Mutex<Player>>) -> Ordering {
+    pub fn compare_ranks(a: &Arc<Mutex<Player>>, b: &Arc<Mutex<Player>>) -> Ordering {
         let a_lock = a.lock();
         let b_lock = b.lock();
         match (a_lock, b_lock) {
@@ -349,4 +886,631 @@ impl SceneFragment {
         }
     }
 
+    // This function orders two players by rank as in compare_ranks, breaking ties between players
+    // of equal rank by name so that the order does not depend on the order of the config file
+    pub fn compare_players(a: &Arc<Mutex<Player>>, b: &Arc<Mutex<Player>>) -> Ordering {
+        match Self::compare_ranks(a, b) {
+            Ordering::Equal => {
+                match (a.lock(), b.lock()) {
+                    (Ok(ref a_guard), Ok(ref b_guard)) => a_guard.name().cmp(b_guard.name()),
+                    _ => Ordering::Equal,
+                }
+            }
+            order => order,
+        }
+    }
+
+    // This function shuffles each run of equally ranked characters using the given generator, so
+    // that their announcement order varies with the seed but stays reproducible. Characters of
+    // different ranks keep their sorted positions.
+    fn shuffle_ties(&mut self, rng: &mut Xorshift) {
+        let mut start = FIRST_CHARACTER;
+        while start < self.characters.len() {
+            let mut end = start + 1;
+            while end < self.characters.len() &&
+            Self::compare_ranks(&self.characters[start], &self.characters[end]) == Ordering::Equal {
+                end += 1;
+            }
+            rng.shuffle(&mut self.characters[start..end]);
+            start = end;
+        }
+    }
+
+}
+
+
+// Scene fragments are displayed for debugging as their quoted title on its own line followed by
+// each character as a Player is displayed, in the order they are announced. A character whose
+// mutex was poisoned is shown as (unavailable):
+// Scene "Act I, Scene 1"
+// Ghost
+//   2 Nay, answer me.
+// Hamlet
+//   1 Who is there?
+impl fmt::Display for SceneFragment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Scene \"{}\"", self.scene_title)?;
+        for c in &self.characters {
+            match c.lock() {
+                Ok(ref c_guard) => write!(f, "{}", **c_guard)?,
+                Err(_) => writeln!(f, "{}", UNAVAILABLE)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering as AtomicOrdering;
+    use crate::lab3::play::Play;
+    use crate::lab3::style::StyleConfig;
+    use crate::lab3::test_support::{lock_globals, map_source, prepared_play, recite_to_string};
+
+    // This function recites a one scene play in which Hamlet and Horatio speak the given lines
+    fn recite_dialogue(hamlet: &str, horatio: &str) -> String {
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt\n"),
+            ("hamlet.txt", hamlet),
+            ("horatio.txt", horatio),
+        ];
+        recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default())
+    }
+
+    #[test]
+    fn line_spoken_by_two_characters_names_them_both() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let transcript = recite_dialogue("1 Who's there?\n", "1 Nay, answer me.\n");
+        assert!(transcript.contains("Who's there?") && transcript.contains("Nay, answer me."));
+        let warnings = declarations::take_warnings();
+        assert!(warnings.contains(&"Warning: line 1 spoken by Hamlet, Horatio".to_string()), "{:?}", warnings);
+    }
+
+    #[test]
+    fn two_part_files_for_one_character_are_merged_in_order() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet1.txt\nGhost ghost.txt\nHamlet hamlet2.txt\n"),
+            ("hamlet1.txt", "1 Who's there?\n"),
+            ("hamlet2.txt", "3 Speak, I am bound to hear.\n"),
+            ("ghost.txt", "2 Mark me.\n"),
+        ];
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert_eq!(transcript.matches("[Enter Hamlet.]").count(), 1);
+        assert!(transcript.contains(" Hamlet\nWho's there?\n\n Ghost\nMark me.\n\n Hamlet\nSpeak, I am bound to hear.\n"), "{}", transcript);
+        assert!(declarations::take_warnings().contains(&"Warning: merging multiple part files for character Hamlet".to_string()));
+    }
+
+    #[test]
+    fn blank_line_policies_between_speakers() {
+        use crate::lab3::style::BlankLinePolicy;
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt\n"),
+            ("hamlet.txt", "1 Stay!\n2 Speak, speak!\n"),
+            ("horatio.txt", "3 'Tis gone.\n"),
+        ];
+        let recite_with = |blank_lines| {
+            let style = StyleConfig { blank_lines, ..StyleConfig::default() };
+            recite_to_string(&mut prepared_play(&files, "script.txt"), &style)
+        };
+        assert!(recite_with(BlankLinePolicy::OnSpeakerChange).contains("\n\n Hamlet\nStay!\nSpeak, speak!\n\n Horatio\n'Tis gone.\n"));
+        assert!(recite_with(BlankLinePolicy::Always).contains("\n\n Hamlet\nStay!\n\nSpeak, speak!\n\n Horatio\n'Tis gone.\n"));
+        assert!(recite_with(BlankLinePolicy::Never).contains("]\n Hamlet\nStay!\nSpeak, speak!\n Horatio\n'Tis gone.\n"));
+        assert_eq!(recite_with(BlankLinePolicy::default()), recite_with(BlankLinePolicy::OnSpeakerChange));
+    }
+
+    #[test]
+    fn malformed_part_line_is_recorded_as_a_warning() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        recite_dialogue("Stay! Speak!\n0 Who's there?\n", "1 Friends to this ground.\n");
+        assert_eq!(declarations::take_warnings(), vec!["Warning: Stay! does not contain a valid usize value and there is no previous line to continue".to_string()]);
+        assert!(declarations::take_warnings().is_empty());
+    }
+
+    #[test]
+    fn speech_spanning_two_physical_lines_is_one_numbered_block() {
+        let _globals = lock_globals();
+        let transcript = recite_dialogue("0 Angels and ministers of grace\ndefend us!\n", "1 Look, my lord, it comes.\n");
+        assert!(transcript.contains(" Hamlet\nAngels and ministers of grace\ndefend us!\n\n Horatio\nLook, my lord, it comes.\n"), "{}", transcript);
+    }
+
+    // This function prepares a scene of eight attendants who rank equally, shuffling them with the
+    // given seed if there is one, and returns the order they are announced in
+    fn attendant_order(seed: Option<u64>) -> Vec<String> {
+        let names = ["Ann", "Bea", "Cal", "Dan", "Eve", "Fay", "Gus", "Hal"];
+        let config: String = names.iter().map(|name| format!("{} {}.txt\n", name, name)).collect();
+        let mut files = vec![("scene.txt".to_string(), config)];
+        files.extend(names.iter().map(|name| (format!("{}.txt", name), "0 Ay.\n".to_string())));
+        let files: Vec<(&str, &str)> = files.iter().map(|(name, contents)| (name.as_str(), contents.as_str())).collect();
+        if let Some(seed) = seed {
+            declarations::SHUFFLE_ON.store(true, AtomicOrdering::SeqCst);
+            declarations::SHUFFLE_SEED.store(seed, AtomicOrdering::SeqCst);
+        }
+        let mut fragment = SceneFragment::new("Court");
+        fragment.prepare("scene.txt", &mut HashSet::new(), map_source(&files)).expect("the scene should prepare");
+        fragment.characters.iter().map(|c| c.lock().unwrap().name().to_string()).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_order_and_different_seeds_can_differ() {
+        let _globals = lock_globals();
+        assert_eq!(attendant_order(None), vec!["Ann", "Bea", "Cal", "Dan", "Eve", "Fay", "Gus", "Hal"]);
+        assert_eq!(attendant_order(Some(7)), attendant_order(Some(7)));
+        assert!((1..10).any(|seed| attendant_order(Some(seed)) != attendant_order(Some(7))));
+    }
+
+    #[test]
+    fn only_the_chosen_character_is_recited() {
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt\n"),
+            ("hamlet.txt", "1 Stay!\n3 I charge thee, speak!\n"),
+            ("horatio.txt", "2 'Tis gone.\n"),
+        ];
+        let only_hamlet = StyleConfig { only: Some("Hamlet".to_string()), ..StyleConfig::default() };
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &only_hamlet);
+        assert_eq!(transcript, concat!(
+            "\n",
+            "Act I\n\n",
+            "[Enter Hamlet.]\n\n",
+            " Hamlet\nStay!\nI charge thee, speak!\n\n",
+            "[Exit Hamlet.]\n\n",
+        ));
+        let everyone = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(everyone.contains(" Hamlet\nStay!\n\n Horatio\n'Tis gone.\n\n Hamlet\nI charge thee, speak!\n"));
+    }
+
+    #[test]
+    fn part_file_shared_by_two_characters_is_complained_about() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Marcellus watch.txt\nBernardo watch.txt\n"),
+            ("watch.txt", "1 Who's there?\n"),
+        ];
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Enter Bernardo.]") && transcript.contains("[Enter Marcellus.]"));
+        assert!(declarations::take_warnings().contains(&"Warning: part file watch.txt used by multiple characters in this scene".to_string()));
+    }
+
+    #[test]
+    fn aside_line_is_marked_and_other_lines_are_not() {
+        let _globals = lock_globals();
+        let transcript = recite_dialogue("1* A little more than kin, and less than kind.\n3 Not so, my lord.\n", "2 How is it that the clouds still hang on you?\n");
+        assert!(transcript.contains(concat!(
+            " Hamlet\n(aside) A little more than kin, and less than kind.\n\n",
+            " Horatio\nHow is it that the clouds still hang on you?\n\n",
+            " Hamlet\nNot so, my lord.\n",
+        )), "{}", transcript);
+    }
+
+    #[test]
+    fn priority_token_reorders_entrances() {
+        let _globals = lock_globals();
+        let mut files = vec![
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt\n"),
+            ("hamlet.txt", "1 Stay!\n"),
+            ("horatio.txt", "2 'Tis gone.\n"),
+        ];
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Enter Hamlet.]\n[Enter Horatio.]\n"));
+
+        files[1] = ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt 1\n");
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Enter Horatio.]\n[Enter Hamlet.]\n"), "{}", transcript);
+        assert!(transcript.contains(" Hamlet\nStay!\n\n Horatio\n'Tis gone.\n"));
+    }
+
+    // A script whose only scene has characters whose part files are missing
+    const LOST_CAST: &[(&str, &str)] = &[
+        ("script.txt", "[scene] Lost\nlost.txt\n"),
+        ("lost.txt", "Nobody missing.txt\n"),
+    ];
+
+    #[test]
+    fn config_with_only_wrong_token_counts_is_an_error() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let files = [("script.txt", "[scene] Bad\nbad.txt\n"), ("bad.txt", "Hamlet\nGhost\n")];
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+        let warnings = declarations::take_warnings();
+        assert_eq!(warnings.iter().filter(|w| w.contains("not exactly two distinct tokens")).count(), 2);
+    }
+
+    #[test]
+    fn scene_left_with_no_characters_is_complained_about_in_whinge_mode() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        declarations::SKIP_MISSING_ON.store(true, AtomicOrdering::SeqCst);
+        let mut play = Play::with_source(map_source(LOST_CAST));
+        assert_eq!(play.prepare("script.txt"), Ok(()));
+        assert!(declarations::take_warnings().contains(&"Warning: scene \"Lost\" has no characters".to_string()));
+    }
+
+    #[test]
+    fn scene_left_with_no_characters_is_an_error_in_strict_mode() {
+        let _globals = lock_globals();
+        declarations::STRICT_ON.store(true, AtomicOrdering::SeqCst);
+        declarations::SKIP_MISSING_ON.store(true, AtomicOrdering::SeqCst);
+        let mut play = Play::with_source(map_source(LOST_CAST));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+    }
+
+    #[test]
+    fn config_needs_one_entry_however_many_lines_it_has() {
+        let _globals = lock_globals();
+        let mut files = vec![("script.txt", "[scene] Act I\nscene.txt\n"), ("scene.txt", "\n\n"), ("hamlet.txt", "1 Stay!\n")];
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+
+        files[1] = ("scene.txt", "[comment] the prince alone\nHamlet hamlet.txt\n");
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Enter Hamlet.]\n") && !transcript.contains("[comment]"), "{}", transcript);
+    }
+
+    #[test]
+    fn out_of_order_part_file_is_an_error_only_when_sorted_lines_are_required() {
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt\n"),
+            ("hamlet.txt", "3 I charge thee, speak!\n1 Stay!\n"),
+            ("horatio.txt", "2 'Tis gone.\n"),
+        ];
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains(" Hamlet\nStay!\n\n Horatio\n'Tis gone.\n\n Hamlet\nI charge thee, speak!\n"));
+
+        declarations::REQUIRE_SORTED_ON.store(true, AtomicOrdering::SeqCst);
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare("script.txt"), Err(declarations::ERR_SCRIPT_GEN));
+    }
+
+    #[test]
+    fn player_and_scene_display_their_lines() {
+        let _globals = lock_globals();
+        let source = map_source(&[
+            ("scene.txt", "Hamlet hamlet.txt\nGhost ghost.txt\n"),
+            ("hamlet.txt", "1 Who is there?\n4* I will watch.\nand wait.\n"),
+            ("ghost.txt", "2 Nay, answer me.\n"),
+        ]);
+        let mut hamlet = Player::new("Hamlet");
+        assert_eq!(hamlet.prepare("hamlet.txt", source.as_ref()), Ok(()));
+        assert_eq!(hamlet.to_string(), "Hamlet\n  1 Who is there?\n  4* I will watch.\n  and wait.\n");
+
+        let mut fragment = SceneFragment::new("Elsinore");
+        assert_eq!(fragment.prepare("scene.txt", &mut HashSet::new(), source), Ok(()));
+        assert_eq!(fragment.to_string(), concat!(
+            "Scene \"Elsinore\"\n",
+            "Hamlet\n",
+            "  1 Who is there?\n",
+            "  4* I will watch.\n",
+            "  and wait.\n",
+            "Ghost\n",
+            "  2 Nay, answer me.\n",
+        ));
+
+        let ghost = Arc::clone(&fragment.characters[1]);
+        let _ = std::thread::spawn(move || {
+            let _guard = ghost.lock();
+            panic!("poisoning the ghost");
+        }).join();
+        assert!(fragment.to_string().ends_with("  and wait.\n(unavailable)\n"));
+    }
+
+    #[test]
+    fn huge_line_number_gap_is_skipped_quickly() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let started = std::time::Instant::now();
+        let transcript = recite_dialogue("0 Stay!\n18446744073709551615 Speak!\n", "1 'Tis gone.\n");
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        assert!(transcript.contains(" Horatio\n'Tis gone.\n\n Hamlet\nSpeak!\n"));
+        assert!(declarations::take_warnings().contains(&format!("Warning: large line-number gap (> {}) near line 2, skipping", MAX_REPORTED_GAP)));
+    }
+
+    #[test]
+    fn number_alone_is_an_empty_line_and_a_word_alone_is_malformed() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let transcript = recite_dialogue("0 Stay!\n2\n", "1 'Tis gone.\n");
+        assert!(transcript.contains(" Hamlet\nStay!\n\n Horatio\n'Tis gone.\n\n Hamlet\n\n"), "{}", transcript);
+        assert_eq!(declarations::take_warnings(), vec!["Warning: part file hamlet.txt has line 2 with no text"]);
+
+        recite_dialogue("abc\n0 Stay!\n", "1 'Tis gone.\n");
+        assert_eq!(declarations::take_warnings(), vec!["Warning: abc does not contain a valid usize value and there is no previous line to continue"]);
+    }
+
+    #[test]
+    fn names_are_colored_only_when_asked_to() {
+        use crate::lab3::style::{name_color, COLOR_RESET};
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet hamlet.txt\nHoratio horatio.txt\n"),
+            ("hamlet.txt", "1 Stay!\n3 Speak!\n"),
+            ("horatio.txt", "2 'Tis gone.\n"),
+        ];
+        assert!(!recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default()).contains('\x1b'));
+
+        let colored = StyleConfig { color: true, ..StyleConfig::default() };
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &colored);
+        let hamlet = format!(" {}Hamlet{}\n", name_color("Hamlet"), COLOR_RESET);
+        let horatio = format!(" {}Horatio{}\n", name_color("Horatio"), COLOR_RESET);
+        assert_eq!(transcript.matches(&hamlet).count(), 2, "{:?}", transcript);
+        assert_eq!(transcript.matches(&horatio).count(), 1);
+        assert_eq!(transcript.matches('\x1b').count(), 6);
+    }
+
+    #[test]
+    fn silent_characters_enter_and_exit_together_under_one_note() {
+        let _globals = lock_globals();
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Claudius claudius.txt\n-Lord lord.txt\n-Page page.txt\n"),
+            ("claudius.txt", "0 Welcome, dear Rosencrantz.\n"),
+            ("lord.txt", ""),
+            ("page.txt", ""),
+        ];
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert_eq!(transcript, concat!(
+            "\n",
+            "Act I\n\n",
+            "[Enter Claudius.]\n[Enter attendants.]\n\n",
+            " Claudius\nWelcome, dear Rosencrantz.\n\n",
+            "[Exit Claudius.]\n[Exit attendants.]\n\n",
+        ));
+
+        match declarations::SILENT_ENTRANCE_LABEL.lock() {
+            Ok(ref mut label) => label.push_str("lords"),
+            Err(_) => panic!("the label should not be poisoned"),
+        }
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Enter lords.]") && !transcript.contains("Lord.]"));
+    }
+
+    #[test]
+    fn missing_part_file_error_names_the_scene_and_character() {
+        let _globals = lock_globals();
+        let source = map_source(&[("scene.txt", "Hamlet hamlet.txt\nGhost ghost.txt\n"), ("hamlet.txt", "1 Who's there?\n")]);
+        let mut fragment = SceneFragment::new("Act I");
+        assert_eq!(fragment.prepare("scene.txt", &mut HashSet::new(), source), Err(declarations::ERR_SCRIPT_GEN));
+        assert_eq!(fragment.part_file_error("Ghost", "ghost.txt"), "Error: scene \"Act I\" character \"Ghost\": cannot open part file ghost.txt");
+    }
+
+    #[test]
+    fn one_based_part_files_have_no_missing_line_zero_under_base_one() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        recite_dialogue("1 Stay!\n", "2 'Tis gone.\n");
+        assert_eq!(declarations::take_warnings(), vec!["Warning: missing line 0"]);
+
+        declarations::LINE_BASE.store(1, AtomicOrdering::SeqCst);
+        recite_dialogue("1 Stay!\n", "2 'Tis gone.\n");
+        assert!(declarations::take_warnings().is_empty());
+    }
+
+    #[test]
+    fn repeated_line_number_is_complained_about_and_both_lines_kept() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let transcript = recite_dialogue("0 Stay!\n1 Speak!\n1 Speak, speak!\n", "2 'Tis gone.\n");
+        assert!(transcript.contains(" Hamlet\nStay!\nSpeak!\nSpeak, speak!\n"), "{}", transcript);
+        assert!(declarations::take_warnings().contains(&"Warning: character Hamlet has duplicate line number 1".to_string()));
+    }
+
+    #[test]
+    fn indented_verse_keeps_its_indentation_when_spoken() {
+        let _globals = lock_globals();
+        let hamlet = "0 To be, or not to be, that is the question:\n1     Whether 'tis nobler in the mind to suffer\n";
+        let transcript = recite_dialogue(hamlet, "2 My lord?\n");
+        assert!(transcript.contains("\nWhether 'tis nobler in the mind to suffer\n"));
+
+        declarations::PRESERVE_INDENT_ON.store(true, AtomicOrdering::SeqCst);
+        let transcript = recite_dialogue(hamlet, "2 My lord?\n");
+        assert!(transcript.contains(concat!(
+            " Hamlet\n",
+            "To be, or not to be, that is the question:\n",
+            "    Whether 'tis nobler in the mind to suffer\n\n",
+            " Horatio\nMy lord?\n",
+        )), "{:?}", transcript);
+    }
+
+    #[test]
+    fn inline_comments_are_removed_only_under_the_flag() {
+        let _globals = lock_globals();
+        let hamlet = "0 Stay! // he reaches out\n2 Speak!//\n";
+        let transcript = recite_dialogue(hamlet, "1 'Tis gone.\n");
+        assert!(transcript.contains("\nStay! // he reaches out\n") && transcript.contains("\nSpeak!//\n"));
+
+        declarations::STRIP_INLINE_COMMENTS_ON.store(true, AtomicOrdering::SeqCst);
+        let transcript = recite_dialogue(hamlet, "1 'Tis gone.\n");
+        assert!(transcript.contains(" Hamlet\nStay!\n\n Horatio\n'Tis gone.\n\n Hamlet\nSpeak!\n"), "{}", transcript);
+
+        match declarations::COMMENT_MARKER.lock() {
+            Ok(ref mut marker) => marker.push('#'),
+            Err(_) => panic!("the marker should not be poisoned"),
+        }
+        let transcript = recite_dialogue("0 Stay! # he reaches out\n2 Speak! // not a comment\n", "1 'Tis gone.\n");
+        assert!(transcript.contains("\nStay!\n") && transcript.contains("\nSpeak! // not a comment\n"), "{}", transcript);
+    }
+
+    #[test]
+    fn silent_character_announced_before_the_first_speaker_is_pointed_out() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let mut files = vec![
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Horatio horatio.txt\nHamlet hamlet.txt\n"),
+            ("hamlet.txt", "0 Who's there?\n"),
+            ("horatio.txt", "1 Friends to this ground.\n"),
+            ("guard.txt", ""),
+        ];
+        prepared_play(&files, "script.txt");
+        assert!(!declarations::take_warnings().iter().any(|w| w.contains("speaks first")));
+
+        files[1] = ("scene.txt", "Horatio horatio.txt\nHamlet hamlet.txt\nGuard guard.txt\n");
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains("[Enter Guard.]\n[Enter Hamlet.]\n[Enter Horatio.]\n"), "{}", transcript);
+        assert!(declarations::take_warnings().contains(&"Warning: scene \"Act I\" announces Guard first but Hamlet speaks first".to_string()));
+    }
+
+    #[test]
+    fn name_alone_infers_its_part_file_only_under_the_flag() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let mut config = PlayConfig::new();
+        SceneFragment::add_config("Hamlet", &mut config);
+        assert!(config.is_empty());
+        assert_eq!(declarations::take_warnings().len(), 1);
+
+        declarations::INFER_PART_FILES_ON.store(true, AtomicOrdering::SeqCst);
+        SceneFragment::add_config("Hamlet", &mut config);
+        SceneFragment::add_config("Ghost spirit.txt", &mut config);
+        *declarations::PART_FILE_EXTENSION.lock().unwrap() = ".part".to_string();
+        SceneFragment::add_config("Horatio", &mut config);
+        assert_eq!(config, vec![
+            ("Hamlet".to_string(), "Hamlet.txt".to_string(), DEFAULT_PRIORITY, false),
+            ("Ghost".to_string(), "spirit.txt".to_string(), DEFAULT_PRIORITY, false),
+            ("Horatio".to_string(), "Horatio.part".to_string(), DEFAULT_PRIORITY, false),
+        ]);
+        assert!(declarations::take_warnings().is_empty());
+    }
+
+    #[test]
+    fn speaking_past_the_last_line_says_nothing_and_is_complained_about() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let source = map_source(&[("ghost.txt", "0 Remember me.\n")]);
+        let mut ghost = Player::new("Ghost");
+        assert_eq!(ghost.prepare("ghost.txt", source.as_ref()), Ok(()));
+        let mut recent_player = String::new();
+        let mut out = Vec::new();
+        assert!(ghost.speak(&mut recent_player, &StyleConfig::default(), &mut |line| line.to_string(), &mut out));
+        assert!(declarations::take_warnings().is_empty());
+        let spoken = out.len();
+        assert!(!ghost.speak(&mut recent_player, &StyleConfig::default(), &mut |line| line.to_string(), &mut out));
+        assert_eq!(out.len(), spoken);
+        assert_eq!(declarations::take_warnings(), vec!["Warning: character Ghost was asked to speak but has no lines left".to_string()]);
+    }
+
+    #[test]
+    fn player_names_are_read_through_the_accessor_after_sorting() {
+        let _globals = lock_globals();
+        let source = map_source(&[("hamlet.txt", "1 Who's there?\n"), ("ghost.txt", "0 Mark me.\n")]);
+        let mut players = [Player::new("Hamlet"), Player::new("Ghost")];
+        assert_eq!(players[0].prepare("hamlet.txt", source.as_ref()), Ok(()));
+        assert_eq!(players[1].prepare("ghost.txt", source.as_ref()), Ok(()));
+        players.sort();
+        assert_eq!(players.iter().map(Player::name).collect::<Vec<_>>(), ["Ghost", "Hamlet"]);
+        let transcript = recite_dialogue("1 Who's there?\n", "0 Nay, answer me.\n");
+        assert!(transcript.contains("[Enter Horatio.]\n[Enter Hamlet.]\n\n Horatio\nNay, answer me.\n\n Hamlet\nWho's there?\n"), "{}", transcript);
+    }
+
+    #[test]
+    fn hexadecimal_line_numbers_are_recited_in_numeric_order() {
+        let _globals = lock_globals();
+        declarations::NUMBER_BASE.store(16, AtomicOrdering::SeqCst);
+        let transcript = recite_dialogue("B I charge thee, speak!\n1 Stay!\n", "0 'Tis here!\nA 'Tis gone.\n");
+        let spoken: Vec<&str> = transcript.lines().filter(|line| line.contains('!') || line.contains("gone")).collect();
+        assert_eq!(spoken, ["'Tis here!", "Stay!", "'Tis gone.", "I charge thee, speak!"]);
+    }
+
+    #[test]
+    fn transposed_looking_config_line_is_complained_about() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let mut config = PlayConfig::new();
+        SceneFragment::add_config("hamlet.txt Hamlet", &mut config);
+        assert_eq!(declarations::take_warnings(), vec!["Warning: the config line hamlet.txt Hamlet names a file before a character; are they reversed?".to_string()]);
+        SceneFragment::add_config("Hamlet hamlet.txt", &mut config);
+        assert!(declarations::take_warnings().is_empty());
+        assert_eq!(config.iter().map(|(name, file, _, _)| (name.as_str(), file.as_str())).collect::<Vec<_>>(),
+            [("hamlet.txt", "Hamlet"), ("Hamlet", "hamlet.txt")]);
+    }
+
+    #[test]
+    fn numbered_line_with_no_text_depends_on_the_mode() {
+        let _globals = lock_globals();
+        let source = map_source(&[("hamlet.txt", "41 Stay!\n42 \n")]);
+        let mut hamlet = Player::new("Hamlet");
+        assert_eq!(hamlet.prepare("hamlet.txt", source.as_ref()), Ok(()));
+        assert_eq!(hamlet.to_string(), "Hamlet\n  41 Stay!\n  42 \n");
+        assert!(declarations::take_warnings().is_empty());
+
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let mut hamlet = Player::new("Hamlet");
+        assert_eq!(hamlet.prepare("hamlet.txt", source.as_ref()), Ok(()));
+        assert_eq!(declarations::take_warnings(), vec!["Warning: part file hamlet.txt has line 42 with no text"]);
+
+        declarations::STRICT_ON.store(true, AtomicOrdering::SeqCst);
+        let mut hamlet = Player::new("Hamlet");
+        assert_eq!(hamlet.prepare("hamlet.txt", source.as_ref()), Err(declarations::ERR_SCRIPT_GEN));
+    }
+
+    #[test]
+    fn consecutive_lines_of_one_speaker_share_one_name() {
+        let _globals = lock_globals();
+        let transcript = recite_dialogue("10 Stay!\n11 Speak, speak!\n12 I charge thee, speak!\n", "13 'Tis gone.\n");
+        assert_eq!(transcript.matches("Hamlet\n").count(), 1);
+        assert!(transcript.contains(" Hamlet\nStay!\nSpeak, speak!\nI charge thee, speak!\n\n Horatio\n'Tis gone.\n"));
+    }
+
+    #[test]
+    fn speaker_name_is_printed_again_after_another_speaker() {
+        let _globals = lock_globals();
+        let transcript = recite_dialogue("10 Stay!\n12 I charge thee, speak!\n", "11 'Tis gone.\n");
+        assert_eq!(transcript.matches("Hamlet\n").count(), 2);
+        assert!(transcript.contains(" Hamlet\nStay!\n\n Horatio\n'Tis gone.\n\n Hamlet\nI charge thee, speak!\n"));
+    }
+
+    #[test]
+    fn quoted_part_file_may_contain_spaces() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let mut config = PlayConfig::new();
+        SceneFragment::add_config("Hamlet \"my part.txt\"", &mut config);
+        SceneFragment::add_config("\"First Player\" player.txt", &mut config);
+        assert!(declarations::take_warnings().is_empty());
+        SceneFragment::add_config("Ghost \"ghost part.txt", &mut config);
+        assert_eq!(declarations::take_warnings(), vec!["Warning: unterminated quote in the config line Ghost \"ghost part.txt".to_string()]);
+        assert_eq!(config.iter().map(|(name, file, _, _)| (name.as_str(), file.as_str())).collect::<Vec<_>>(),
+            [("Hamlet", "my part.txt"), ("First Player", "player.txt"), ("Ghost", "ghost part.txt")]);
+
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Hamlet \"my part.txt\"\n"),
+            ("my part.txt", "0 Who's there?\n"),
+        ];
+        let transcript = recite_to_string(&mut prepared_play(&files, "script.txt"), &StyleConfig::default());
+        assert!(transcript.contains(" Hamlet\nWho's there?\n"), "{}", transcript);
+    }
+
+    #[test]
+    fn characters_sharing_a_first_line_are_pointed_out_when_prepared() {
+        let _globals = lock_globals();
+        declarations::WHINGE_ON.store(true, AtomicOrdering::SeqCst);
+        let files = [
+            ("script.txt", "[scene] Act I\nscene.txt\n"),
+            ("scene.txt", "Horatio horatio.txt\nHamlet hamlet.txt\nGhost ghost.txt\n"),
+            ("hamlet.txt", "0 Who's there?\n"),
+            ("horatio.txt", "0 Friends to this ground.\n"),
+            ("ghost.txt", "1 Remember me.\n"),
+        ];
+        let mut play = Play::with_source(map_source(&files));
+        assert_eq!(play.prepare("script.txt"), Ok(()));
+        assert_eq!(declarations::take_warnings(), vec!["Warning: characters Hamlet, Horatio share first line 0 in scene \"Act I\"".to_string()]);
+    }
 }
@@ -0,0 +1,153 @@
+/*
+ * Author: Daniel Palmer
+ * Email: d.m.palmer@wustl.edu
+ * File: style.rs
+ * Summary: This file contains the StyleConfig struct, which holds the options that
+ * control how a play is laid out when it is recited and which parts of it are shown.
+ * It is passed from the Play down to each Player when it speaks. It also holds the
+ * palette used to give each character their own color in a terminal.
+ *
+ */
+
+
+// Where blank lines are printed between the lines of the play
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BlankLinePolicy {
+    #[default]
+    OnSpeakerChange, // before a character's name whenever the speaker changes
+    Always, // before every line, including consecutive lines from the same speaker
+    Never, // never
+}
+
+impl BlankLinePolicy {
+    // This function converts the name given on the command line into a policy, returning None if
+    // the name is not recognized
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "speaker-change" => Some(Self::OnSpeakerChange),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct StyleConfig {
+    pub blank_lines: BlankLinePolicy,
+    pub only: Option<String>, // the single character to recite, or None for everyone
+    pub wrap_width: Option<usize>, // the column to wrap spoken lines at, or None to not wrap
+    pub truncate_width: Option<usize>, // how many characters of a spoken line to print, or None for all
+    pub separators: bool, // whether a rule line is printed between scenes
+    pub color: bool, // whether character names are printed in their color
+    pub page_scenes: Option<usize>, // how many scenes to recite before pausing, or None to not pause
+    pub markers: bool, // whether machine-readable lines mark where each scene starts and ends
+    pub skip_empty_scenes: bool, // whether scenes where nobody has a line are left out
+}
+
+const MIN_WRAP_ROOM: usize = 1; // the fewest characters of text put on a wrapped line
+
+// ANSI escape codes for the colors characters' names are printed in, and for going back to normal
+const PALETTE: [&str; 6] = [
+    "\x1b[31m", // red
+    "\x1b[32m", // green
+    "\x1b[33m", // yellow
+    "\x1b[34m", // blue
+    "\x1b[35m", // magenta
+    "\x1b[36m", // cyan
+];
+pub const COLOR_RESET: &str = "\x1b[0m";
+const NAME_HASH_MULTIPLIER: u64 = 31;
+const ELLIPSIS: char = '…'; // marks where a truncated line was cut off
+
+// This function returns the escape code for the color a character's name is printed in. The color
+// only depends on the name, so a character is the same color in every scene and on every run.
+pub fn name_color(name: &str) -> &'static str {
+    let hash = name.bytes().fold(0u64, |hash, b| hash.wrapping_mul(NAME_HASH_MULTIPLIER).wrapping_add(b as u64));
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+
+// This function shortens text to its first width characters followed by an ellipsis if it is
+// longer than width characters, and otherwise returns it unchanged. Characters are counted rather
+// than bytes, so a multibyte character is never split.
+pub fn truncate_text(text: &str, width: usize) -> String {
+    match text.char_indices().nth(width) {
+        Some((cut, _)) => format!("{}{}", &text[..cut], ELLIPSIS),
+        None => text.to_string(),
+    }
+}
+
+
+// This function word wraps text so that no output line is longer than width characters. Lines
+// already in the text are kept, words are only split when a single word is longer than width,
+// and runs of spaces between words are collapsed to one. A line's leading indentation is kept at
+// the start of its first wrapped line and repeated on the lines it continues onto, so indented
+// verse stays indented. If the indentation leaves no room, one character is still fit per line.
+pub fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut wrapped = Vec::new();
+    for line in text.lines() {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let room = width.saturating_sub(indent.chars().count()).max(MIN_WRAP_ROOM);
+        let mut current = String::new();
+        let mut current_len = 0;
+        for word in line.split_whitespace() {
+            let mut word_chars: Vec<char> = word.chars().collect();
+            if current_len > 0 && current_len + 1 + word_chars.len() > room {
+                wrapped.push(format!("{}{}", indent, std::mem::take(&mut current)));
+                current_len = 0;
+            }
+            while word_chars.len() > room {
+                let rest = word_chars.split_off(room);
+                wrapped.push(format!("{}{}", indent, word_chars.into_iter().collect::<String>()));
+                word_chars = rest;
+            }
+            if current_len > 0 {
+                current.push(' ');
+                current_len += 1;
+            }
+            current_len += word_chars.len();
+            current.extend(word_chars);
+        }
+        wrapped.push(format!("{}{}", indent, current));
+    }
+    wrapped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_line_wraps_at_the_width() {
+        let wrapped = wrap_text("To be, or not to be, that is the question", 16);
+        assert_eq!(wrapped, vec!["To be, or not to", "be, that is the", "question"]);
+        assert!(wrapped.iter().all(|l| l.chars().count() <= 16));
+    }
+
+    #[test]
+    fn word_longer_than_the_width_is_split() {
+        assert_eq!(wrap_text("Honorificabilitudinitatibus", 10), vec!["Honorifica", "bilitudini", "tatibus"]);
+    }
+
+    #[test]
+    fn indentation_is_kept_on_the_first_and_continuation_lines() {
+        let wrapped = wrap_text("    Whether 'tis nobler in the mind to suffer", 20);
+        assert_eq!(wrapped, vec!["    Whether 'tis", "    nobler in the", "    mind to suffer"]);
+        assert!(wrapped.iter().all(|l| l.chars().count() <= 20));
+    }
+
+    #[test]
+    fn indentation_wider_than_the_width_still_makes_progress() {
+        assert_eq!(wrap_text("    ab", 2), vec!["    a", "    b"]);
+    }
+
+    #[test]
+    fn multibyte_text_is_truncated_between_characters() {
+        assert_eq!(truncate_text("Adieu, adieu, adieu! Erinnere dich, süßer Prinz", 39), "Adieu, adieu, adieu! Erinnere dich, süß\u{2026}");
+        assert_eq!(truncate_text("Süße", 2), "Sü\u{2026}");
+        assert_eq!(truncate_text("Süße", 4), "Süße");
+    }
+}
@@ -0,0 +1,76 @@
+/*
+ * Author: Daniel Palmer
+ * Email: d.m.palmer@wustl.edu
+ * File: test_support.rs
+ * Summary: This file contains helpers shared by the unit tests of the library and of
+ * the binary, which reaches them through the test-support feature. The options live in
+ * global statics, so every test that prepares or recites a play holds the globals lock
+ * for its whole run, which also resets the options to their defaults. Plays are built
+ * from in-memory files so that the tests do not depend on the disk.
+ *
+ */
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::declarations;
+use super::line_source::{MapSource, SharedSource};
+use super::play::Play;
+use super::style::StyleConfig;
+
+static GLOBALS: Mutex<()> = Mutex::new(());
+
+
+// This function waits until no other test is using the global options, resets them to their
+// defaults, and returns a guard that keeps other tests out until it is dropped. A test that
+// panicked while holding the lock does not stop the others from running.
+pub fn lock_globals() -> MutexGuard<'static, ()> {
+    let guard = GLOBALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    declarations::reset_options();
+    guard
+}
+
+// This function returns a source serving the given (name, contents) pairs as files
+pub fn map_source(files: &[(&str, &str)]) -> SharedSource {
+    let files: HashMap<String, String> = files.iter()
+        .map(|(name, contents)| (name.to_string(), contents.to_string()))
+        .collect();
+    Arc::new(MapSource(files))
+}
+
+// This function prepares the named script from the given in-memory files, panicking if it could
+// not be prepared
+pub fn prepared_play(files: &[(&str, &str)], script: &str) -> Play {
+    let mut play = Play::with_source(map_source(files));
+    play.prepare(script).expect("the play should prepare");
+    play
+}
+
+// This function recites the play in the given style and returns what was written
+pub fn recite_to_string(play: &mut Play, style: &StyleConfig) -> String {
+    let mut out = Vec::new();
+    play.recite(style, &mut out);
+    String::from_utf8(out).expect("the recitation should be UTF-8")
+}
+
+// This function returns an empty directory for a test to write files into, named after the test
+// and the process so that tests running at the same time do not share one
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lab3client-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("the test directory should be created");
+    dir
+}
+
+// The two scene play most of the tests recite. Hamlet and the Ghost share the first scene, and
+// Horatio joins Hamlet in the second.
+pub const TWO_SCENE_PLAY: &[(&str, &str)] = &[
+    ("script.txt", "[scene] Act I\nscene1.txt\n[scene] Act II\nscene2.txt\n"),
+    ("scene1.txt", "Hamlet hamlet1.txt\nGhost ghost.txt\n"),
+    ("scene2.txt", "Hamlet hamlet2.txt\nHoratio horatio.txt\n"),
+    ("hamlet1.txt", "1 Who's there?\n3 Speak, I am bound to hear.\n"),
+    ("ghost.txt", "2 Mark me.\n4 I am thy father's spirit.\n"),
+    ("hamlet2.txt", "1 Horatio, or I do forget myself.\n"),
+    ("horatio.txt", "2 The same, my lord.\n"),
+];
@@ -0,0 +1,10 @@
+/*
+ * Author: Daniel Palmer
+ * Email: d.m.palmer@wustl.edu
+ * File: lib.rs
+ * Summary: This file exposes the lab3 module as a library, so that the client binary
+ * and the server can both prepare and recite plays with the same code.
+ *
+ */
+
+pub mod lab3;
@@ -8,38 +8,412 @@
  */
 
 
-pub mod lab3;
-
 use std::env;
 use std::io::Write;
+use std::sync::Arc;
+use lab3client::lab3;
+
 use lab3::declarations;
+use lab3::line_source::{FsSource, SharedSource, ZipSource};
 use lab3::play::Play;
 use lab3::return_wrapper::ReturnWrapper;
+use lab3::style::{BlankLinePolicy, StyleConfig};
+
+const WATCH_POLL_MS: u64 = 500; // how often watch mode checks the files for changes
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H"; // clears the terminal and moves the cursor to the top
 
 
 // This function is called whenver the program is ran with improper command line arguments and it
 // prints a message telling the user how to run the program
 fn usage(name: &String) {
-    match writeln!(std::io::stdout().lock(), "Usage: ./{name} <script_file_name> [whinge]") {
+    match writeln!(std::io::stdout().lock(), "Usage: ./{name} <script_file_name> [whinge] [options]\n\
+        Options:\n\
+        \x20 --whinge, -w    complain about problems in the script, the same as whinge\n\
+        \x20 --no-whinge     do not complain, even if whinge mode was turned on another way\n\
+        \x20 --strict        treat problems that would only be warnings as errors\n\
+        \x20 --defer-warnings\n\
+        \x20                 hold warnings back until the play has been recited\n\
+        \x20 --warnings-file <path>\n\
+        \x20                 write warnings to the file at path instead of stderr\n\
+        \x20 --timings       print how long each scene took to prepare and recite\n\
+        \x20 --skip-missing  leave out characters whose part files cannot be read\n\
+        \x20 --toc           print a table of contents before the play\n\
+        \x20 --continuous-numbering\n\
+        \x20                 number lines continuously across scenes rather than per scene\n\
+        \x20 --parallel-recite\n\
+        \x20                 recite the scenes at the same time and print them in order\n\
+        \x20 --require-sorted\n\
+        \x20                 fail if a part file's line numbers are not in ascending order\n\
+        \x20 --trace-files   print the name of each file as it is read\n\
+        \x20 --progress      print how many lines of a large part file have been read so far\n\
+        \x20 --watch         recite again whenever the script or its part files change, until Ctrl-C\n\
+        \x20 --ignore-name-case\n\
+        \x20                 keep characters on stage between scenes that spell their name in\n\
+        \x20                 different case\n\
+        \x20 --preserve-indent\n\
+        \x20                 keep the leading indentation of lines in part files\n\
+        \x20 --lossy         replace invalid UTF-8 in files instead of failing\n\
+        \x20 --output <path> recite into the file at path instead of stdout\n\
+        \x20 --server <address> --get <script_file_name>\n\
+        \x20                 have the server at address recite a script and print it, in place\n\
+        \x20                 of a script file\n\
+        \x20 --bundle <zip>  read the script and every file it names from an uncompressed zip\n\
+        \x20 --transpose <script_file_name>\n\
+        \x20                 alternate the scenes of the play with those of another script\n\
+        \x20 --diff <script_file_name>\n\
+        \x20                 print the lines that differ from another edition instead of reciting\n\
+        \x20 --side-by-side <script_file_name>\n\
+        \x20                 recite the play and a parallel script, such as a translation, in\n\
+        \x20                 two columns matched by scene and line number\n\
+        \x20 --width <n>     the width of each column when reciting side by side (38 by default)\n\
+        \x20 --canonical     recite the scenes sorted by title\n\
+        \x20 --number-scenes number scenes that share a title when reciting\n\
+        \x20 --blocking      print the characters in each scene before the play\n\
+        \x20 --count         print how many lines each character speaks before the play\n\
+        \x20 --silent-label <name>\n\
+        \x20                 what characters whose names start with - are called when they\n\
+        \x20                 enter and exit (attendants by default)\n\
+        \x20 --number-base <b>\n\
+        \x20                 read part file line numbers in base b, from 2 to 36 (10 by default)\n\
+        \x20 --infer-part-files\n\
+        \x20                 accept config lines with only a name, reading the part file named\n\
+        \x20                 after the character with the part file extension\n\
+        \x20 --strip-inline-comments\n\
+        \x20                 drop the rest of a part file line after a comment marker\n\
+        \x20 --comment-marker <marker>\n\
+        \x20                 the marker that starts an inline comment (// by default)\n\
+        \x20 --part-extension <ext>\n\
+        \x20                 the extension of file names, used to spot scene titles that end\n\
+        \x20                 in one (.txt by default)\n\
+        \x20 --seed <n>      shuffle characters who share a first line using seed n\n\
+        \x20 --fetch-retries <n>\n\
+        \x20                 retry connecting to a tcp:// file's server up to n times\n\
+        \x20 --find <n>      print the scene and character of line n instead of reciting\n\
+        \x20 --longest       print the longest line in each scene instead of reciting\n\
+        \x20 --markdown      print the play as Markdown instead of reciting\n\
+        \x20 --stats-json    print scene, character, and line counts as JSON instead of reciting\n\
+        \x20 --speakers      print the order characters speak in each scene instead of reciting\n\
+        \x20 --echo-config   print each config entry as it was parsed before preparing it\n\
+        \x20 --base <n>      the line number part files start from (0 by default)\n\
+        \x20 --repeat <n>    recite the whole play n times in a row\n\
+        \x20 --repeat-separator <text>\n\
+        \x20                 print text on a line of its own between repetitions of the play\n\
+        \x20 --max-scenes <n>\n\
+        \x20                 fail if the script has more than n scenes (10000 by default)\n\
+        \x20 --prepare-timeout <ms>\n\
+        \x20                 fail if a scene takes longer than ms milliseconds to prepare\n\
+        \x20 --only <name>   recite only the named character's lines\n\
+        \x20 --separators    print a rule line between scenes\n\
+        \x20 --markers       print @@SCENE n title@@ and @@ENDSCENE n@@ around each scene\n\
+        \x20 --skip-empty-scenes\n\
+        \x20                 leave out scenes where nobody has a line\n\
+        \x20 --page <n>      pause for enter after every n scenes when run in a terminal\n\
+        \x20 --color         print each character's name in their own color in a terminal\n\
+        \x20 --wrap <n>      word wrap spoken lines at n columns\n\
+        \x20 --truncate <n>  print only the first n characters of each spoken line, then …\n\
+        \x20 --until-line <n>\n\
+        \x20                 stop reciting once line n has been spoken, numbering lines across\n\
+        \x20                 the whole play, and have everyone on stage exit\n\
+        \x20 --max-output-lines <n>\n\
+        \x20                 stop reciting after n spoken lines and stage directions\n\
+        \x20 --blank-lines <speaker-change|always|never>\n\
+        \x20                 when to print a blank line before a line of the play") {
         Ok(_) => {}, //success
         Err(_) => {}, //fail
     }
 }
 
-// This function is used to parse the command line arguments. It takes one parameter, a mutable
-// reference to a string in which it places the name of the file provided as the first command line
-// argument. It also sets the whinge mode flag if "whinge" was provided as the second command line
-// argument. If the program was ran improperly it calls the usage function and returns an error.
-fn parse_args(name: &mut String) -> Result<(), u8> {
+// The files, addresses, and text given on the command line besides the script, each None if it
+// was not given
+#[derive(Default)]
+struct RunOptions {
+    transpose_file: Option<String>, // a script whose scenes alternate with the play's
+    bundle_file: Option<String>, // a zip to read every file from
+    diff_file: Option<String>, // another edition to compare the play with
+    side_by_side_file: Option<String>, // a parallel script to recite alongside the play
+    output_file: Option<String>, // a file to recite into instead of stdout
+    server: Option<String>, // the address of a server to have recite a script
+    get_script: Option<String>, // the script the server should recite
+    repeat_separator: Option<String>, // a line printed between repetitions of the play
+}
+
+// This function is used to parse the given command line arguments, starting with the program
+// name, looking up environment variables with the given function. It takes a mutable reference
+// to a string in which it places the name of the file provided as the first command line
+// argument, a mutable reference to the run options in which it places the other files and
+// addresses given, and a mutable reference to the style used when reciting. It also sets the
+// whinge mode flag if "whinge" was provided as the second command line argument or --whinge or
+// -w was given. Defaults are first taken from the environment, where LAB3_WHINGE=1 turns on
+// whinge mode and LAB3_OUTPUT names the output file, and the command line takes precedence over
+// them. --no-whinge is applied last, so it turns whinge mode off however else it was turned on.
+// Options beginning with -- may appear anywhere and set their associated flags in declarations
+// or fields of the style. If the program was ran improperly it calls the usage function and
+// returns an error. When a server and a script to get from it are given, no script file is
+// expected, and they must be given together.
+fn parse_args(
+    cmd_args: impl IntoIterator<Item = String>,
+    env_var: &dyn Fn(&str) -> Option<String>,
+    name: &mut String,
+    options: &mut RunOptions,
+    style: &mut StyleConfig,
+) -> Result<(), u8> {
+    use std::sync::atomic::Ordering;
+    if env_var(declarations::WHINGE_ENV).is_some_and(|value| value == declarations::ENV_ON) {
+        declarations::WHINGE_ON.store(true, Ordering::SeqCst);
+    }
+    if let Some(path) = env_var(declarations::OUTPUT_ENV) {
+        options.output_file = Some(path);
+    }
+
     let mut args = Vec::<String>::new();
-    for arg in env::args() {
-        args.push(arg);
+    let mut bad_option = false;
+    let mut no_whinge = false;
+    let mut env_args = cmd_args.into_iter();
+    while let Some(arg) = env_args.next() {
+        match arg.as_str() {
+            "--whinge" | "-w" => declarations::WHINGE_ON.store(true, Ordering::SeqCst),
+            "--no-whinge" => no_whinge = true,
+            "--strict" => declarations::STRICT_ON.store(true, Ordering::SeqCst),
+            "--defer-warnings" => declarations::DEFER_WARNINGS_ON.store(true, Ordering::SeqCst),
+            "--warnings-file" => {
+                match env_args.next().map(std::fs::File::create) {
+                    Some(Ok(f)) => declarations::set_warning_sink(Box::new(f)),
+                    Some(Err(_)) => {
+                        match writeln!(std::io::stderr().lock(), "Error: the warnings file could not be created") {
+                            Ok(_) => {}, //success
+                            Err(_) => {}, //fail
+                        }
+                        bad_option = true;
+                    }
+                    None => bad_option = true,
+                }
+            }
+            "--timings" => declarations::TIMINGS_ON.store(true, Ordering::SeqCst),
+            "--skip-missing" => declarations::SKIP_MISSING_ON.store(true, Ordering::SeqCst),
+            "--toc" => declarations::TOC_ON.store(true, Ordering::SeqCst),
+            "--continuous-numbering" => declarations::CONTINUOUS_NUMBERING_ON.store(true, Ordering::SeqCst),
+            "--parallel-recite" => declarations::PARALLEL_RECITE_ON.store(true, Ordering::SeqCst),
+            "--require-sorted" => declarations::REQUIRE_SORTED_ON.store(true, Ordering::SeqCst),
+            "--trace-files" => declarations::TRACE_FILES_ON.store(true, Ordering::SeqCst),
+            "--progress" => declarations::PROGRESS_ON.store(true, Ordering::SeqCst),
+            "--watch" => declarations::WATCH_ON.store(true, Ordering::SeqCst),
+            "--ignore-name-case" => declarations::IGNORE_NAME_CASE_ON.store(true, Ordering::SeqCst),
+            "--preserve-indent" => declarations::PRESERVE_INDENT_ON.store(true, Ordering::SeqCst),
+            "--lossy" => declarations::LOSSY_ON.store(true, Ordering::SeqCst),
+            "--server" => {
+                match env_args.next() {
+                    Some(address) => options.server = Some(address),
+                    None => bad_option = true,
+                }
+            }
+            "--get" => {
+                match env_args.next() {
+                    Some(script) => options.get_script = Some(script),
+                    None => bad_option = true,
+                }
+            }
+            "--diff" => {
+                match env_args.next() {
+                    Some(file) => options.diff_file = Some(file),
+                    None => bad_option = true,
+                }
+            }
+            "--side-by-side" => {
+                match env_args.next() {
+                    Some(file) => options.side_by_side_file = Some(file),
+                    None => bad_option = true,
+                }
+            }
+            "--width" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(width)) if width > 0 => declarations::COLUMN_WIDTH.store(width, Ordering::SeqCst),
+                    _ => bad_option = true,
+                }
+            }
+            "--output" => {
+                match env_args.next() {
+                    Some(file) => options.output_file = Some(file),
+                    None => bad_option = true,
+                }
+            }
+            "--bundle" => {
+                match env_args.next() {
+                    Some(file) => options.bundle_file = Some(file),
+                    None => bad_option = true,
+                }
+            }
+            "--transpose" => {
+                match env_args.next() {
+                    Some(file) => options.transpose_file = Some(file),
+                    None => bad_option = true,
+                }
+            }
+            "--canonical" => declarations::CANONICAL_ON.store(true, Ordering::SeqCst),
+            "--number-scenes" => declarations::NUMBER_SCENES_ON.store(true, Ordering::SeqCst),
+            "--blocking" => declarations::BLOCKING_ON.store(true, Ordering::SeqCst),
+            "--count" => declarations::COUNT_ON.store(true, Ordering::SeqCst),
+            "--silent-label" => {
+                match (env_args.next(), declarations::SILENT_ENTRANCE_LABEL.lock()) {
+                    (Some(label), Ok(ref mut current)) => **current = label,
+                    _ => bad_option = true,
+                }
+            }
+            "--infer-part-files" => declarations::INFER_PART_FILES_ON.store(true, Ordering::SeqCst),
+            "--strip-inline-comments" => declarations::STRIP_INLINE_COMMENTS_ON.store(true, Ordering::SeqCst),
+            "--comment-marker" => {
+                match (env_args.next(), declarations::COMMENT_MARKER.lock()) {
+                    (Some(marker), Ok(ref mut current)) => **current = marker,
+                    _ => bad_option = true,
+                }
+            }
+            "--part-extension" => {
+                match (env_args.next(), declarations::PART_FILE_EXTENSION.lock()) {
+                    (Some(extension), Ok(ref mut current)) => **current = extension,
+                    _ => bad_option = true,
+                }
+            }
+            "--seed" => {
+                match env_args.next().map(|s| s.parse::<u64>()) {
+                    Some(Ok(seed)) => {
+                        declarations::SHUFFLE_SEED.store(seed, Ordering::SeqCst);
+                        declarations::SHUFFLE_ON.store(true, Ordering::SeqCst);
+                    }
+                    _ => bad_option = true,
+                }
+            }
+            "--fetch-retries" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(retries)) => declarations::FETCH_RETRIES.store(retries, Ordering::SeqCst),
+                    _ => bad_option = true,
+                }
+            }
+            "--number-base" => {
+                match env_args.next().map(|s| s.parse::<u32>()) {
+                    Some(Ok(base)) if (declarations::MIN_NUMBER_BASE..=declarations::MAX_NUMBER_BASE).contains(&base) => {
+                        declarations::NUMBER_BASE.store(base, Ordering::SeqCst);
+                    }
+                    _ => bad_option = true,
+                }
+            }
+            "--until-line" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(number)) => {
+                        declarations::UNTIL_LINE.store(number, Ordering::SeqCst);
+                        declarations::UNTIL_ON.store(true, Ordering::SeqCst);
+                    }
+                    _ => bad_option = true,
+                }
+            }
+            "--find" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(number)) => {
+                        declarations::FIND_LINE.store(number, Ordering::SeqCst);
+                        declarations::FIND_ON.store(true, Ordering::SeqCst);
+                    }
+                    _ => bad_option = true,
+                }
+            }
+            "--longest" => declarations::LONGEST_ON.store(true, Ordering::SeqCst),
+            "--max-scenes" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(max)) if max != declarations::NO_LIMIT => declarations::MAX_SCENES.store(max, Ordering::SeqCst),
+                    _ => bad_option = true,
+                }
+            }
+            "--repeat" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(times)) if times >= declarations::SINGLE_RECITAL => {
+                        declarations::REPEAT.store(times, Ordering::SeqCst);
+                    }
+                    _ => bad_option = true,
+                }
+            }
+            "--repeat-separator" => {
+                match env_args.next() {
+                    Some(separator) => options.repeat_separator = Some(separator),
+                    None => bad_option = true,
+                }
+            }
+            "--base" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(base)) => declarations::LINE_BASE.store(base, Ordering::SeqCst),
+                    _ => bad_option = true,
+                }
+            }
+            "--echo-config" => declarations::ECHO_CONFIG_ON.store(true, Ordering::SeqCst),
+            "--markdown" => declarations::MARKDOWN_ON.store(true, Ordering::SeqCst),
+            "--stats-json" => declarations::STATS_JSON_ON.store(true, Ordering::SeqCst),
+            "--speakers" => declarations::SPEAKERS_ON.store(true, Ordering::SeqCst),
+            "--prepare-timeout" => {
+                match env_args.next().map(|s| s.parse::<u64>()) {
+                    Some(Ok(ms)) if ms != declarations::NO_TIMEOUT => {
+                        declarations::PREPARE_TIMEOUT_MS.store(ms, Ordering::SeqCst);
+                    }
+                    _ => bad_option = true,
+                }
+            }
+            "--only" => {
+                match env_args.next() {
+                    Some(character) => style.only = Some(character),
+                    None => bad_option = true,
+                }
+            }
+            "--separators" => style.separators = true,
+            "--markers" => style.markers = true,
+            "--skip-empty-scenes" => style.skip_empty_scenes = true,
+            "--color" => style.color = true,
+            "--page" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(scenes)) if scenes > 0 => style.page_scenes = Some(scenes),
+                    _ => bad_option = true,
+                }
+            }
+            "--wrap" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(width)) if width > 0 => style.wrap_width = Some(width),
+                    _ => bad_option = true,
+                }
+            }
+            "--truncate" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(width)) if width > 0 => style.truncate_width = Some(width),
+                    _ => bad_option = true,
+                }
+            }
+            "--max-output-lines" => {
+                match env_args.next().map(|s| s.parse::<usize>()) {
+                    Some(Ok(max)) if max != declarations::NO_LIMIT => {
+                        declarations::MAX_OUTPUT_LINES.store(max, Ordering::SeqCst);
+                    }
+                    _ => bad_option = true,
+                }
+            }
+            "--blank-lines" => {
+                match env_args.next().as_deref().and_then(BlankLinePolicy::from_name) {
+                    Some(policy) => style.blank_lines = policy,
+                    None => bad_option = true,
+                }
+            }
+            _ if arg.starts_with(declarations::OPTION_PREFIX) => bad_option = true,
+            _ => args.push(arg),
+        }
     }
     
+    if options.server.is_some() || options.get_script.is_some() {
+        if bad_option || options.server.is_none() || options.get_script.is_none() || args.len() != declarations::REMOTE_ARGS {
+            usage(&args[declarations::PROG_NAME]);
+            return Err(declarations::ERR_CMD_LINE);
+        }
+        if no_whinge {
+            declarations::WHINGE_ON.store(false, Ordering::SeqCst);
+        }
+        return Ok(());
+    }
+
     //Check if valid input
-    if args.len() < declarations::MIN_ARGS  || 
+    if bad_option || args.len() < declarations::MIN_ARGS  || 
     args.len() > declarations::MAX_ARGS || 
-    (args.len() == declarations::MAX_ARGS && args[declarations::WHINGE_MODE] != "whinge".to_string()){
+    (args.len() == declarations::MAX_ARGS && args[declarations::WHINGE_MODE] != "whinge"){
 
         usage(&args[declarations::PROG_NAME]);
         return Err(declarations::ERR_CMD_LINE);
@@ -48,28 +422,364 @@ fn parse_args(name: &mut String) -> Result<(), u8> {
     *name = args[declarations::CONFIG_FILE].clone(); 
     
     if args.len() == declarations::MAX_ARGS {
-        use std::sync::atomic::Ordering;
         declarations::WHINGE_ON.store(true, Ordering::SeqCst); 
     }
+    if no_whinge {
+        declarations::WHINGE_ON.store(false, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+
+// This function prepares a play from the named script and, in echo config mode, writes the config
+// entries that were parsed to the given writer, even if the play could not be prepared
+fn prepare_echoed(script_file: &str, source: &SharedSource, out: &mut dyn Write) -> Result<Play, u8> {
+    let mut play = Play::with_source(source.clone());
+    let prepared = play.prepare(script_file);
+    use std::sync::atomic::Ordering;
+    if declarations::ECHO_CONFIG_ON.load(Ordering::SeqCst) && write!(out, "{}", declarations::take_config_echo()).is_err() {
+        return Err(declarations::ERR_OUTPUT);
+    }
+    prepared.map(|_| play)
+}
+
+
+// This function prepares the play from the named script, along with any other scripts the run
+// options name, and then prints whatever the command line asked for, which is the recited play
+// unless another mode was chosen. Everything it prints goes to the given writer. It returns an
+// error if a script could not be prepared or the output failed.
+fn run(script_file: &str, options: &RunOptions, style: &StyleConfig, source: &SharedSource, out: &mut dyn Write) -> Result<(), u8> {
+    let mut play = prepare_echoed(script_file, source, out)?;
+    if let Some(other_file) = &options.transpose_file {
+        play = play.interleave(prepare_echoed(other_file, source, out)?);
+    }
+    let mut diff_play: Option<Play> = None;
+    if let Some(other_file) = &options.diff_file {
+        diff_play = Some(prepare_echoed(other_file, source, out)?);
+    }
+    let mut parallel_play: Option<Play> = None;
+    if let Some(other_file) = &options.side_by_side_file {
+        parallel_play = Some(prepare_echoed(other_file, source, out)?);
+    }
+
+    use std::sync::atomic::Ordering;
+    if declarations::CANONICAL_ON.load(Ordering::SeqCst) {
+        play.sort_canonical();
+    }
+    if declarations::NUMBER_SCENES_ON.load(Ordering::SeqCst) {
+        play.number_duplicate_scenes();
+    }
+    if declarations::TOC_ON.load(Ordering::SeqCst) {
+        play.print_toc(out);
+    }
+    if declarations::BLOCKING_ON.load(Ordering::SeqCst) {
+        play.print_blocking(out);
+    }
+    if declarations::COUNT_ON.load(Ordering::SeqCst) {
+        play.print_line_counts(out);
+    }
+
+    if let Some(other) = &diff_play {
+        play.print_diff(other, out);
+    } else if let Some(other) = &parallel_play {
+        play.side_by_side(other, declarations::COLUMN_WIDTH.load(Ordering::SeqCst), out);
+    } else if declarations::FIND_ON.load(Ordering::SeqCst) {
+        play.print_find_line(declarations::FIND_LINE.load(Ordering::SeqCst), out);
+    } else if declarations::LONGEST_ON.load(Ordering::SeqCst) {
+        play.print_longest_lines(out);
+    } else if declarations::SPEAKERS_ON.load(Ordering::SeqCst) {
+        play.print_speaking_order(out);
+    } else if declarations::STATS_JSON_ON.load(Ordering::SeqCst) {
+        play.to_stats_json(out)?;
+    } else if declarations::MARKDOWN_ON.load(Ordering::SeqCst) {
+        play.to_markdown(out)?;
+    } else {
+        for repetition in 0..declarations::REPEAT.load(Ordering::SeqCst) {
+            if repetition > 0 {
+                if declarations::output_truncated() {
+                    break;
+                }
+                if let Some(separator) = &options.repeat_separator {
+                    if writeln!(out, "{}", separator).is_err() {
+                        return Err(declarations::ERR_OUTPUT);
+                    }
+                }
+            }
+            play.fresh_copy().recite(style, out);
+        }
+    }
     Ok(())
 }
 
+// This function returns the last modified time of each of the named files, with None for any
+// file whose time cannot be read, such as one that has been removed
+fn modified_times(files: &[String]) -> Vec<Option<std::time::SystemTime>> {
+    files.iter()
+        .map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// This function returns the source the play's files are read from, which is the bundle if one
+// was given and otherwise the filesystem. It returns an error if the bundle could not be read.
+fn open_source(options: &RunOptions) -> Result<SharedSource, u8> {
+    match &options.bundle_file {
+        Some(path) => Ok(Arc::new(ZipSource::open(path)?)),
+        None => Ok(Arc::new(FsSource)),
+    }
+}
+
+// This function returns the files to watch for changes after a run, which are every file read
+// during the run along with the given file the play starts from, the script or the bundle it is
+// in, in case it could not be read. The files read are forgotten so that the next run collects
+// its own.
+fn watched_files(first_file: &str) -> Vec<String> {
+    let mut watched = declarations::take_files_read();
+    if !watched.iter().any(|f| f == first_file) {
+        watched.push(first_file.to_string());
+    }
+    watched
+}
+
+// This function blocks until any of the named files is modified, created, or removed, checking
+// them every WATCH_POLL_MS milliseconds
+fn wait_for_change(files: &[String]) {
+    let before = modified_times(files);
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(WATCH_POLL_MS));
+        if modified_times(files) != before {
+            return;
+        }
+    }
+}
 
 // The main function executes the program which includes retrieving command line arguments,
-// constructing the play, and printing the play.  
+// constructing the play, and printing the play. In watch mode it then waits for the files the
+// play was read from to change, reads a bundle again if the play came from one, and prints the
+// play again, clearing the terminal first if the output goes to one, until the program is
+// interrupted.
 fn main() -> ReturnWrapper {
     let mut script_file: String = Default::default();
+    let mut options: RunOptions = Default::default();
+    let mut style: StyleConfig = Default::default();
 
-    if let Err(e) = parse_args(&mut script_file){
+    if let Err(e) = parse_args(env::args(), &|key| env::var(key).ok(), &mut script_file, &mut options, &mut style){
         return ReturnWrapper::new(Err(e));
     }
+    // names are only colored when the play goes to a terminal, which an output file never is
+    use std::io::IsTerminal;
+    style.color = style.color && options.output_file.is_none() && std::io::stdout().is_terminal();
 
-    let mut play = Play::new();
-    if let Err(e) = play.prepare(&script_file){
-        return ReturnWrapper::new(Err(e));
+    let source = match open_source(&options) {
+        Ok(source) => source,
+        Err(e) => return ReturnWrapper::new(Err(e)),
+    };
+
+    let mut out: Box<dyn Write> = match &options.output_file {
+        Some(path) => match std::fs::File::create(path) {
+            Ok(f) => Box::new(std::io::BufWriter::new(f)),
+            Err(_) => {
+                match writeln!(std::io::stderr().lock(), "Error: the output file {} could not be created", path) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return ReturnWrapper::new(Err(declarations::ERR_OUTPUT));
+            }
+        },
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    if let (Some(address), Some(script)) = (&options.server, &options.get_script) {
+        let fetched = declarations::grab_remote_recitation(address, script, &mut out);
+        if out.flush().is_err() {
+            return ReturnWrapper::new(Err(declarations::ERR_OUTPUT));
+        }
+        return ReturnWrapper::new(fetched);
     }
 
-    play.recite();
-    
-    ReturnWrapper::new(Ok(()))
+    let mut result = run(&script_file, &options, &style, &source, &mut out);
+    if out.flush().is_err() {
+        declarations::flush_deferred_warnings();
+        return ReturnWrapper::new(Err(declarations::ERR_OUTPUT));
+    }
+    declarations::flush_deferred_warnings();
+
+    use std::sync::atomic::Ordering;
+    while declarations::WATCH_ON.load(Ordering::SeqCst) {
+        wait_for_change(&watched_files(options.bundle_file.as_ref().unwrap_or(&script_file)));
+        declarations::reset_run_state();
+        if options.output_file.is_none() && std::io::stdout().is_terminal() {
+            match write!(std::io::stdout().lock(), "{}", CLEAR_SCREEN) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+        }
+        result = match open_source(&options) {
+            Ok(source) => run(&script_file, &options, &style, &source, &mut out),
+            Err(e) => Err(e),
+        };
+        if out.flush().is_err() {
+            declarations::flush_deferred_warnings();
+            return ReturnWrapper::new(Err(declarations::ERR_OUTPUT));
+        }
+        declarations::flush_deferred_warnings();
+    }
+
+    ReturnWrapper::new(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use lab3::test_support::{lock_globals, map_source, temp_dir, TWO_SCENE_PLAY};
+
+    // This function runs the two scene play with the given options and returns what was written
+    fn run_to_string(options: &RunOptions) -> String {
+        let mut out = Vec::new();
+        assert_eq!(run("script.txt", options, &StyleConfig::default(), &map_source(TWO_SCENE_PLAY), &mut out), Ok(()));
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn two_repetitions_are_the_transcript_twice() {
+        let _globals = lock_globals();
+        let once = run_to_string(&RunOptions::default());
+        declarations::REPEAT.store(2, Ordering::SeqCst);
+        assert_eq!(run_to_string(&RunOptions::default()), once.repeat(2));
+    }
+
+    #[test]
+    fn repetitions_are_separated_by_the_separator() {
+        let _globals = lock_globals();
+        let once = run_to_string(&RunOptions::default());
+        declarations::REPEAT.store(3, Ordering::SeqCst);
+        let options = RunOptions { repeat_separator: Some("-----".to_string()), ..Default::default() };
+        assert_eq!(run_to_string(&options), format!("{once}-----\n{once}-----\n{once}"));
+    }
+
+    #[test]
+    fn repetitions_stop_once_the_output_is_truncated() {
+        let _globals = lock_globals();
+        declarations::REPEAT.store(3, Ordering::SeqCst);
+        declarations::MAX_OUTPUT_LINES.store(3, Ordering::SeqCst);
+        let options = RunOptions { repeat_separator: Some("-----".to_string()), ..Default::default() };
+        let transcript = run_to_string(&options);
+        assert!(!transcript.contains("-----"), "{}", transcript);
+        assert!(transcript.contains("Who's there?"));
+        assert!(!transcript.contains("Mark me."));
+    }
+
+    #[test]
+    fn summaries_before_the_play_go_to_the_output() {
+        let _globals = lock_globals();
+        declarations::TOC_ON.store(true, Ordering::SeqCst);
+        declarations::BLOCKING_ON.store(true, Ordering::SeqCst);
+        declarations::COUNT_ON.store(true, Ordering::SeqCst);
+        declarations::ECHO_CONFIG_ON.store(true, Ordering::SeqCst);
+        let transcript = run_to_string(&RunOptions::default());
+        assert!(transcript.starts_with("SCENE: Act I\nFILE: scene1.txt\nSCENE: Act II\nFILE: scene2.txt\nCONFIG: "), "{}", transcript);
+        assert!(transcript.contains("CONFIG: scene1.txt\n  CHARACTER: Hamlet PART: hamlet1.txt\n  CHARACTER: Ghost PART: ghost.txt\n"), "{}", transcript);
+        assert!(transcript.contains(concat!(
+            "1. Act I\n2. Act II\n",
+            "Scene \"Act I\": Hamlet, Ghost\nScene \"Act II\": Hamlet, Horatio\n",
+            "Hamlet: 3\nGhost: 2\nHoratio: 1\n",
+        )), "{}", transcript);
+        assert!(transcript.contains("Who's there?"));
+    }
+
+    #[test]
+    fn modes_instead_of_reciting_go_to_the_output() {
+        let _globals = lock_globals();
+        declarations::FIND_ON.store(true, Ordering::SeqCst);
+        declarations::FIND_LINE.store(2, Ordering::SeqCst);
+        assert_eq!(run_to_string(&RunOptions::default()), "Line 2: scene \"Act I\", Ghost\nLine 2: scene \"Act II\", Horatio\n");
+        declarations::FIND_ON.store(false, Ordering::SeqCst);
+        declarations::SPEAKERS_ON.store(true, Ordering::SeqCst);
+        assert_eq!(run_to_string(&RunOptions::default()), "Scene \"Act I\": Hamlet, Ghost, Hamlet, Ghost\nScene \"Act II\": Hamlet, Horatio\n");
+        declarations::SPEAKERS_ON.store(false, Ordering::SeqCst);
+        declarations::LONGEST_ON.store(true, Ordering::SeqCst);
+        assert_eq!(run_to_string(&RunOptions::default()), concat!(
+            "Scene \"Act I\": Hamlet (26 characters): Speak, I am bound to hear.\n",
+            "Scene \"Act II\": Hamlet (31 characters): Horatio, or I do forget myself.\n",
+        ));
+        declarations::LONGEST_ON.store(false, Ordering::SeqCst);
+        let options = RunOptions { diff_file: Some("script.txt".to_string()), ..Default::default() };
+        assert_eq!(run_to_string(&options), "No differences\n");
+    }
+
+    #[test]
+    fn every_file_read_is_watched_once() {
+        let _globals = lock_globals();
+        let dir = temp_dir("watched");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(path("script.txt"), format!("[scene] Act I\n{}\n[scene] Act II\n{}\n", path("scene.txt"), path("scene.txt"))).unwrap();
+        std::fs::write(path("scene.txt"), format!("Hamlet {}\nGhost {}\n", path("hamlet.txt"), path("ghost.txt"))).unwrap();
+        std::fs::write(path("hamlet.txt"), "1 Who's there?\n").unwrap();
+        std::fs::write(path("ghost.txt"), "2 Mark me.\n").unwrap();
+
+        let source: SharedSource = Arc::new(FsSource);
+        assert_eq!(run(&path("script.txt"), &RunOptions::default(), &StyleConfig::default(), &source, &mut Vec::new()), Ok(()));
+        let mut expected = vec![path("ghost.txt"), path("hamlet.txt"), path("scene.txt"), path("script.txt")];
+        expected.sort();
+        assert_eq!(watched_files(&path("script.txt")), expected);
+        assert_eq!(watched_files(&path("script.txt")), vec![path("script.txt")]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // This function parses the given command line, after the program name, with the given
+    // environment variables set, and returns the result along with the script name and options
+    fn parse(cmd_args: &[&str], env_vars: &[(&str, &str)]) -> (Result<(), u8>, String, RunOptions, StyleConfig) {
+        let mut name = String::new();
+        let mut options = RunOptions::default();
+        let mut style = StyleConfig::default();
+        let cmd_args = std::iter::once("lab3client").chain(cmd_args.iter().copied()).map(str::to_string);
+        let env_var = |key: &str| env_vars.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string());
+        let result = parse_args(cmd_args, &env_var, &mut name, &mut options, &mut style);
+        (result, name, options, style)
+    }
+
+    #[test]
+    fn every_spelling_of_whinge_turns_it_on() {
+        let _globals = lock_globals();
+        for cmd_args in [&["script.txt", "whinge"][..], &["--whinge", "script.txt"], &["script.txt", "-w"]] {
+            declarations::reset_options();
+            let (result, name, _, _) = parse(cmd_args, &[]);
+            assert_eq!((result, name.as_str()), (Ok(()), "script.txt"));
+            assert!(declarations::WHINGE_ON.load(Ordering::SeqCst), "{:?}", cmd_args);
+        }
+        declarations::reset_options();
+        assert_eq!(parse(&["script.txt"], &[]).0, Ok(()));
+        assert!(!declarations::WHINGE_ON.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn environment_defaults_give_way_to_the_command_line() {
+        let _globals = lock_globals();
+        let env_vars = [(declarations::WHINGE_ENV, declarations::ENV_ON), (declarations::OUTPUT_ENV, "recital.txt")];
+        let (result, _, options, _) = parse(&["script.txt"], &env_vars);
+        assert_eq!(result, Ok(()));
+        assert!(declarations::WHINGE_ON.load(Ordering::SeqCst));
+        assert_eq!(options.output_file.as_deref(), Some("recital.txt"));
+
+        declarations::reset_options();
+        let (result, _, options, _) = parse(&["script.txt", "--no-whinge", "--output", "other.txt"], &env_vars);
+        assert_eq!(result, Ok(()));
+        assert!(!declarations::WHINGE_ON.load(Ordering::SeqCst));
+        assert_eq!(options.output_file.as_deref(), Some("other.txt"));
+
+        declarations::reset_options();
+        assert_eq!(parse(&["script.txt"], &[(declarations::WHINGE_ENV, "0")]).0, Ok(()));
+        assert!(!declarations::WHINGE_ON.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn no_whinge_wins_wherever_it_is_given() {
+        let _globals = lock_globals();
+        for cmd_args in [&["script.txt", "whinge", "--no-whinge"][..], &["--no-whinge", "script.txt", "whinge"], &["--no-whinge", "-w", "script.txt"]] {
+            declarations::reset_options();
+            assert_eq!(parse(cmd_args, &[]).0, Ok(()));
+            assert!(!declarations::WHINGE_ON.load(Ordering::SeqCst), "{:?}", cmd_args);
+        }
+    }
 }
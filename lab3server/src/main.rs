@@ -1,3 +1,65 @@
-fn main() {
-    println!("Hello, world!");
+/*
+ * Author: Daniel Palmer
+ * Email: d.m.palmer@wustl.edu
+ * File: main.rs
+ * Summary: This file contains the main function for the server, which opens a
+ * Server on the network address given on the command line and runs it, serving
+ * scripts from the directory given after it or from the current directory, and
+ * handling at most the number of connections at once given after that.
+ *
+ */
+
+pub mod server;
+
+use std::env;
+use std::io::Write;
+use std::process::ExitCode;
+use server::Server;
+
+const MIN_ARGS: usize = 2;
+const MAX_ARGS: usize = 4;
+const PROG_NAME: usize = 0;
+const NETWORK_ADDR: usize = 1;
+const BASE_DIR: usize = 2;
+const MAX_CONNECTIONS: usize = 3;
+const DEFAULT_BASE_DIR: &str = ".";
+const ERR_CMD_LINE: u8 = 5;
+
+
+// This function prints a message telling the user how to run the program
+fn usage(name: &str) {
+    match writeln!(std::io::stdout().lock(), "Usage: ./{name} <network_address> [script_directory] [max_connections]") {
+        Ok(_) => {}, //success
+        Err(_) => {}, //fail
+    }
+}
+
+// The main function opens a server on the address from the command line, serving scripts from
+// the given directory and handling at most the given number of connections at once, and runs it
+// until it stops accepting connections
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < MIN_ARGS || args.len() > MAX_ARGS {
+        usage(&args[PROG_NAME]);
+        return ExitCode::from(ERR_CMD_LINE);
+    }
+
+    let base_dir = args.get(BASE_DIR).map_or(DEFAULT_BASE_DIR, |dir| dir.as_str());
+    let mut server = Server::new(base_dir);
+    if let Some(max) = args.get(MAX_CONNECTIONS) {
+        match max.parse::<usize>() {
+            Ok(max) if max > 0 => server.set_max_connections(max),
+            _ => {
+                usage(&args[PROG_NAME]);
+                return ExitCode::from(ERR_CMD_LINE);
+            }
+        }
+    }
+    if let Err(e) = server.open(&args[NETWORK_ADDR]) {
+        return ExitCode::from(e);
+    }
+    match server.run() {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => ExitCode::from(e),
+    }
 }
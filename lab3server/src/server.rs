@@ -0,0 +1,845 @@
+/*
+ * Author: Daniel Palmer
+ * Email: d.m.palmer@wustl.edu
+ * File: server.rs
+ * Summary: This file contains the Server struct and its implementation. A Server
+ * listens on a network address and, for each client that connects, checks that the
+ * client speaks the same protocol version and then reads the name
+ * of a script file in its base directory from the client and sends back the file's
+ * lines, or the names of the scripts it serves if the client sends LIST, or the play
+ * recited from a script if the client sends GET and the script's name. Parsed files
+ * and prepared plays are kept in a ScriptCache so that repeated requests for unchanged
+ * files do not go back to the disk. Only a limited number of clients are handled at
+ * once, and any others are told the server is busy.
+ *
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use lab3client::lab3::declarations::{self, escape_reply_line};
+use lab3client::lab3::line_source::{confined_path, DirSource, LineSource, PathError, SharedSource};
+use lab3client::lab3::play::Play;
+use lab3client::lab3::style::StyleConfig;
+
+pub const ERR_BIND: u8 = 1;
+pub const ERR_NOT_OPEN: u8 = 2;
+pub const ERR_FILE: u8 = 3;
+pub const ERR_LOCK: u8 = 4;
+
+const LIST_COMMAND: &str = "LIST";
+const GET_COMMAND: &str = "GET";
+const END_OF_REPLY: &str = "LAB3 END"; // sent after a file or a recited play
+const UNKNOWN_FILE: &str = "LAB3 ERR unknown file";
+const FORBIDDEN_PATH: &str = "LAB3 ERR forbidden path"; // the name leads outside the base directory
+const UNKNOWN_SCRIPT: &str = "LAB3 ERR unknown script";
+const PARSE_FAILURE: &str = "LAB3 ERR parse failure";
+const PROTOCOL_VERSION: &str = "LAB3 v1";
+const HANDSHAKE_ACCEPTED: &str = "LAB3 v1 OK";
+const HANDSHAKE_REJECTED: &str = "LAB3 ERR unsupported";
+const SCRIPT_EXTENSION: &str = "txt";
+const BUSY: &str = "BUSY"; // sent instead of handling a connection when too many are open
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30); // how long a read or write may wait
+
+type CachedScript = (SystemTime, Arc<Vec<String>>); // (modification time, trimmed lines)
+type FileTimes = Vec<(String, Option<SystemTime>)>; // (file name, modification time if it exists)
+type CachedPlay = (FileTimes, Arc<Play>); // (every file read while preparing, prepared play)
+
+// The warnings, files read, echoed config entries, and output lines counted while a play is
+// prepared and recited are kept for the whole process, so only one connection prepares and recites
+// a play at a time
+static RECITATION: Mutex<()> = Mutex::new(());
+
+
+// Reads files through another source, remembering the modification time of every file it is asked
+// for before reading it, so that a cached play can tell whether any of its files have changed
+struct RecordingSource {
+    inner: SharedSource,
+    base_dir: PathBuf,
+    read: Mutex<FileTimes>,
+}
+
+impl RecordingSource {
+    fn new(inner: SharedSource, base_dir: &Path) -> Self {
+        Self {
+            inner,
+            base_dir: base_dir.to_path_buf(),
+            read: Mutex::new(Vec::new()),
+        }
+    }
+
+    // This function records the named file and its modification time, if it has one
+    fn record(&self, name: &str) {
+        let modified = modified_time(&self.base_dir.join(name));
+        match self.read.lock() {
+            Ok(ref mut read) => read.push((name.to_string(), modified)),
+            Err(_) => {}, // the play is not cached, since its files are not all known
+        }
+    }
+
+    // This function returns the files read so far, or None if they could not all be recorded
+    fn files_read(&self) -> Option<FileTimes> {
+        match self.read.lock() {
+            Ok(ref read) => Some(read.to_vec()),
+            Err(_) => None,
+        }
+    }
+}
+
+impl LineSource for RecordingSource {
+    fn lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        self.record(name);
+        self.inner.lines(name)
+    }
+
+    fn indented_lines(&self, name: &str) -> Result<Vec<String>, u8> {
+        self.record(name);
+        self.inner.indented_lines(name)
+    }
+
+    fn lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        self.record(name);
+        self.inner.lines_with_progress(name, every, progress)
+    }
+
+    fn indented_lines_with_progress(&self, name: &str, every: usize, progress: &mut dyn FnMut(usize)) -> Result<Vec<String>, u8> {
+        self.record(name);
+        self.inner.indented_lines_with_progress(name, every, progress)
+    }
+}
+
+// This function returns the modification time of the given file, or None if it has none
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+
+pub struct ScriptCache {
+    scripts: RwLock<HashMap<String, CachedScript>>,
+    plays: RwLock<HashMap<PathBuf, CachedPlay>>,
+}
+
+impl Default for ScriptCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScriptCache {
+    pub fn new() -> Self {
+        Self {
+            scripts: RwLock::new(HashMap::new()),
+            plays: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // This function reads the trimmed lines of a file, returning an error if the file could not
+    // be opened or read
+    fn read_lines(file_name: &str) -> Result<Vec<String>, u8> {
+        let f = match File::open(file_name) {
+            Ok(f) => f,
+            Err(_) => return Err(ERR_FILE),
+        };
+        let mut lines = Vec::new();
+        for line in BufReader::new(f).lines() {
+            match line {
+                Ok(l) => lines.push(l.trim().to_string()),
+                Err(_) => return Err(ERR_FILE),
+            }
+        }
+        Ok(lines)
+    }
+
+    // This function returns the lines of the given file. If the file is in the cache and its
+    // modification time has not changed since it was read, the cached lines are returned without
+    // touching the file's contents. Otherwise the file is read and the cache is updated. The bool
+    // in the result is true on a cache hit. Anything other than a regular file, such as a
+    // directory, is an error.
+    pub fn get(&self, file_name: &str) -> Result<(Arc<Vec<String>>, bool), u8> {
+        let modified = match std::fs::metadata(file_name) {
+            Ok(m) if m.is_file() => match m.modified() {
+                Ok(t) => t,
+                Err(_) => return Err(ERR_FILE),
+            },
+            _ => return Err(ERR_FILE),
+        };
+        match self.scripts.read() {
+            Ok(ref scripts) => {
+                if let Some((cached_time, lines)) = scripts.get(file_name) {
+                    if *cached_time == modified {
+                        return Ok((lines.clone(), true));
+                    }
+                }
+            }
+            Err(_) => return Err(ERR_LOCK),
+        }
+        let lines = Arc::new(Self::read_lines(file_name)?);
+        match self.scripts.write() {
+            Ok(ref mut scripts) => {
+                scripts.insert(file_name.to_string(), (modified, lines.clone()));
+            }
+            Err(_) => return Err(ERR_LOCK),
+        }
+        Ok((lines, false))
+    }
+
+    // This function returns a copy of the play prepared from the named script in the given base
+    // directory, ready to be recited. If the play is in the cache and none of the files read while
+    // preparing it have been modified, created, or removed since, the cached play is copied
+    // without preparing it again. Otherwise the play is prepared and the cache is updated. The
+    // bool in the result is true on a cache hit.
+    pub fn get_play(&self, base_dir: &Path, script_name: &str) -> Result<(Play, bool), u8> {
+        self.get_play_from(Arc::new(DirSource(base_dir.to_path_buf())), base_dir, script_name)
+    }
+
+    // This function is get_play with the files read from the given source, which reads them
+    // relative to the base directory
+    fn get_play_from(&self, source: SharedSource, base_dir: &Path, script_name: &str) -> Result<(Play, bool), u8> {
+        let key = base_dir.join(script_name);
+        match self.plays.read() {
+            Ok(ref plays) => {
+                if let Some((files, play)) = plays.get(&key) {
+                    if files.iter().all(|(name, modified)| modified_time(&base_dir.join(name)) == *modified) {
+                        return Ok((play.fresh_copy(), true));
+                    }
+                }
+            }
+            Err(_) => return Err(ERR_LOCK),
+        }
+        let recorder = Arc::new(RecordingSource::new(source, base_dir));
+        let mut play = Play::with_source(recorder.clone());
+        play.prepare(script_name)?;
+        if let Some(files) = recorder.files_read() {
+            match self.plays.write() {
+                Ok(ref mut plays) => {
+                    plays.insert(key, (files, Arc::new(play.fresh_copy())));
+                }
+                Err(_) => return Err(ERR_LOCK),
+            }
+        }
+        Ok((play, false))
+    }
+}
+
+
+// Counts a connection as active for as long as it is alive, so that a handler thread gives up
+// its place when it finishes, even if it panics
+struct ActiveConnection(Arc<AtomicUsize>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+pub struct Server {
+    listener: Option<TcpListener>,
+    listening_addr: String,
+    base_dir: PathBuf, // requested files are looked up in this directory
+    cache: Arc<ScriptCache>,
+    max_connections: usize, // the most connections handled at once
+    active: Arc<AtomicUsize>, // how many connections are being handled
+    idle_timeout: Duration, // how long a connection may wait on the client before it is closed
+}
+
+impl Server {
+    pub fn new(base_dir: &str) -> Self {
+        Self {
+            listener: None,
+            listening_addr: String::new(),
+            base_dir: PathBuf::from(base_dir),
+            cache: Arc::new(ScriptCache::new()),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            active: Arc::new(AtomicUsize::new(0)),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    // This function sets how many connections the server handles at once. Connections beyond
+    // that are told the server is busy and closed.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    // This function sets how long a connection may wait to read from or write to its client. A
+    // client that sends or reads nothing for that long is disconnected, so that idle clients
+    // cannot hold every connection the server will handle.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.listener.is_some()
+    }
+
+    // This function binds the server to the given network address, returning an error if the
+    // address could not be bound
+    pub fn open(&mut self, addr: &str) -> Result<(), u8> {
+        match TcpListener::bind(addr) {
+            Ok(l) => {
+                self.listener = Some(l);
+                self.listening_addr = addr.to_string();
+                Ok(())
+            }
+            Err(_) => {
+                match writeln!(std::io::stderr().lock(), "Error: could not listen on {}", addr) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                Err(ERR_BIND)
+            }
+        }
+    }
+
+    // This function returns the names of the scripts the server is willing to serve, which are the
+    // files in its base directory with the script extension, sorted by name
+    pub fn list_scripts(&self) -> Vec<String> {
+        Self::scripts_in(&self.base_dir)
+    }
+
+    // This function returns the sorted names of the files in the given directory with the script
+    // extension. If the directory cannot be read no names are returned.
+    fn scripts_in(dir: &Path) -> Vec<String> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == SCRIPT_EXTENSION))
+            .filter_map(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    // This function prepares and recites the named script from the base directory, reading the
+    // files it names from the base directory as well, and sends the transcript to the client with
+    // each line escaped so that none is mistaken for a protocol line, followed by the end of
+    // transcript line. The play is taken from the cache so that an unchanged script is not prepared
+    // again. If the script is not a file in the base directory, leads outside it, or could not be
+    // prepared, an error line is sent instead. Plays are recited one at a time, and the warnings,
+    // files read, echoed config entries, and output lines counted for each are forgotten before
+    // the next, since they are shared by every connection.
+    fn send_recitation(writer: &mut TcpStream, cache: &ScriptCache, base_dir: &Path, script_name: &str) {
+        let reply = match confined_path(base_dir, script_name) {
+            Err(PathError::Outside) => FORBIDDEN_PATH,
+            Ok(path) if path.is_file() => {
+                match Self::recite_alone(cache, base_dir, script_name) {
+                    Ok((transcript, hit)) => {
+                        for line in String::from_utf8_lossy(&transcript).lines() {
+                            if writeln!(writer, "{}", escape_reply_line(line)).is_err() {
+                                return;
+                            }
+                        }
+                        match writeln!(std::io::stdout().lock(), "Recited {} ({})", script_name, if hit { "cached" } else { "prepared" }) {
+                            Ok(_) => {}, //success
+                            Err(_) => {}, //fail
+                        }
+                        END_OF_REPLY
+                    }
+                    Err(_) => PARSE_FAILURE,
+                }
+            }
+            _ => UNKNOWN_SCRIPT,
+        };
+        match writeln!(writer, "{}", reply) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+    }
+
+    // This function prepares the named script from the cache and recites it, returning the
+    // transcript and whether the play was cached, or an error if it could not be prepared. No
+    // other play is prepared or recited until the run state it counted has been forgotten.
+    fn recite_alone(cache: &ScriptCache, base_dir: &Path, script_name: &str) -> Result<(Vec<u8>, bool), u8> {
+        let _recitation = RECITATION.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let recited = cache.get_play(base_dir, script_name).map(|(mut play, hit)| {
+            let mut transcript = Vec::new();
+            play.recite(&StyleConfig::default(), &mut transcript);
+            (transcript, hit)
+        });
+        declarations::reset_run_state();
+        recited
+    }
+
+    // This function handles a single client connection. The first line the client sends must be
+    // the protocol version, which is acknowledged, and otherwise the client is told the version is
+    // unsupported and the connection is closed. It then reads the request from the next line. For
+    // LIST it replies with the name of each script it serves, one per line, followed by a blank
+    // line. For GET followed by a script name it replies with the play recited from that script.
+    // Otherwise the request is the name of a file in the base directory and it replies with the
+    // file's lines, escaped so that none is mistaken for a protocol line, followed by the end of
+    // reply line. Only files inside the base directory are served, so a name that is absolute or
+    // climbs out of it is refused. If the file cannot be served an error line is sent instead.
+    // The connection is then closed, as it is if the client leaves the server waiting to read or
+    // write for longer than the idle timeout.
+    fn handle_connection(stream: TcpStream, cache: Arc<ScriptCache>, base_dir: PathBuf, idle_timeout: Duration) {
+        if stream.set_read_timeout(Some(idle_timeout)).is_err() || stream.set_write_timeout(Some(idle_timeout)).is_err() {
+            return;
+        }
+        let mut writer = match stream.try_clone() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let mut reader = BufReader::new(stream);
+        let mut handshake = String::new();
+        if reader.read_line(&mut handshake).is_err() {
+            return;
+        }
+        if handshake.trim() != PROTOCOL_VERSION {
+            match writeln!(std::io::stderr().lock(), "Error: client sent unsupported handshake \"{}\"", handshake.trim()) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            match writeln!(writer, "{}", HANDSHAKE_REJECTED) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            return;
+        }
+        if writeln!(writer, "{}", HANDSHAKE_ACCEPTED).is_err() {
+            return;
+        }
+        let mut request = String::new();
+        if reader.read_line(&mut request).is_err() {
+            return;
+        }
+        let file_name = request.trim();
+        if let Some((GET_COMMAND, script_name)) = file_name.split_once(' ') {
+            Self::send_recitation(&mut writer, &cache, &base_dir, script_name.trim());
+            return;
+        }
+        if file_name == LIST_COMMAND {
+            for name in Self::scripts_in(&base_dir) {
+                if writeln!(writer, "{}", name).is_err() {
+                    return;
+                }
+            }
+            match writeln!(writer) {
+                Ok(_) => {}, //success
+                Err(_) => {}, //fail
+            }
+            return;
+        }
+        let path = match confined_path(&base_dir, file_name) {
+            Ok(path) => path,
+            Err(e) => {
+                match writeln!(std::io::stderr().lock(), "Error: refused to serve {}", file_name) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                match writeln!(writer, "{}", if e == PathError::Outside { FORBIDDEN_PATH } else { UNKNOWN_FILE }) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                return;
+            }
+        };
+        match cache.get(&path.to_string_lossy()) {
+            Ok((lines, hit)) => {
+                match writeln!(std::io::stdout().lock(), "Serving {} ({})", file_name, if hit { "cached" } else { "read from disk" }) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                for line in lines.iter() {
+                    if writeln!(writer, "{}", escape_reply_line(line)).is_err() {
+                        return;
+                    }
+                }
+                match writeln!(writer, "{}", END_OF_REPLY) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+            }
+            Err(_) => {
+                match writeln!(std::io::stderr().lock(), "Error: could not serve {}", file_name) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+                match writeln!(writer, "{}", UNKNOWN_FILE) {
+                    Ok(_) => {}, //success
+                    Err(_) => {}, //fail
+                }
+            }
+        }
+    }
+
+    // This function accepts connections until the listener fails, handling each connection on
+    // its own thread. If the most connections allowed are already being handled, the new
+    // connection is sent BUSY and closed instead of being handled, so that a flood of clients
+    // cannot start an unbounded number of threads.
+    pub fn run(&self) -> Result<(), u8> {
+        let listener = match &self.listener {
+            Some(l) => l,
+            None => return Err(ERR_NOT_OPEN),
+        };
+        match writeln!(std::io::stdout().lock(), "Listening on {}", self.listening_addr) {
+            Ok(_) => {}, //success
+            Err(_) => {}, //fail
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut s) => {
+                    if self.active.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+                        self.active.fetch_sub(1, Ordering::SeqCst);
+                        match writeln!(s, "{}", BUSY) {
+                            Ok(_) => {}, //success
+                            Err(_) => {}, //fail
+                        }
+                        continue;
+                    }
+                    let active = ActiveConnection(self.active.clone());
+                    let cache = self.cache.clone();
+                    let base_dir = self.base_dir.clone();
+                    let idle_timeout = self.idle_timeout;
+                    thread::spawn(move || {
+                        let _active = active;
+                        Self::handle_connection(s, cache, base_dir, idle_timeout);
+                    });
+                }
+                Err(_) => {
+                    match writeln!(std::io::stderr().lock(), "Error: failed to accept a connection") {
+                        Ok(_) => {}, //success
+                        Err(_) => {}, //fail
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+    use std::sync::atomic::Ordering as AtomicOrdering;
+    use std::time::Duration;
+
+    // The client's options are global, so tests that change them or fetch through the client take
+    // turns
+    static CLIENT_OPTIONS: Mutex<()> = Mutex::new(());
+
+    fn lock_client_options() -> MutexGuard<'static, ()> {
+        let guard = CLIENT_OPTIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        declarations::reset_options();
+        guard
+    }
+
+    // This function returns a loopback address that nothing is listening on
+    fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("a free port should be found");
+        listener.local_addr().expect("the port should be known").to_string()
+    }
+
+    // This function creates an empty directory for the named test holding the given files
+    fn dir_with(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lab3server-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("the test directory should be created");
+        for (file, contents) in files {
+            if let Some(parent) = dir.join(file).parent() {
+                std::fs::create_dir_all(parent).expect("the test directory should be created");
+            }
+            std::fs::write(dir.join(file), contents).expect("the test file should be written");
+        }
+        dir
+    }
+
+    // This function starts a server for the given directory at the given address on another
+    // thread
+    fn serve_at(address: &str, dir: &Path, max_connections: usize) {
+        let mut server = Server::new(&dir.to_string_lossy());
+        server.set_max_connections(max_connections);
+        assert_eq!(server.open(address), Ok(()));
+        thread::spawn(move || server.run());
+    }
+
+    // This function starts a server for the given directory and returns its address
+    fn serve(dir: &Path) -> String {
+        let address = free_address();
+        serve_at(&address, dir, DEFAULT_MAX_CONNECTIONS);
+        address
+    }
+
+    // This function connects to the server at the given address, agrees on the protocol version,
+    // sends the given request, and returns every line of the reply until the server hangs up
+    fn request(address: &str, request: &str) -> Vec<String> {
+        let mut stream = TcpStream::connect(address).expect("the server should accept");
+        writeln!(stream, "{}", PROTOCOL_VERSION).unwrap();
+        writeln!(stream, "{}", request).unwrap();
+        let mut lines = BufReader::new(stream).lines().map_while(Result::ok);
+        assert_eq!(lines.next().as_deref(), Some(HANDSHAKE_ACCEPTED));
+        lines.collect()
+    }
+
+    #[test]
+    fn matching_handshake_is_accepted() {
+        let address = serve(&dir_with("handshake_ok", &[("script.txt", "")]));
+        let mut stream = TcpStream::connect(&address).unwrap();
+        writeln!(stream, "{}", PROTOCOL_VERSION).unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        assert_eq!(reply.trim(), HANDSHAKE_ACCEPTED);
+    }
+
+    #[test]
+    fn mismatching_handshake_is_rejected_and_closed() {
+        let address = serve(&dir_with("handshake_bad", &[("script.txt", "")]));
+        let mut stream = TcpStream::connect(&address).unwrap();
+        writeln!(stream, "LAB3 v2").unwrap();
+        writeln!(stream, "{}", LIST_COMMAND).unwrap();
+        let reply: Vec<String> = BufReader::new(stream).lines().map_while(Result::ok).collect();
+        assert_eq!(reply, vec![HANDSHAKE_REJECTED]);
+    }
+
+    #[test]
+    fn list_names_the_scripts_in_the_base_directory() {
+        let dir = dir_with("list", &[("hamlet.txt", ""), ("macbeth.txt", ""), ("notes.md", "")]);
+        std::fs::create_dir_all(dir.join("folder.txt")).unwrap();
+        let address = serve(&dir);
+        assert_eq!(request(&address, LIST_COMMAND), vec!["hamlet.txt", "macbeth.txt", ""]);
+    }
+
+    #[test]
+    fn files_outside_the_base_directory_are_refused() {
+        let dir = dir_with("traversal", &[("base/part.txt", "1 Hello\n"), ("secret.txt", "1 Secret\n")]);
+        let address = serve(&dir.join("base"));
+        assert_eq!(request(&address, "part.txt"), vec!["1 Hello", END_OF_REPLY]);
+        assert_eq!(request(&address, "../secret.txt"), vec![FORBIDDEN_PATH]);
+        assert_eq!(request(&address, &dir.join("secret.txt").to_string_lossy()), vec![FORBIDDEN_PATH]);
+        assert_eq!(request(&address, "/etc/passwd"), vec![FORBIDDEN_PATH]);
+        assert_eq!(request(&address, "absent.txt"), vec![UNKNOWN_FILE]);
+    }
+
+    #[test]
+    fn fetch_retries_until_a_late_server_starts() {
+        let _options = lock_client_options();
+        declarations::FETCH_RETRIES.store(5, AtomicOrdering::SeqCst);
+        let dir = dir_with("late", &[("part.txt", "1 Hello\n2 There\n")]);
+        let address = free_address();
+        let (late_address, late_dir) = (address.clone(), dir.clone());
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(250));
+            serve_at(&late_address, &late_dir, DEFAULT_MAX_CONNECTIONS);
+        });
+        let mut lines = Vec::new();
+        assert_eq!(declarations::grab_remote_lines(&format!("tcp://{}/part.txt", address), &mut lines), Ok(()));
+        assert_eq!(lines, vec!["1 Hello", "2 There"]);
+    }
+
+    #[test]
+    fn fetch_without_retries_fails_when_nothing_is_listening() {
+        let _options = lock_client_options();
+        let mut lines = Vec::new();
+        assert_eq!(declarations::grab_remote_lines(&format!("tcp://{}/part.txt", free_address()), &mut lines), Err(declarations::ERR_SCRIPT_GEN));
+    }
+
+    #[test]
+    fn missing_remote_file_is_the_same_error_as_a_missing_local_file() {
+        let _options = lock_client_options();
+        let address = serve(&dir_with("missing", &[]));
+        let mut remote = Vec::new();
+        let mut local = Vec::new();
+        let remote_result = declarations::grab_remote_lines(&format!("tcp://{}/absent.txt", address), &mut remote);
+        assert_eq!(remote_result, declarations::grab_trimmed_file_lines("/nonexistent/absent.txt", &mut local));
+        assert_eq!(remote_result, Err(declarations::ERR_SCRIPT_GEN));
+        assert!(remote.is_empty());
+    }
+
+    #[test]
+    fn fetched_lines_that_look_like_protocol_lines_arrive_intact() {
+        let _options = lock_client_options();
+        let address = serve(&dir_with("escaped", &[("part.txt", "LAB3 END\nLAB3 ERR not an error\n\\backslash\n")]));
+        let mut lines = Vec::new();
+        assert_eq!(declarations::grab_remote_lines(&format!("tcp://{}/part.txt", address), &mut lines), Ok(()));
+        assert_eq!(lines, vec!["LAB3 END", "LAB3 ERR not an error", "\\backslash"]);
+    }
+
+    #[test]
+    fn fetch_retries_while_the_server_is_busy() {
+        let _options = lock_client_options();
+        declarations::FETCH_RETRIES.store(5, AtomicOrdering::SeqCst);
+        let dir = dir_with("busy_retry", &[("part.txt", "1 Hello\n")]);
+        let address = free_address();
+        serve_at(&address, &dir, 1);
+        let held = TcpStream::connect(&address).expect("the first connection should be accepted");
+        thread::sleep(Duration::from_millis(50));
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+        let mut lines = Vec::new();
+        assert_eq!(declarations::grab_remote_lines(&format!("tcp://{}/part.txt", address), &mut lines), Ok(()));
+        assert_eq!(lines, vec!["1 Hello"]);
+    }
+
+    const GET_PLAY: &[(&str, &str)] = &[
+        ("script.txt", "[scene] Act I\nscene1.txt\n"),
+        ("scene1.txt", "Hamlet hamlet.txt\nGhost ghost.txt\n"),
+        ("hamlet.txt", "1 Who's there?\n3 LAB3 END\n"),
+        ("ghost.txt", "2 Mark me.\n"),
+    ];
+
+    #[test]
+    fn get_sends_the_play_recited_from_the_base_directory() {
+        let _options = lock_client_options();
+        let dir = dir_with("get", GET_PLAY);
+        let mut local = Play::with_source(Arc::new(DirSource(dir.clone())));
+        assert_eq!(local.prepare("script.txt"), Ok(()));
+        let mut expected = Vec::new();
+        local.recite(&StyleConfig::default(), &mut expected);
+        declarations::reset_run_state();
+
+        let address = serve(&dir);
+        let mut transcript = Vec::new();
+        assert_eq!(declarations::grab_remote_recitation(&address, "script.txt", &mut transcript), Ok(()));
+        assert_eq!(String::from_utf8(transcript).unwrap(), String::from_utf8(expected).unwrap());
+    }
+
+    #[test]
+    fn get_forgets_the_run_state_of_each_play() {
+        let _options = lock_client_options();
+        let address = serve(&dir_with("get_state", GET_PLAY));
+        for _ in 0..2 {
+            let reply = request(&address, "GET script.txt");
+            assert_eq!(reply.last().map(String::as_str), Some(END_OF_REPLY));
+            assert!(declarations::take_files_read().is_empty());
+            assert_eq!(declarations::warning_count(), 0);
+            assert_eq!(declarations::OUTPUT_LINES.load(AtomicOrdering::SeqCst), 0);
+        }
+    }
+
+    #[test]
+    fn get_waits_for_the_recitation_in_progress() {
+        let _options = lock_client_options();
+        let address = serve(&dir_with("get_alone", GET_PLAY));
+        let recitation = RECITATION.lock().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiting_address = address.clone();
+        thread::spawn(move || sender.send(request(&waiting_address, "GET script.txt")));
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(recitation);
+        let reply = receiver.recv_timeout(Duration::from_secs(5)).expect("the recitation should finish");
+        assert_eq!(reply.last().map(String::as_str), Some(END_OF_REPLY));
+    }
+
+    #[test]
+    fn get_reports_unknown_and_unparsable_scripts() {
+        let _options = lock_client_options();
+        let address = serve(&dir_with("get_errors", &[("script.txt", "[scene] Act I\nabsent.txt\n")]));
+        assert_eq!(request(&address, "GET absent.txt"), vec![UNKNOWN_SCRIPT]);
+        assert_eq!(request(&address, "GET script.txt"), vec![PARSE_FAILURE]);
+    }
+
+    #[test]
+    fn get_refuses_scripts_and_part_files_outside_the_base_directory() {
+        let _options = lock_client_options();
+        let dir = dir_with("get_traversal", &[("secret.txt", "1 Secret\n"), ("base/ghost.txt", "2 Mark me.\n")]);
+        let secret = dir.join("secret.txt").to_string_lossy().into_owned();
+        let files = [
+            ("climbs.txt", "[scene] Act I\nclimbs_scene.txt\n".to_string()),
+            ("climbs_scene.txt", "Ghost ghost.txt\nSpy ../secret.txt\n".to_string()),
+            ("absolute.txt", "[scene] Act I\nabsolute_scene.txt\n".to_string()),
+            ("absolute_scene.txt", format!("Ghost ghost.txt\nSpy {}\n", secret)),
+        ];
+        for (name, contents) in &files {
+            std::fs::write(dir.join("base").join(name), contents).unwrap();
+        }
+        let address = serve(&dir.join("base"));
+        assert_eq!(request(&address, "GET ../secret.txt"), vec![FORBIDDEN_PATH]);
+        assert_eq!(request(&address, &format!("GET {}", secret)), vec![FORBIDDEN_PATH]);
+        assert_eq!(request(&address, "GET climbs.txt"), vec![PARSE_FAILURE]);
+        assert_eq!(request(&address, "GET absolute.txt"), vec![PARSE_FAILURE]);
+    }
+
+    // Reads files through a DirSource, counting how many it has read
+    struct CountingSource(DirSource, AtomicUsize);
+
+    impl LineSource for CountingSource {
+        fn lines(&self, name: &str) -> Result<Vec<String>, u8> {
+            self.1.fetch_add(1, AtomicOrdering::SeqCst);
+            self.0.lines(name)
+        }
+    }
+
+    #[test]
+    fn unchanged_play_is_not_read_again() {
+        let _options = lock_client_options();
+        let dir = dir_with("play_cache", GET_PLAY);
+        let source = Arc::new(CountingSource(DirSource(dir.clone()), AtomicUsize::new(0)));
+        let cache = ScriptCache::new();
+        let (mut first, hit) = cache.get_play_from(source.clone(), &dir, "script.txt").expect("the play should prepare");
+        assert!(!hit);
+        let reads = source.1.load(AtomicOrdering::SeqCst);
+        assert_eq!(reads, GET_PLAY.len());
+
+        let (mut second, hit) = cache.get_play_from(source.clone(), &dir, "script.txt").expect("the play should be cached");
+        assert!(hit);
+        assert_eq!(source.1.load(AtomicOrdering::SeqCst), reads);
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        first.recite(&StyleConfig::default(), &mut a);
+        second.recite(&StyleConfig::default(), &mut b);
+        assert_eq!(a, b);
+
+        let ghost = File::options().append(true).open(dir.join("ghost.txt")).unwrap();
+        ghost.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        let (_, hit) = cache.get_play_from(source.clone(), &dir, "script.txt").expect("the play should prepare");
+        assert!(!hit);
+        assert_eq!(source.1.load(AtomicOrdering::SeqCst), 2 * reads);
+    }
+
+    #[test]
+    fn connection_beyond_the_limit_is_told_the_server_is_busy() {
+        let dir = dir_with("busy", &[("part.txt", "1 Hello\n")]);
+        let address = free_address();
+        serve_at(&address, &dir, 2);
+        let held: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(&address).unwrap()).collect();
+        thread::sleep(Duration::from_millis(50));
+        let extra = TcpStream::connect(&address).unwrap();
+        let reply: Vec<String> = BufReader::new(extra).lines().map_while(Result::ok).collect();
+        assert_eq!(reply, vec![BUSY]);
+
+        drop(held);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(request(&address, "part.txt"), vec!["1 Hello", END_OF_REPLY]);
+    }
+
+    #[test]
+    fn idle_connection_gives_up_its_place() {
+        let dir = dir_with("idle", &[("part.txt", "1 Hello\n")]);
+        let address = free_address();
+        let mut server = Server::new(&dir.to_string_lossy());
+        server.set_max_connections(1);
+        server.set_idle_timeout(Duration::from_millis(100));
+        assert_eq!(server.open(&address), Ok(()));
+        thread::spawn(move || server.run());
+        let idle = TcpStream::connect(&address).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(request(&address, "part.txt"), vec!["1 Hello", END_OF_REPLY]);
+        drop(idle);
+    }
+
+    #[test]
+    fn fetch_reports_progress_as_lines_arrive() {
+        let _options = lock_client_options();
+        let address = serve(&dir_with("progress", &[("part.txt", "1 One\n2 Two\n3 Three\n4 Four\n5 Five\n")]));
+        let mut lines = Vec::new();
+        let mut reported = Vec::new();
+        let fetched = declarations::grab_remote_lines_with_progress(&format!("tcp://{}/part.txt", address), &mut lines, 2, &mut |read| reported.push(read));
+        assert_eq!(fetched, Ok(()));
+        assert_eq!(lines.len(), 5);
+        assert_eq!(reported, vec![2, 4]);
+    }
+}